@@ -0,0 +1,198 @@
+use cosmwasm_std::{Addr, Deps, DepsMut, Env, Storage, Uint128};
+
+use cw_asset::{Asset, AssetInfo};
+
+use fields_of_mars::martian_field::{
+    Config, Health, Position, PositionAccounting, PositionPnlResponse, SignedValue, State,
+};
+
+use crate::error::ContractError;
+use crate::health::{compute_health, compute_value_per_lp_token};
+use crate::prices::query_price;
+use crate::state::{ACCOUNTING, CONFIG, POSITION, STATE};
+
+/// Compute the value of assets, measured in the short asset
+///
+/// Liquidity tokens of the primary pair are valued at the price of the liquidity token used to
+/// compute positions' health. Assets without a price source are valued at zero
+pub fn compute_assets_value(
+    deps: Deps,
+    config: &Config,
+    assets: &[Asset],
+) -> Result<Uint128, ContractError> {
+    let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
+
+    let mut value = Uint128::zero();
+    for asset in assets {
+        let price = if asset.info == liquidity_token_info {
+            compute_value_per_lp_token(deps, config, None, None)?
+        } else if asset.info == config.primary_asset_info
+            || asset.info == config.secondary_asset_info
+            || asset.info == config.astro_token_info
+        {
+            query_price(deps, config, &asset.info)?
+        } else {
+            continue;
+        };
+        value += asset.amount * price;
+    }
+
+    Ok(value)
+}
+
+/// Compute the value of a position's bonded liquidity tokens and unlocked assets, i.e. of everything
+/// it holds before its debt is deducted
+pub fn compute_position_value(
+    deps: Deps,
+    config: &Config,
+    position: &Position,
+    health: &Health,
+) -> Result<Uint128, ContractError> {
+    let unlocked_assets = position.unlocked_assets.to_vec();
+    let unlocked_value = compute_assets_value(deps, config, &unlocked_assets)?;
+    Ok(health.bond_value + unlocked_value)
+}
+
+/// Record assets deposited by a user
+pub fn record_deposits(
+    deps: DepsMut,
+    config: &Config,
+    user_addr: &Addr,
+    assets: &[Asset],
+) -> Result<(), ContractError> {
+    if assets.is_empty() {
+        return Ok(());
+    }
+
+    let value = compute_assets_value(deps.as_ref(), config, assets)?;
+    let mut accounting = ACCOUNTING.load(deps.storage, user_addr).unwrap_or_default();
+    accounting.record_deposit(value);
+    ACCOUNTING.save(deps.storage, user_addr, &accounting)?;
+    Ok(())
+}
+
+/// Record debt of the given value borrowed by a user
+pub fn record_borrow(
+    storage: &mut dyn Storage,
+    user_addr: &Addr,
+    value: Uint128,
+) -> Result<(), ContractError> {
+    let mut accounting = ACCOUNTING.load(storage, user_addr).unwrap_or_default();
+    accounting.debt_value_borrowed += value;
+    ACCOUNTING.save(storage, user_addr, &accounting)?;
+    Ok(())
+}
+
+/// Record debt of the given value repaid on behalf of a user
+pub fn record_repay(
+    storage: &mut dyn Storage,
+    user_addr: &Addr,
+    value: Uint128,
+) -> Result<(), ContractError> {
+    let mut accounting = ACCOUNTING.load(storage, user_addr).unwrap_or_default();
+    accounting.debt_value_repaid += value;
+    ACCOUNTING.save(storage, user_addr, &accounting)?;
+    Ok(())
+}
+
+/// Record assets refunded to a user. `position` is the user's position before the refund, whose
+/// unlocked assets include the ones refunded
+pub fn record_withdrawal(
+    deps: DepsMut,
+    env: &Env,
+    config: &Config,
+    state: &State,
+    user_addr: &Addr,
+    position: &Position,
+    assets: &[Asset],
+) -> Result<(), ContractError> {
+    if assets.is_empty() {
+        return Ok(());
+    }
+
+    // the position's equity includes its unlocked assets, which are about to be refunded
+    let health = compute_health(deps.as_ref(), env, config, state, position)?;
+    let equity = compute_position_value(deps.as_ref(), config, position, &health)?
+        .saturating_sub(health.debt_value);
+
+    let value = compute_assets_value(deps.as_ref(), config, assets)?;
+    let mut accounting = ACCOUNTING.load(deps.storage, user_addr).unwrap_or_default();
+    accounting.record_withdrawal(value, equity);
+    ACCOUNTING.save(deps.storage, user_addr, &accounting)?;
+    Ok(())
+}
+
+pub fn query_position_pnl(
+    deps: Deps,
+    env: Env,
+    user: String,
+) -> Result<PositionPnlResponse, ContractError> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+    let health = compute_health(deps, &env, &config, &state, &position)?;
+
+    // positions opened before v1.1.0 that haven't been touched since are reported as if they were
+    // seeded now
+    let accounting = match ACCOUNTING.may_load(deps.storage, &user_addr)? {
+        Some(accounting) => accounting,
+        None => seeded_accounting(deps, &config, &position, &health)?,
+    };
+
+    // unlocked assets are valued the same way as when they are withdrawn
+    let position_value = compute_position_value(deps, &config, &position, &health)?;
+
+    Ok(PositionPnlResponse {
+        realized_pnl: SignedValue::difference(accounting.realized_profit, accounting.realized_loss),
+        unrealized_pnl: SignedValue::difference(
+            position_value,
+            health.debt_value + accounting.cost_basis,
+        ),
+        accounting,
+        health,
+    })
+}
+
+/// Accounting of a position opened before accounting was introduced in v1.1.0
+///
+/// Its history is unknown, so its cost basis is set to its current equity, as if it had been
+/// deposited then. Its PnL is tracked from that point onwards
+fn seeded_accounting(
+    deps: Deps,
+    config: &Config,
+    position: &Position,
+    health: &Health,
+) -> Result<PositionAccounting, ContractError> {
+    let equity =
+        compute_position_value(deps, config, position, health)?.saturating_sub(health.debt_value);
+    let mut accounting = PositionAccounting::default();
+    accounting.record_deposit(equity);
+    Ok(accounting)
+}
+
+/// Seed the accounting of a position opened before v1.1.0, if it has none yet
+///
+/// This must be called the first time a position is touched after the migration, before its value
+/// changes. Positions opened after the migration have accounting from their first deposit
+pub fn seed_accounting(
+    deps: DepsMut,
+    env: &Env,
+    config: &Config,
+    user_addr: &Addr,
+) -> Result<(), ContractError> {
+    if ACCOUNTING.has(deps.storage, user_addr) {
+        return Ok(());
+    }
+
+    let position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+    if position == Position::default() {
+        return Ok(());
+    }
+
+    let state = STATE.load(deps.storage)?;
+    let health = compute_health(deps.as_ref(), env, config, &state, &position)?;
+    let accounting = seeded_accounting(deps.as_ref(), config, &position, &health)?;
+    ACCOUNTING.save(deps.storage, user_addr, &accounting)?;
+    Ok(())
+}
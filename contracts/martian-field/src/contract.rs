@@ -1,14 +1,20 @@
+use std::convert::TryFrom;
+
 use cosmwasm_std::{
-    entry_point, to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdError,
-    StdResult,
+    entry_point, to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdResult,
 };
 
 use fields_of_mars::martian_field::msg::{
     CallbackMsg, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg,
 };
+use fields_of_mars::martian_field::ReplyId;
 
+use crate::error::ContractError;
 use crate::helpers::unwrap_reply;
-use crate::{execute, execute_callbacks as callbacks, execute_replies as replies, queries};
+use crate::{
+    accounting, execute, execute_callbacks as callbacks, execute_replies as replies, migrations,
+    prices, queries, simulation,
+};
 
 #[entry_point]
 pub fn instantiate(
@@ -16,14 +22,19 @@ pub fn instantiate(
     _env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = msg.check(deps.api)?;
     config.validate()?;
     execute::init_storage(deps, config)
 }
 
 #[entry_point]
-pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+pub fn execute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
     let api = deps.api;
     match msg {
         ExecuteMsg::UpdatePosition(actions) => execute::update_position(deps, env, info, actions),
@@ -33,10 +44,64 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
         } => execute::harvest(deps, env, info, max_spread, slippage_tolerance),
         ExecuteMsg::Liquidate {
             user,
-        } => execute::liquidate(deps, env, info, api.addr_validate(&user)?),
+            repay_amount,
+            max_spread,
+        } => {
+            let user_addr = api.addr_validate(&user)?;
+            match repay_amount {
+                Some(repay_amount) => execute::liquidate_with_funds(
+                    deps,
+                    env,
+                    info,
+                    user_addr,
+                    repay_amount,
+                    max_spread,
+                ),
+                None => execute::liquidate(deps, env, info, user_addr, max_spread),
+            }
+        }
+        ExecuteMsg::LiquidateMany {
+            users,
+        } => {
+            let user_addrs =
+                users.iter().map(|user| api.addr_validate(user)).collect::<StdResult<Vec<_>>>()?;
+            execute::liquidate_many(deps, env, info, user_addrs)
+        }
         ExecuteMsg::UpdateConfig {
-            new_config,
-        } => execute::update_config(deps, info, new_config.check(api)?),
+            config_update,
+        } => execute::update_config(deps, info, config_update),
+        ExecuteMsg::UpdateFeeConfig {
+            performance_fee,
+            treasury,
+        } => execute::update_fee_config(
+            deps,
+            info,
+            performance_fee,
+            treasury.map(|treasury| api.addr_validate(&treasury)).transpose()?,
+        ),
+        ExecuteMsg::AddOperator {
+            operator,
+            expires_in,
+        } => execute::add_operator(deps, env, info, api.addr_validate(&operator)?, expires_in),
+        ExecuteMsg::RemoveOperator {
+            operator,
+        } => execute::remove_operator(deps, info, api.addr_validate(&operator)?),
+        ExecuteMsg::UpdatePauseFlags {
+            pause_flags_update,
+        } => execute::update_pause_flags(deps, info, pause_flags_update),
+        ExecuteMsg::UpdatePriceSnapshot {} => execute::update_price_snapshot(deps, env),
+        ExecuteMsg::ProposeNewGovernance {
+            new_governance,
+            expires_in,
+        } => execute::propose_new_governance(
+            deps,
+            env,
+            info,
+            api.addr_validate(&new_governance)?,
+            expires_in,
+        ),
+        ExecuteMsg::AcceptGovernance {} => execute::accept_governance(deps, env, info),
+        ExecuteMsg::CancelGovernanceProposal {} => execute::cancel_governance_proposal(deps, info),
         ExecuteMsg::Callback(callback_msg) => execute_callback(deps, env, info, callback_msg),
     }
 }
@@ -46,18 +111,18 @@ fn execute_callback(
     env: Env,
     info: MessageInfo,
     msg: CallbackMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     if info.sender != env.contract.address {
-        return Err(StdError::generic_err("callbacks cannot be invoked externally"));
+        return Err(ContractError::Unauthorized {});
     }
     match msg {
         CallbackMsg::ProvideLiquidity {
             user_addr,
             slippage_tolerance,
-        } => callbacks::provide_liquidity(deps, user_addr, slippage_tolerance),
+        } => callbacks::provide_liquidity(deps, env, user_addr, slippage_tolerance),
         CallbackMsg::WithdrawLiquidity {
             user_addr,
-        } => callbacks::withdraw_liquidity(deps, user_addr),
+        } => callbacks::withdraw_liquidity(deps, env, user_addr),
         CallbackMsg::Bond {
             user_addr,
         } => callbacks::bond(deps, env, user_addr),
@@ -77,19 +142,39 @@ fn execute_callback(
             user_addr,
             recipient_addr,
             percentage,
-        } => callbacks::refund(deps, user_addr, recipient_addr, percentage),
+        } => callbacks::refund(deps, env, user_addr, recipient_addr, percentage),
         CallbackMsg::Swap {
             user_addr,
             offer_asset_info,
             offer_amount,
             max_spread,
-        } => callbacks::swap(deps, user_addr, offer_asset_info, offer_amount, max_spread),
+        } => callbacks::swap(deps, env, user_addr, offer_asset_info, offer_amount, max_spread),
         CallbackMsg::Balance {
+            user_addr,
+            max_spread,
+        } => callbacks::balance(deps, env, user_addr, max_spread),
+        CallbackMsg::SetLeverage {
+            user_addr,
+            target_ltv,
             max_spread,
-        } => callbacks::balance(deps, env, max_spread),
+            slippage_tolerance,
+        } => callbacks::set_leverage(
+            deps,
+            env,
+            user_addr,
+            target_ltv,
+            max_spread,
+            slippage_tolerance,
+        ),
+        CallbackMsg::ClosePosition {
+            user_addr,
+            max_spread,
+        } => callbacks::close_position(deps, env, user_addr, max_spread),
         CallbackMsg::Cover {
             user_addr,
-        } => callbacks::cover(deps, env, user_addr),
+            repay_amount,
+            max_spread,
+        } => callbacks::cover(deps, env, user_addr, repay_amount, max_spread),
         CallbackMsg::AssertHealth {
             user_addr,
         } => callbacks::assert_health(deps, env, user_addr),
@@ -103,27 +188,68 @@ fn execute_callback(
 }
 
 #[entry_point]
-pub fn reply(deps: DepsMut, _env: Env, reply: Reply) -> StdResult<Response> {
-    match reply.id {
-        0 => replies::after_provide_liquidity(deps, unwrap_reply(reply)?),
-        1 => replies::after_withdraw_liquidity(deps, unwrap_reply(reply)?),
-        2 => replies::after_swap(deps, unwrap_reply(reply)?),
-        3 => replies::failed_apollo_reward_update(reply.result),
-        id => Err(StdError::generic_err(format!("invalid reply id: {}", id))),
+pub fn reply(deps: DepsMut, env: Env, reply: Reply) -> Result<Response, ContractError> {
+    let id = ReplyId::try_from(reply.id).map_err(|_| ContractError::InvalidReplyId {
+        id: reply.id,
+    })?;
+    match id {
+        ReplyId::ProvideLiquidity => {
+            replies::after_provide_liquidity(deps, env, unwrap_reply(reply)?)
+        }
+        ReplyId::WithdrawLiquidity => {
+            replies::after_withdraw_liquidity(deps, env, unwrap_reply(reply)?)
+        }
+        ReplyId::Swap => replies::after_swap(deps, env, unwrap_reply(reply)?),
+        ReplyId::ApolloRewardUpdate => replies::failed_apollo_reward_update(reply.result),
     }
 }
 
 #[entry_point]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let res = match msg {
         QueryMsg::Config {} => to_binary(&queries::query_config(deps, env)?),
         QueryMsg::State {} => to_binary(&queries::query_state(deps, env)?),
+        QueryMsg::PendingGovernance {} => to_binary(&queries::query_pending_governance(deps)?),
+        QueryMsg::Operators {
+            start_after,
+            limit,
+        } => to_binary(&queries::query_operators(deps, start_after, limit)?),
+        QueryMsg::PauseFlags {} => to_binary(&queries::query_pause_flags(deps)?),
+        QueryMsg::Twap {} => to_binary(&prices::query_twap(deps)?),
         QueryMsg::Position {
             user,
         } => to_binary(&queries::query_position(deps, env, user)?),
         QueryMsg::Health {
             user,
         } => to_binary(&queries::query_health(deps, env, user)?),
+        QueryMsg::LiquidationPrice {
+            user,
+        } => to_binary(&queries::query_liquidation_price(deps, env, user)?),
+        QueryMsg::Positions {
+            start_after,
+            limit,
+        } => to_binary(&queries::query_positions(deps, start_after, limit)?),
+        QueryMsg::SimulateUpdatePosition {
+            user,
+            actions,
+            funds,
+        } => {
+            to_binary(&simulation::query_simulate_update_position(deps, env, user, actions, funds)?)
+        }
+        QueryMsg::PositionsWithHealth {
+            start_after,
+            limit,
+            min_ltv,
+        } => to_binary(&queries::query_positions_with_health(
+            deps,
+            env,
+            start_after,
+            limit,
+            min_ltv,
+        )?),
+        QueryMsg::PositionPnl {
+            user,
+        } => to_binary(&accounting::query_position_pnl(deps, env, user)?),
         QueryMsg::Snapshot {
             user,
         } => to_binary(&queries::query_snapshot(deps, user)?),
@@ -133,10 +259,11 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
         QueryMsg::StrategyInfo {} => to_binary(&queries::query_strategy_info(deps, env)?),
         QueryMsg::Tvl {} => to_binary(&queries::query_tvl(deps, env)?),
         QueryMsg::Apr {} => to_binary(&queries::query_apr(deps)?),
-    }
+    }?;
+    Ok(res)
 }
 
 #[entry_point]
-pub fn migrate(_deps: DepsMut, _env: Env, _msg: MigrateMsg) -> StdResult<Response> {
-    Ok(Response::new()) // do nothing
+pub fn migrate(deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    migrations::migrate(deps, env, msg)
 }
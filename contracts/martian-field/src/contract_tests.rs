@@ -1,19 +1,47 @@
-use cosmwasm_std::testing::{
-    mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+use std::str::FromStr;
+
+use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+use cosmwasm_std::{
+    attr, from_binary, Addr, BankMsg, Coin, ContractResult, CosmosMsg, Decimal, Event, OwnedDeps,
+    Reply, Response, SubMsgExecutionResponse, Uint128, WasmMsg,
 };
-use cosmwasm_std::{Addr, Coin, Decimal, OwnedDeps, StdError, Uint128};
 
-use cw_asset::{Asset, AssetInfo};
+use cw_asset::{Asset, AssetInfo, AssetList};
+
+use fields_of_mars::adapters::{
+    ApolloFactory, Generator, Oracle, OracleUnchecked, Pair, PairUnchecked, PriceSource,
+    PriceSourceUnchecked, RedBank,
+};
+use fields_of_mars::error::FieldsOfMarsError;
+use fields_of_mars::martian_field::msg::{Action, CallbackMsg, ExecuteMsg, MigrateMsg, QueryMsg};
+use fields_of_mars::martian_field::{
+    Config, ConfigUnchecked, ConfigUpdate, Health, OperatorInfo, OperatorResponse, PauseFlags,
+    PauseFlagsUpdate, PendingGovernanceUnchecked, Position, PositionAccounting,
+    PositionPnlResponse, PositionResponse, PositionUnchecked, PositionsWithHealthResponse,
+    PriceSnapshot, ReplyId, SignedValue, SimulateUpdatePositionResponse,
+};
+use fields_of_mars::testing::CustomQuerier;
 
-use fields_of_mars::adapters::{ApolloFactory, Generator, Oracle, Pair, RedBank};
-use fields_of_mars::martian_field::msg::{Action, ExecuteMsg};
-use fields_of_mars::martian_field::Config;
+use crate::contract::{execute, instantiate, migrate, query, reply};
+use crate::error::ContractError;
+use crate::health::{compute_position_health, TotalValues};
+use crate::helpers::{assert_price_deviation, compute_belief_price, save_reply_context};
+use crate::math::{
+    compute_liquidation_price, compute_optimal_swap_amount, compute_partial_liquidation_value,
+    compute_swap_offer, compute_swap_return, compute_twap,
+};
+use crate::migrations::{v1_0_0, CONTRACT_NAME, CONTRACT_VERSION};
+use crate::prices::query_price;
 
-use crate::contract::{execute, instantiate};
+type MockDeps = OwnedDeps<MockStorage, MockApi, CustomQuerier>;
 
 /// Deploy the contract, returns the `deps` object
-fn setup_test() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
-    let mut deps = mock_dependencies(&[]);
+fn setup_test() -> MockDeps {
+    let mut deps = OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: CustomQuerier::default(),
+    };
 
     let config = Config {
         primary_asset_info: AssetInfo::native("uluna"),
@@ -33,16 +61,37 @@ fn setup_test() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
         red_bank: RedBank {
             contract_addr: Addr::unchecked("red_bank"),
         },
-        oracle: Oracle {
-            contract_addr: Addr::unchecked("oracle"),
+        primary_price_source: PriceSource::MarsOracle {
+            oracle: Oracle {
+                contract_addr: Addr::unchecked("oracle"),
+            },
+        },
+        secondary_price_source: PriceSource::Fixed {
+            price: Decimal::one(),
+        },
+        astro_price_source: PriceSource::MarsOracle {
+            oracle: Oracle {
+                contract_addr: Addr::unchecked("oracle"),
+            },
         },
         treasury: Addr::unchecked("treasury"),
         governance: Addr::unchecked("governance"),
-        operators: vec![Addr::unchecked("operator")],
+        guardian: Addr::unchecked("guardian"),
+        fee_manager: Addr::unchecked("fee_manager"),
+        operator_manager: Addr::unchecked("operator_manager"),
+        permissionless_harvest: false,
         max_ltv: Decimal::from_ratio(83u128, 100u128),
         performance_fee: Decimal::from_ratio(5u128, 100u128),
+        harvest_bounty: Decimal::zero(),
+        min_harvest_interval: 0,
         max_initial_ltv: Decimal::from_ratio(75u128, 100u128),
         bonus_rate: Decimal::from_ratio(1u128, 100u128),
+        close_factor: Decimal::from_ratio(1u128, 2u128),
+        full_liquidation_ltv: Decimal::from_ratio(9u128, 10u128),
+        liquidation_max_spread: Decimal::from_ratio(5u128, 100u128),
+        price_deviation_tolerance: Decimal::from_ratio(5u128, 100u128),
+        twap_window: 600,
+        max_price_age: 3600,
         apr_query_adapter: Addr::unchecked("apr_query_adapter"),
         apollo_factory: ApolloFactory {
             contract_addr: Addr::unchecked("apollo_factory"),
@@ -52,9 +101,91 @@ fn setup_test() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
 
     instantiate(deps.as_mut(), mock_env(), mock_info("deployer", &[]), config.into()).unwrap();
 
+    let msg = ExecuteMsg::AddOperator {
+        operator: "operator".to_string(),
+        expires_in: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("operator_manager", &[]), msg).unwrap();
+
     deps
 }
 
+/// Overwrite the stored config and version with those of a v1.0.0 deployment
+fn downgrade_to_v1_0_0(deps: &mut MockDeps) {
+    let config = crate::state::CONFIG.load(deps.as_ref().storage).unwrap();
+    let legacy_config = v1_0_0::Config {
+        primary_asset_info: config.primary_asset_info,
+        secondary_asset_info: config.secondary_asset_info,
+        astro_token_info: config.astro_token_info,
+        primary_pair: config.primary_pair,
+        astro_pair: config.astro_pair,
+        astro_generator: config.astro_generator,
+        red_bank: config.red_bank,
+        oracle: Oracle {
+            contract_addr: Addr::unchecked("oracle"),
+        },
+        treasury: config.treasury,
+        governance: config.governance,
+        operators: vec![Addr::unchecked("legacy_operator")],
+        max_ltv: config.max_ltv,
+        max_initial_ltv: config.max_initial_ltv,
+        performance_fee: config.performance_fee,
+        bonus_rate: config.bonus_rate,
+        apr_query_adapter: config.apr_query_adapter,
+        apollo_factory: config.apollo_factory,
+        min_position_size: config.min_position_size,
+    };
+    v1_0_0::CONFIG.save(deps.as_mut().storage, &legacy_config).unwrap();
+
+    let state = crate::state::STATE.load(deps.as_ref().storage).unwrap();
+    let legacy_state = v1_0_0::State {
+        total_bond_units: state.total_bond_units,
+        total_debt_units: state.total_debt_units,
+        pending_rewards: state.pending_rewards,
+    };
+    v1_0_0::STATE.save(deps.as_mut().storage, &legacy_state).unwrap();
+    cw2::CONTRACT.remove(deps.as_mut().storage);
+}
+
+/// Mock the primary pair, the oracle, Astro generator and Red Bank, such that each liquidity token is
+/// worth 20 uusd and the contract has the given amounts of liquidity tokens bonded and uusd borrowed.
+/// The TWAP of uluna, as well as its oracle price, is 100 uusd
+fn setup_markets(deps: &mut MockDeps, total_bonded_amount: u128, total_debt_amount: u128) {
+    let contract_addr = mock_env().contract.address;
+    let now = mock_env().block.time.seconds();
+    let snapshots = vec![
+        PriceSnapshot {
+            timestamp: now - 600,
+            price_cumulative: Uint128::zero(),
+        },
+        PriceSnapshot {
+            timestamp: now,
+            price_cumulative: Uint128::new(100 * 600 * 1_000_000),
+        },
+    ];
+    crate::state::PRICE_SNAPSHOTS
+        .save(deps.as_mut().storage, &Addr::unchecked("uluna_uusd_pair"), &snapshots)
+        .unwrap();
+
+    deps.querier.set_pool(
+        &Addr::unchecked("uluna_uusd_pair"),
+        [Asset::native("uluna", 1_000_000u128), Asset::native("uusd", 100_000_000u128)],
+        Uint128::new(10_000_000),
+    );
+    deps.querier.set_oracle_price(&AssetInfo::native("uluna"), Decimal::from_ratio(100u128, 1u128));
+    deps.querier.set_bonded_amount(&contract_addr, Uint128::new(total_bonded_amount));
+    deps.querier.set_debt_amount(&contract_addr, Uint128::new(total_debt_amount));
+}
+
+/// Save a user's position, and add its bond and debt units to the totals in state
+fn save_position(deps: &mut MockDeps, user: &str, position: &Position) {
+    let mut state = crate::state::STATE.load(deps.as_ref().storage).unwrap();
+    state.total_bond_units += position.bond_units;
+    state.total_debt_units += position.debt_units;
+    crate::state::STATE.save(deps.as_mut().storage, &state).unwrap();
+    crate::state::POSITION.save(deps.as_mut().storage, &Addr::unchecked(user), position).unwrap();
+}
+
 #[test]
 fn handling_native_deposits() {
     let mut deps = setup_test();
@@ -68,9 +199,15 @@ fn handling_native_deposits() {
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
     assert_eq!(
         res,
-        Err(StdError::generic_err("sent fund mismatch! expected: uusd:67890, received 0"))
+        Err(ContractError::SentFundMismatch {
+            expected: "uusd:67890".to_string(),
+            received: Uint128::zero(),
+        })
     );
 
+    // failed executions aren't reverted in unit tests, so each case starts from a fresh state
+    let mut deps = setup_test();
+
     // fund amount mismatch
     let deposits = vec![Coin::new(12345, "uluna"), Coin::new(69420, "uusd")];
     let msg = ExecuteMsg::UpdatePosition(vec![
@@ -80,10 +217,14 @@ fn handling_native_deposits() {
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
     assert_eq!(
         res,
-        Err(StdError::generic_err("sent fund mismatch! expected: uusd:67890, received 69420"))
+        Err(ContractError::SentFundMismatch {
+            expected: "uusd:67890".to_string(),
+            received: Uint128::new(69420),
+        })
     );
 
     // extra fund
+    let mut deps = setup_test();
     let deposits =
         vec![Coin::new(12345, "uluna"), Coin::new(69420, "uusd"), Coin::new(88888, "uatom")];
     let msg = ExecuteMsg::UpdatePosition(vec![
@@ -91,5 +232,2011 @@ fn handling_native_deposits() {
         Action::Deposit(Asset::native("uusd", 69420u128).into()),
     ]);
     let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &deposits), msg);
-    assert_eq!(res, Err(StdError::generic_err("extra funds received: uatom:88888")));
+    assert_eq!(
+        res,
+        Err(ContractError::ExtraFundsReceived {
+            funds: "uatom:88888".to_string(),
+        })
+    );
+}
+
+#[test]
+fn migrating() {
+    let mut deps = setup_test();
+
+    // version is stored on instantiation
+    let version = cw2::get_contract_version(deps.as_ref().storage).unwrap();
+    assert_eq!(version.contract, CONTRACT_NAME);
+    assert_eq!(version.version, CONTRACT_VERSION);
+
+    // migrating to the same version is a no-op
+    let msg = MigrateMsg {
+        config_update: None,
+    };
+    migrate(deps.as_mut(), mock_env(), msg.clone()).unwrap();
+
+    // alice opened a position in v1.0.0, which has no accounting. all prices are read from the
+    // oracle after the migration
+    setup_markets(&mut deps, 1_000_000, 5_000_000);
+    deps.querier.set_oracle_price(&AssetInfo::native("uusd"), Decimal::one());
+    save_position(
+        &mut deps,
+        "alice",
+        &Position {
+            bond_units: Uint128::new(1_000_000),
+            debt_units: Uint128::new(1_000_000),
+            unlocked_assets: AssetList::from(vec![Asset::native("uusd", 1_000_000u128)]),
+        },
+    );
+
+    // contracts deployed before versioning are migrated from v1.0.0, which requires the max price
+    // age and the price source of ASTRO to be set explicitly
+    downgrade_to_v1_0_0(&mut deps);
+    let res = migrate(deps.as_mut(), mock_env(), msg.clone());
+    assert_eq!(
+        res,
+        Err(ContractError::MigrationParamMissing {
+            param: "max_price_age".to_string(),
+            version: "1.0.0".to_string(),
+        })
+    );
+
+    let mut config_update = ConfigUpdate {
+        max_price_age: Some(3600),
+        ..Default::default()
+    };
+    let v1_0_0_msg = MigrateMsg {
+        config_update: Some(config_update.clone()),
+    };
+    let res = migrate(deps.as_mut(), mock_env(), v1_0_0_msg);
+    assert_eq!(
+        res,
+        Err(ContractError::MigrationParamMissing {
+            param: "astro_price_source".to_string(),
+            version: "1.0.0".to_string(),
+        })
+    );
+
+    // new roles are assigned to governance, and operators are moved out of config
+    let astro_spot = PriceSourceUnchecked::AstroportSpot {
+        pair: PairUnchecked {
+            contract_addr: "astro_uusd_pair".to_string(),
+            liquidity_token: "astro_uusd_lp_token".to_string(),
+        },
+        max_deviation: Decimal::from_ratio(5u128, 100u128),
+    };
+    config_update.astro_price_source = Some(astro_spot.clone());
+    deps.querier.set_pool(
+        &Addr::unchecked("astro_uusd_pair"),
+        [
+            Asset::cw20(Addr::unchecked("astro_token"), 1_000_000u128),
+            Asset::native("uusd", 2_000_000u128),
+        ],
+        Uint128::new(1_000_000),
+    );
+    let v1_0_0_msg = MigrateMsg {
+        config_update: Some(config_update),
+    };
+    migrate(deps.as_mut(), mock_env(), v1_0_0_msg).unwrap();
+
+    let version = cw2::get_contract_version(deps.as_ref().storage).unwrap();
+    assert_eq!(version.version, CONTRACT_VERSION);
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let config: ConfigUnchecked = from_binary(&res).unwrap();
+    assert_eq!(config.guardian, "governance".to_string());
+    assert_eq!(config.fee_manager, "governance".to_string());
+    assert_eq!(config.operator_manager, "governance".to_string());
+    assert!(!config.permissionless_harvest);
+    assert_eq!(config.harvest_bounty, Decimal::zero());
+    assert_eq!(config.full_liquidation_ltv, config.max_ltv);
+    assert_eq!(config.max_price_age, 3600);
+
+    // alice's accounting isn't seeded by the migration. until her position is touched, her PnL is
+    // reported as if her cost basis were her current equity, i.e. 20,000,000 uusd of liquidity
+    // tokens plus 1,000,000 uusd unlocked, less 5,000,000 uusd of debt
+    let alice_addr = Addr::unchecked("alice");
+    assert!(!crate::state::ACCOUNTING.has(deps.as_ref().storage, &alice_addr));
+
+    let query_msg = QueryMsg::PositionPnl {
+        user: "alice".to_string(),
+    };
+    let res = query(deps.as_ref(), mock_env(), query_msg).unwrap();
+    let pnl: PositionPnlResponse = from_binary(&res).unwrap();
+    assert_eq!(pnl.accounting.cost_basis, Uint128::new(16_000_000));
+    assert_eq!(pnl.unrealized_pnl, SignedValue::default());
+
+    // her accounting is seeded the first time she updates her position, before her deposit is
+    // recorded on top of it
+    let execute_msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uusd", 500_000u128).into(),
+    )]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("alice", &[Coin::new(500_000, "uusd")]),
+        execute_msg,
+    )
+    .unwrap();
+
+    let accounting = crate::state::ACCOUNTING.load(deps.as_ref().storage, &alice_addr).unwrap();
+    assert_eq!(accounting.cost_basis, Uint128::new(16_500_000));
+    assert_eq!(accounting.value_deposited, Uint128::new(16_500_000));
+
+    // prices of the primary and secondary assets are read from the v1.0.0 oracle
+    let mars_oracle = PriceSourceUnchecked::MarsOracle {
+        oracle: OracleUnchecked {
+            contract_addr: "oracle".to_string(),
+        },
+    };
+    assert_eq!(config.primary_price_source, mars_oracle);
+    assert_eq!(config.secondary_price_source, mars_oracle);
+    assert_eq!(config.astro_price_source, astro_spot);
+
+    let query_msg = QueryMsg::Operators {
+        start_after: Some("governance".to_string()),
+        limit: Some(1),
+    };
+    let res = query(deps.as_ref(), mock_env(), query_msg).unwrap();
+    let operators: Vec<OperatorResponse> = from_binary(&res).unwrap();
+    assert_eq!(
+        operators,
+        vec![OperatorResponse {
+            operator: "legacy_operator".to_string(),
+            info: OperatorInfo {
+                added_at_height: mock_env().block.height,
+                expires_at: None,
+            },
+        }]
+    );
+
+    // cannot migrate from a different contract
+    cw2::set_contract_version(deps.as_mut().storage, "crates.io:cw20-base", "0.9.1").unwrap();
+    let res = migrate(deps.as_mut(), mock_env(), msg.clone());
+    assert_eq!(
+        res,
+        Err(ContractError::InvalidContractName {
+            expected: CONTRACT_NAME.to_string(),
+            actual: "crates.io:cw20-base".to_string(),
+        })
+    );
+
+    // cannot downgrade
+    cw2::set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "99.0.0").unwrap();
+    let res = migrate(deps.as_mut(), mock_env(), msg.clone());
+    assert_eq!(
+        res,
+        Err(ContractError::DowngradeNotAllowed {
+            from: "99.0.0".to_string(),
+            to: CONTRACT_VERSION.to_string(),
+        })
+    );
+
+    // cannot migrate from an unknown version
+    cw2::set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.1.0").unwrap();
+    let res = migrate(deps.as_mut(), mock_env(), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::UnknownVersion {
+            version: "0.1.0".to_string(),
+        })
+    );
+}
+
+#[test]
+fn transferring_governance() {
+    let mut deps = setup_test();
+
+    // only governance can propose
+    let msg = ExecuteMsg::ProposeNewGovernance {
+        new_governance: "new_governance".to_string(),
+        expires_in: Some(100),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::PendingGovernance {}).unwrap();
+    let pending: Option<PendingGovernanceUnchecked> = from_binary(&res).unwrap();
+    assert_eq!(
+        pending,
+        Some(PendingGovernanceUnchecked {
+            new_governance: "new_governance".to_string(),
+            expires_at: Some(mock_env().block.time.seconds() + 100),
+        })
+    );
+
+    // only the proposed account can accept
+    let msg = ExecuteMsg::AcceptGovernance {};
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    // cannot accept once expired
+    let mut env = mock_env();
+    env.block.time = env.block.time.plus_seconds(101);
+    let res = execute(deps.as_mut(), env, mock_info("new_governance", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::GovernanceProposalExpired {}));
+
+    execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg.clone()).unwrap();
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let config: ConfigUnchecked = from_binary(&res).unwrap();
+    assert_eq!(config.governance, "new_governance".to_string());
+
+    // proposal is consumed once accepted
+    let res = execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg);
+    assert_eq!(res, Err(ContractError::NoPendingGovernance {}));
+
+    // governance can cancel a proposal
+    let msg = ExecuteMsg::ProposeNewGovernance {
+        new_governance: "typo".to_string(),
+        expires_in: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg).unwrap();
+
+    let msg = ExecuteMsg::CancelGovernanceProposal {};
+    execute(deps.as_mut(), mock_env(), mock_info("new_governance", &[]), msg).unwrap();
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::PendingGovernance {}).unwrap();
+    let pending: Option<PendingGovernanceUnchecked> = from_binary(&res).unwrap();
+    assert_eq!(pending, None);
+}
+
+#[test]
+fn updating_fee_config() {
+    let mut deps = setup_test();
+
+    // only fee manager can update fees
+    let msg = ExecuteMsg::UpdateFeeConfig {
+        performance_fee: Some(Decimal::from_ratio(1u128, 10u128)),
+        treasury: Some("new_treasury".to_string()),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    execute(deps.as_mut(), mock_env(), mock_info("fee_manager", &[]), msg).unwrap();
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let config: ConfigUnchecked = from_binary(&res).unwrap();
+    assert_eq!(config.performance_fee, Decimal::from_ratio(1u128, 10u128));
+    assert_eq!(config.treasury, "new_treasury".to_string());
+}
+
+#[test]
+fn managing_operators() {
+    let mut deps = setup_test();
+
+    // only operator manager can add operators
+    let msg = ExecuteMsg::AddOperator {
+        operator: "keeper".to_string(),
+        expires_in: Some(3600),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    execute(deps.as_mut(), mock_env(), mock_info("operator_manager", &[]), msg).unwrap();
+
+    // operators are enumerated in order of their addresses
+    let msg = QueryMsg::Operators {
+        start_after: None,
+        limit: None,
+    };
+    let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+    let operators: Vec<OperatorResponse> = from_binary(&res).unwrap();
+    let expires_at = mock_env().block.time.seconds() + 3600;
+    assert_eq!(
+        operators,
+        vec![
+            OperatorResponse {
+                operator: "keeper".to_string(),
+                info: OperatorInfo {
+                    added_at_height: mock_env().block.height,
+                    expires_at: Some(expires_at),
+                },
+            },
+            OperatorResponse {
+                operator: "operator".to_string(),
+                info: OperatorInfo {
+                    added_at_height: mock_env().block.height,
+                    expires_at: None,
+                },
+            },
+        ]
+    );
+
+    let msg = QueryMsg::Operators {
+        start_after: Some("keeper".to_string()),
+        limit: Some(1),
+    };
+    let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+    let operators: Vec<OperatorResponse> = from_binary(&res).unwrap();
+    assert_eq!(operators.len(), 1);
+    assert_eq!(operators[0].operator, "operator".to_string());
+
+    // non-operators and operators whose role has expired cannot harvest
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    let mut env = mock_env();
+    env.block.time = env.block.time.plus_seconds(3601);
+    let res = execute(deps.as_mut(), env, mock_info("keeper", &[]), msg);
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    // only operator manager can remove operators, and only existing ones
+    let msg = ExecuteMsg::RemoveOperator {
+        operator: "keeper".to_string(),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    execute(deps.as_mut(), mock_env(), mock_info("operator_manager", &[]), msg.clone()).unwrap();
+
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator_manager", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::OperatorNotFound {
+            operator: "keeper".to_string(),
+        })
+    );
+}
+
+#[test]
+fn updating_config() {
+    let mut deps = setup_test();
+
+    // only governance can update config
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            max_initial_ltv: Some(Decimal::from_ratio(90u128, 100u128)),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("fee_manager", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    // the resulting config is validated; max initial ltv must not exceed max ltv
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "max initial ltv",
+            "0.9; must be <= max ltv 0.83",
+        )))
+    );
+
+    // only provided parameters are updated, and the diff is recorded
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            max_ltv: Some(Decimal::from_ratio(90u128, 100u128)),
+            max_initial_ltv: Some(Decimal::from_ratio(80u128, 100u128)),
+            permissionless_harvest: Some(false),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+    assert_eq!(
+        res.events,
+        vec![Event::new("config_updated").add_attributes(vec![
+            attr("max_ltv_before", "0.83"),
+            attr("max_ltv_after", "0.9"),
+            attr("max_initial_ltv_before", "0.75"),
+            attr("max_initial_ltv_after", "0.8"),
+        ])]
+    );
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let config: ConfigUnchecked = from_binary(&res).unwrap();
+    assert_eq!(config.max_ltv, Decimal::from_ratio(90u128, 100u128));
+    assert_eq!(config.max_initial_ltv, Decimal::from_ratio(80u128, 100u128));
+    assert_eq!(config.bonus_rate, Decimal::from_ratio(1u128, 100u128));
+
+    // full liquidation ltv must not be below max ltv
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            full_liquidation_ltv: Some(Decimal::from_ratio(85u128, 100u128)),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "full liquidation ltv",
+            "0.85; must be >= max ltv 0.9",
+        )))
+    );
+}
+
+#[test]
+fn pausing() {
+    let mut deps = setup_test();
+
+    // only guardian or governance can pause
+    let msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            deposit: Some(true),
+            liquidate: Some(true),
+            harvest: Some(false),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("operator", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    // an event is emitted for each flag that is toggled; `harvest` is already unpaused
+    let res = execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), msg).unwrap();
+    assert_eq!(
+        res.events,
+        vec![
+            Event::new("pause_flag_updated")
+                .add_attribute("flag", "deposit")
+                .add_attribute("paused", "true")
+                .add_attribute("sender", "guardian"),
+            Event::new("pause_flag_updated")
+                .add_attribute("flag", "liquidate")
+                .add_attribute("paused", "true")
+                .add_attribute("sender", "guardian"),
+        ]
+    );
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::PauseFlags {}).unwrap();
+    let pause_flags: PauseFlags = from_binary(&res).unwrap();
+    assert_eq!(
+        pause_flags,
+        PauseFlags {
+            deposit: true,
+            liquidate: true,
+            ..Default::default()
+        }
+    );
+
+    // paused actions are rejected
+    let deposit_amount = Uint128::new(100);
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Deposit(
+        Asset::native("uluna", deposit_amount).into(),
+    )]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("alice", &[Coin::new(deposit_amount.u128(), "uluna")]),
+        msg,
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::ActionPaused {
+            action: "deposit".to_string(),
+        })
+    );
+
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        repay_amount: None,
+        max_spread: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::ActionPaused {
+            action: "liquidate".to_string(),
+        })
+    );
+
+    // repaying and unbonding are always allowed
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Unbond {
+            bond_units_to_reduce: Uint128::new(100),
+        },
+        Action::Repay {
+            amount: Uint128::new(100),
+        },
+    ]);
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+    // governance can unpause
+    let msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            deposit: Some(false),
+            ..Default::default()
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg).unwrap();
+
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::PauseFlags {}).unwrap();
+    let pause_flags: PauseFlags = from_binary(&res).unwrap();
+    assert!(!pause_flags.deposit);
+}
+
+#[test]
+fn harvesting_permissionlessly() {
+    let mut deps = setup_test();
+
+    // by default, only operators can harvest
+    let msg = ExecuteMsg::Harvest {
+        max_spread: None,
+        slippage_tolerance: None,
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("keeper", &[]), msg.clone());
+    assert_eq!(res, Err(ContractError::Unauthorized {}));
+
+    // bounty is validated together with the performance fee
+    let update_msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            permissionless_harvest: Some(true),
+            harvest_bounty: Some(Decimal::from_ratio(1u128, 100u128)),
+            min_harvest_interval: Some(3600),
+            performance_fee: Some(Decimal::from_ratio(995u128, 1000u128)),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), update_msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "fee rate",
+            "0.995 + harvest bounty 0.01; must be <= 1",
+        )))
+    );
+
+    let update_msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            permissionless_harvest: Some(true),
+            harvest_bounty: Some(Decimal::from_ratio(1u128, 100u128)),
+            min_harvest_interval: Some(3600),
+            ..Default::default()
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), update_msg).unwrap();
+
+    // anyone can harvest now, but not before the minimum interval has elapsed
+    let mut state = crate::state::STATE.load(deps.as_ref().storage).unwrap();
+    state.last_harvest = mock_env().block.time.seconds();
+    crate::state::STATE.save(deps.as_mut().storage, &state).unwrap();
+
+    let mut env = mock_env();
+    env.block.time = env.block.time.plus_seconds(1800);
+    let res = execute(deps.as_mut(), env, mock_info("keeper", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::HarvestTooSoon {
+            next_harvest: mock_env().block.time.seconds() + 3600,
+        })
+    );
+}
+
+#[test]
+fn zapping() {
+    let mut deps = setup_test();
+
+    // only primary or secondary asset can be zapped
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Zap {
+        asset: Asset::native("uatom", 12345u128).into(),
+        max_spread: None,
+        slippage_tolerance: None,
+    }]);
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &[Coin::new(12345, "uatom")]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::InvalidOfferAsset {
+            asset: "uatom".to_string(),
+        })
+    );
+
+    // the deposited asset is balanced, provided and bonded, before leftovers are refunded
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::Zap {
+        asset: Asset::native("uusd", 69420u128).into(),
+        max_spread: Some(Decimal::from_ratio(1u128, 100u128)),
+        slippage_tolerance: Some(Decimal::from_ratio(2u128, 100u128)),
+    }]);
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &[Coin::new(69420, "uusd")]), msg)
+            .unwrap();
+
+    let contract_addr = mock_env().contract.address;
+    let user_addr = Addr::unchecked("alice");
+    let callbacks = [
+        CallbackMsg::Balance {
+            user_addr: Some(user_addr.clone()),
+            max_spread: Some(Decimal::from_ratio(1u128, 100u128)),
+        },
+        CallbackMsg::ProvideLiquidity {
+            user_addr: Some(user_addr.clone()),
+            slippage_tolerance: Some(Decimal::from_ratio(2u128, 100u128)),
+        },
+        CallbackMsg::Bond {
+            user_addr: Some(user_addr.clone()),
+        },
+        CallbackMsg::Refund {
+            user_addr: user_addr.clone(),
+            recipient_addr: user_addr.clone(),
+            percentage: Decimal::one(),
+        },
+    ];
+    let expected: Vec<CosmosMsg> = callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&contract_addr).unwrap())
+        .collect();
+    let actual: Vec<CosmosMsg> =
+        res.messages[1..5].iter().map(|submsg| submsg.msg.clone()).collect();
+    assert_eq!(actual, expected);
+
+    let position = crate::state::POSITION.load(deps.as_ref().storage, &user_addr).unwrap();
+    assert_eq!(
+        position.unlocked_assets.find(&AssetInfo::native("uusd")),
+        Some(&Asset::native("uusd", 69420u128))
+    );
+}
+
+#[test]
+fn setting_leverage() {
+    let mut deps = setup_test();
+
+    // the amount to borrow or repay is computed in a callback, after previous actions
+    let msg = ExecuteMsg::UpdatePosition(vec![
+        Action::Deposit(Asset::native("uusd", 12345u128).into()),
+        Action::SetLeverage {
+            target_ltv: Decimal::from_ratio(5u128, 10u128),
+            max_spread: None,
+            slippage_tolerance: None,
+        },
+    ]);
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("alice", &[Coin::new(12345, "uusd")]), msg)
+            .unwrap();
+
+    let user_addr = Addr::unchecked("alice");
+    let callback = CallbackMsg::SetLeverage {
+        user_addr: user_addr.clone(),
+        target_ltv: Decimal::from_ratio(5u128, 10u128),
+        max_spread: None,
+        slippage_tolerance: None,
+    };
+    assert_eq!(
+        res.messages[1].msg,
+        callback.into_cosmos_msg(&mock_env().contract.address).unwrap()
+    );
+
+    // target LTV must not exceed max initial LTV
+    let msg = ExecuteMsg::Callback(CallbackMsg::SetLeverage {
+        user_addr,
+        target_ltv: Decimal::from_ratio(8u128, 10u128),
+        max_spread: None,
+        slippage_tolerance: None,
+    });
+    let contract_addr = mock_env().contract.address;
+    let res = execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::LtvTooHigh {
+            ltv: Some(Decimal::from_ratio(8u128, 10u128)),
+            max: Decimal::from_ratio(75u128, 100u128),
+        })
+    );
+}
+
+#[test]
+fn setting_leverage_to_target_ltv() {
+    let mut deps = setup_test();
+    let contract_addr = mock_env().contract.address;
+    let user_addr = Addr::unchecked("alice");
+
+    // alice has all 1,000,000 of the contract's bonded liquidity tokens, worth 20,000,000 uusd, and
+    // all of its 5,000,000 uusd debt, i.e. her LTV is 0.25
+    setup_markets(&mut deps, 1_000_000, 5_000_000);
+    save_position(
+        &mut deps,
+        "alice",
+        &Position {
+            bond_units: Uint128::new(1_000_000),
+            debt_units: Uint128::new(1_000_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+    let bond_value = 20_000_000u128;
+    let debt_value = 5_000_000u128;
+
+    let set_leverage = |deps: &mut MockDeps, target_ltv: Decimal| {
+        let msg = ExecuteMsg::Callback(CallbackMsg::SetLeverage {
+            user_addr: user_addr.clone(),
+            target_ltv,
+            max_spread: None,
+            slippage_tolerance: None,
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg).unwrap()
+    };
+    let callback_msgs = |callbacks: Vec<CallbackMsg>| {
+        callbacks
+            .iter()
+            .map(|callback| callback.into_cosmos_msg(&contract_addr).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // borrowing 10,000,000 uusd and bonding it brings the LTV up to 0.5
+    let target_ltv = Decimal::from_ratio(1u128, 2u128);
+    let res = set_leverage(&mut deps, target_ltv);
+    let borrow_amount = 10_000_000u128;
+    assert_eq!(
+        Decimal::from_ratio(debt_value + borrow_amount, bond_value + borrow_amount),
+        target_ltv
+    );
+    assert_eq!(
+        res.messages.iter().map(|sub_msg| sub_msg.msg.clone()).collect::<Vec<_>>(),
+        callback_msgs(vec![
+            CallbackMsg::Borrow {
+                user_addr: user_addr.clone(),
+                borrow_amount: Uint128::new(borrow_amount),
+            },
+            CallbackMsg::Balance {
+                user_addr: Some(user_addr.clone()),
+                max_spread: None,
+            },
+            CallbackMsg::ProvideLiquidity {
+                user_addr: Some(user_addr.clone()),
+                slippage_tolerance: None,
+            },
+            CallbackMsg::Bond {
+                user_addr: Some(user_addr.clone()),
+            },
+        ])
+    );
+
+    // unbonding liquidity tokens worth 1,250,000 uusd and repaying that amount brings the LTV down
+    // to 0.2. nothing is left to rebond
+    let target_ltv = Decimal::from_ratio(1u128, 5u128);
+    let res = set_leverage(&mut deps, target_ltv);
+    let repay_amount = 1_250_000u128;
+    assert_eq!(
+        Decimal::from_ratio(debt_value - repay_amount, bond_value - repay_amount),
+        target_ltv
+    );
+    assert_eq!(
+        res.messages.iter().map(|sub_msg| sub_msg.msg.clone()).collect::<Vec<_>>(),
+        callback_msgs(vec![
+            CallbackMsg::Unbond {
+                user_addr: user_addr.clone(),
+                bond_units_to_reduce: Uint128::new(62_500),
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: user_addr.clone(),
+            },
+            CallbackMsg::Cover {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(Uint128::new(repay_amount)),
+                max_spread: None,
+            },
+            CallbackMsg::Repay {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(Uint128::new(repay_amount)),
+            },
+        ])
+    );
+
+    // the position is already at the target LTV, so there is nothing to borrow, repay or rebond
+    let res = set_leverage(&mut deps, Decimal::from_ratio(1u128, 4u128));
+    assert_eq!(res.messages, vec![]);
+    assert!(res.attributes.contains(&attr("secondary_borrowed", "0")));
+}
+
+#[test]
+fn simulating_update_position() {
+    let mut deps = setup_test();
+    let contract_addr = mock_env().contract.address;
+    let user_addr = Addr::unchecked("alice");
+
+    // alice has all 1,000,000 of the contract's bonded liquidity tokens, worth 20,000,000 uusd, and
+    // all of its 5,000,000 uusd debt
+    setup_markets(&mut deps, 1_000_000, 5_000_000);
+    save_position(
+        &mut deps,
+        "alice",
+        &Position {
+            bond_units: Uint128::new(1_000_000),
+            debt_units: Uint128::new(1_000_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+
+    let actions = vec![
+        Action::Deposit(Asset::native("uusd", 1_000_000u128).into()),
+        Action::Borrow {
+            amount: Uint128::new(500_000),
+        },
+        Action::Repay {
+            amount: Uint128::new(1_500_000),
+        },
+    ];
+    let funds = vec![Coin::new(1_000_000, "uusd")];
+
+    let query_msg = QueryMsg::SimulateUpdatePosition {
+        user: "alice".to_string(),
+        actions: actions.clone(),
+        funds: funds.clone(),
+    };
+    let res = query(deps.as_ref(), mock_env(), query_msg).unwrap();
+    let simulated: SimulateUpdatePositionResponse = from_binary(&res).unwrap();
+    assert_eq!(simulated.error, None);
+
+    // execute the same actions and the callbacks they dispatch, updating the debt the contract owes
+    // Red Bank as the borrow and repay messages would
+    let msg = ExecuteMsg::UpdatePosition(actions);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &funds), msg).unwrap();
+    let mut total_debt_amount = Uint128::new(5_000_000);
+    for submsg in res.messages {
+        let callback = match submsg.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: target,
+                msg,
+                ..
+            }) if target == contract_addr.as_str() => match from_binary(&msg).unwrap() {
+                ExecuteMsg::Callback(callback) => callback,
+                _ => panic!("unexpected message"),
+            },
+            // the message updating rewards at Apollo factory
+            _ => continue,
+        };
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(contract_addr.as_str(), &[]),
+            ExecuteMsg::Callback(callback.clone()),
+        )
+        .unwrap();
+        match callback {
+            CallbackMsg::Borrow {
+                borrow_amount,
+                ..
+            } => total_debt_amount += borrow_amount,
+            CallbackMsg::Repay {
+                ..
+            } => {
+                let repaid = res.attributes.iter().find(|attr| attr.key == "secondary_repaid");
+                total_debt_amount -= Uint128::from_str(&repaid.unwrap().value).unwrap();
+            }
+            _ => (),
+        }
+        deps.querier.set_debt_amount(&contract_addr, total_debt_amount);
+    }
+
+    // the simulated position and health match those resulting from the execution
+    let res = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Position {
+            user: "alice".to_string(),
+        },
+    )
+    .unwrap();
+    let position: PositionUnchecked = from_binary(&res).unwrap();
+    assert_eq!(simulated.position, position);
+    assert_eq!(position.debt_units, Uint128::new(800_000));
+
+    let res = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Health {
+            user: "alice".to_string(),
+        },
+    )
+    .unwrap();
+    let health: Health = from_binary(&res).unwrap();
+    assert_eq!(simulated.health, health);
+
+    // the simulation applies the same minimum position size check as the `AssertHealth` callback
+    let mut config = crate::state::CONFIG.load(deps.as_ref().storage).unwrap();
+    config.min_position_size = Uint128::new(30_000_000);
+    crate::state::CONFIG.save(deps.as_mut().storage, &config).unwrap();
+    let query_msg = QueryMsg::SimulateUpdatePosition {
+        user: "alice".to_string(),
+        actions: vec![],
+        funds: vec![],
+    };
+    let res = query(deps.as_ref(), mock_env(), query_msg).unwrap();
+    let simulated: SimulateUpdatePositionResponse = from_binary(&res).unwrap();
+    let expected = ContractError::PositionTooSmall {
+        size: Uint128::new(20_000_000),
+        min: Uint128::new(30_000_000),
+    };
+    assert_eq!(simulated.error, Some(expected.to_string()));
+
+    let msg = ExecuteMsg::Callback(CallbackMsg::AssertHealth {
+        user_addr,
+    });
+    let res = execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg);
+    assert_eq!(res, Err(expected));
+}
+
+#[test]
+fn closing_position() {
+    let mut deps = setup_test();
+
+    // closing a position is allowed even if all other actions are paused
+    let msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            deposit: Some(true),
+            borrow: Some(true),
+            bond: Some(true),
+            swap: Some(true),
+            harvest: Some(true),
+            liquidate: Some(true),
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), msg).unwrap();
+
+    let msg = ExecuteMsg::UpdatePosition(vec![Action::ClosePosition {
+        max_spread: Some(Decimal::from_ratio(1u128, 100u128)),
+    }]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), msg).unwrap();
+
+    let user_addr = Addr::unchecked("alice");
+    let contract_addr = mock_env().contract.address;
+    let callback = CallbackMsg::ClosePosition {
+        user_addr: user_addr.clone(),
+        max_spread: Some(Decimal::from_ratio(1u128, 100u128)),
+    };
+    assert_eq!(res.messages[1].msg, callback.clone().into_cosmos_msg(&contract_addr).unwrap());
+
+    // the position has neither bond units nor debt, so there is nothing to unbond or repay
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(contract_addr.as_str(), &[]),
+        ExecuteMsg::Callback(callback),
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 0);
+
+    setup_markets(&mut deps, 2_000, 1_000);
+    save_position(
+        &mut deps,
+        "bob",
+        &Position {
+            bond_units: Uint128::new(1_000),
+            debt_units: Uint128::zero(),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+    save_position(
+        &mut deps,
+        "charlie",
+        &Position {
+            bond_units: Uint128::new(1_000),
+            debt_units: Uint128::new(1_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+
+    let close_position = |deps: &mut MockDeps, user: &str| {
+        let msg = ExecuteMsg::Callback(CallbackMsg::ClosePosition {
+            user_addr: Addr::unchecked(user),
+            max_spread: None,
+        });
+        let res = execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg)
+            .unwrap();
+        res.messages.into_iter().map(|submsg| submsg.msg).collect::<Vec<_>>()
+    };
+    let into_cosmos_msgs = |callbacks: Vec<CallbackMsg>| {
+        callbacks
+            .iter()
+            .map(|callback| callback.into_cosmos_msg(&contract_addr).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // a position without debt is unbonded and withdrawn, but not covered or repaid
+    let bob = Addr::unchecked("bob");
+    assert_eq!(
+        close_position(&mut deps, "bob"),
+        into_cosmos_msgs(vec![
+            CallbackMsg::Unbond {
+                user_addr: bob.clone(),
+                bond_units_to_reduce: Uint128::new(1_000),
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: bob.clone(),
+            },
+        ])
+    );
+
+    // a position with debt is also covered and repaid
+    let charlie = Addr::unchecked("charlie");
+    assert_eq!(
+        close_position(&mut deps, "charlie"),
+        into_cosmos_msgs(vec![
+            CallbackMsg::Unbond {
+                user_addr: charlie.clone(),
+                bond_units_to_reduce: Uint128::new(1_000),
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: charlie.clone(),
+            },
+            CallbackMsg::Cover {
+                user_addr: charlie.clone(),
+                repay_amount: None,
+                max_spread: None,
+            },
+            CallbackMsg::Repay {
+                user_addr: charlie,
+                repay_amount: None,
+            },
+        ])
+    );
+
+    // Red Bank rejects zero-amount repayments, so none is sent for a position without debt
+    let msg = ExecuteMsg::Callback(CallbackMsg::Repay {
+        user_addr: bob,
+        repay_amount: None,
+    });
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg).unwrap();
+    assert_eq!(res, Response::default());
+
+    // once closed, the position passes the health check even if a minimum position size is set
+    let mut config = crate::state::CONFIG.load(deps.as_ref().storage).unwrap();
+    config.min_position_size = Uint128::new(1_000_000);
+    crate::state::CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    let assert_health = |deps: &mut MockDeps, user: &str| {
+        let msg = ExecuteMsg::Callback(CallbackMsg::AssertHealth {
+            user_addr: Addr::unchecked(user),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg)
+    };
+    assert!(assert_health(&mut deps, "alice").is_ok());
+
+    // an open position is still subject to it. bob's 1,000 liquidity tokens are worth 20,000 uusd
+    assert_eq!(
+        assert_health(&mut deps, "bob"),
+        Err(ContractError::PositionTooSmall {
+            size: Uint128::new(20_000),
+            min: Uint128::new(1_000_000),
+        })
+    );
+}
+
+#[test]
+fn liquidating_with_max_spread() {
+    let mut deps = setup_test();
+
+    // liquidators can't raise the max spread above the configured one
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        repay_amount: None,
+        max_spread: Some(Decimal::from_ratio(10u128, 100u128)),
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::MaxSpreadTooHigh {
+            max_spread: Decimal::from_ratio(10u128, 100u128),
+            max: Decimal::from_ratio(5u128, 100u128),
+        })
+    );
+
+    // the same goes for liquidators repaying with their own funds, even though no swap is made
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        repay_amount: Some(Uint128::new(1000)),
+        max_spread: Some(Decimal::from_ratio(10u128, 100u128)),
+    };
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("liquidator", &[Coin::new(1000, "uusd")]),
+        msg,
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::MaxSpreadTooHigh {
+            max_spread: Decimal::from_ratio(10u128, 100u128),
+            max: Decimal::from_ratio(5u128, 100u128),
+        })
+    );
+
+    // the configured max spread is bounded
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            liquidation_max_spread: Some(Decimal::from_ratio(60u128, 100u128)),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "liquidation max spread",
+            "0.6; must be in (0, 0.5]",
+        )))
+    );
+
+    // the belief price is the amount of primary asset paid per unit of secondary asset
+    let belief_price =
+        compute_belief_price(Decimal::from_ratio(20u128, 1u128), Decimal::from_ratio(1u128, 1u128));
+    assert_eq!(belief_price, Some(Decimal::from_ratio(1u128, 20u128)));
+    assert_eq!(compute_belief_price(Decimal::zero(), Decimal::one()), None);
+}
+
+#[test]
+fn liquidating_with_funds() {
+    let mut deps = setup_test();
+
+    // the liquidator must send exactly the specified amount of secondary asset
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        repay_amount: Some(Uint128::new(100)),
+        max_spread: None,
+    };
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("liquidator", &[Coin::new(99, "uusd")]),
+        msg.clone(),
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::SentFundMismatch {
+            expected: Asset::native("uusd", 100u128).to_string(),
+            received: Uint128::new(99),
+        })
+    );
+
+    // no other funds can be sent
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("liquidator", &[Coin::new(100, "uusd"), Coin::new(100, "uluna")]),
+        msg.clone(),
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::ExtraFundsReceived {
+            funds: AssetList::from(vec![Asset::native("uluna", 100u128)]).to_string(),
+        })
+    );
+
+    // liquidations with funds are also subject to the pause flag
+    let pause_msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            liquidate: Some(true),
+            ..Default::default()
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), pause_msg).unwrap();
+    let res =
+        execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[Coin::new(100, "uusd")]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::ActionPaused {
+            action: "liquidate".to_string(),
+        })
+    );
+
+    // unpause, and give alice a position with all of the contract's 1,000,000 bonded liquidity
+    // tokens, worth 20,000,000 uusd, and all of its 17,000,000 uusd debt, i.e. an LTV of 0.85
+    let unpause_msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            liquidate: Some(false),
+            ..Default::default()
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), unpause_msg).unwrap();
+    setup_markets(&mut deps, 1_000_000, 17_000_000);
+    save_position(
+        &mut deps,
+        "alice",
+        &Position {
+            bond_units: Uint128::new(1_000_000),
+            debt_units: Uint128::new(1_000_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+
+    // LTV is below the full liquidation LTV, so at most half of the debt, 8,500,000 uusd, can be
+    // repaid. the liquidator seizes liquidity tokens worth that plus the 1% bonus, i.e. 8,585,000
+    // uusd or 429,250 of alice's bond units
+    let user_addr = Addr::unchecked("alice");
+    let contract_addr = mock_env().contract.address;
+    let msg = ExecuteMsg::Liquidate {
+        user: "alice".to_string(),
+        repay_amount: Some(Uint128::new(10_000_000)),
+        max_spread: None,
+    };
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("liquidator", &[Coin::new(10_000_000, "uusd")]),
+        msg,
+    )
+    .unwrap();
+    let expected = [
+        CallbackMsg::Repay {
+            user_addr: user_addr.clone(),
+            repay_amount: Some(Uint128::new(8_500_000)),
+        },
+        CallbackMsg::Unbond {
+            user_addr: user_addr.clone(),
+            bond_units_to_reduce: Uint128::new(429_250),
+        },
+        CallbackMsg::WithdrawLiquidity {
+            user_addr: user_addr.clone(),
+        },
+        CallbackMsg::Refund {
+            user_addr: user_addr.clone(),
+            recipient_addr: Addr::unchecked("liquidator"),
+            percentage: Decimal::one(),
+        },
+    ];
+    // the first message updates user rewards at Apollo factory
+    assert_eq!(
+        res.messages[1..].iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>(),
+        expected
+            .iter()
+            .map(|callback| callback.into_cosmos_msg(&contract_addr).unwrap())
+            .collect::<Vec<_>>()
+    );
+
+    // repaying leaves alice with the funds in excess of the repaid amount, which are refunded to
+    // the liquidator. as liquidity is not actually withdrawn here, they are the only assets refunded
+    let callback = |deps: &mut MockDeps, callback: &CallbackMsg| {
+        let msg = ExecuteMsg::Callback(callback.clone());
+        execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg).unwrap()
+    };
+    callback(&mut deps, &expected[0]);
+    let res = callback(&mut deps, &expected[3]);
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "liquidator".to_string(),
+            amount: vec![Coin::new(1_500_000, "uusd")],
+        })
+    );
+
+    let position = crate::state::POSITION.load(deps.as_ref().storage, &user_addr).unwrap();
+    assert_eq!(position.debt_units, Uint128::new(500_000));
+    assert_eq!(position.unlocked_assets, AssetList::default());
+}
+
+#[test]
+fn liquidating_many() {
+    let mut deps = setup_test();
+
+    // the batch is refused as a whole if the TWAP is stale, before any position is evaluated
+    let msg = ExecuteMsg::LiquidateMany {
+        users: vec!["alice".to_string(), "bob".to_string()],
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg.clone());
+    assert_eq!(
+        res,
+        Err(ContractError::StalePrice {
+            last_updated: 0,
+        })
+    );
+
+    // the health of every position is derived from the same total values
+    let totals = TotalValues {
+        bonded_value: Uint128::new(10000),
+        debt_value: Uint128::new(8000),
+        bond_units: Uint128::new(1000),
+        debt_units: Uint128::new(800),
+        secondary_price: Decimal::one(),
+    };
+    let unhealthy = Position {
+        bond_units: Uint128::new(500),
+        debt_units: Uint128::new(450),
+        unlocked_assets: AssetList::default(),
+    };
+    let health = compute_position_health(&totals, &unhealthy);
+    assert_eq!(health.bond_value, Uint128::new(5000));
+    assert_eq!(health.debt_value, Uint128::new(4500));
+    assert_eq!(health.ltv, Some(Decimal::from_ratio(9u128, 10u128)));
+
+    let closed = Position {
+        bond_units: Uint128::zero(),
+        debt_units: Uint128::zero(),
+        unlocked_assets: AssetList::default(),
+    };
+    assert_eq!(compute_position_health(&totals, &closed).ltv, None);
+
+    // alice and bob each have half of the contract's 1,000,000 bonded liquidity tokens, worth
+    // 10,000,000 uusd. of the 10,500,000 uusd debt, alice owes 8,500,000 (LTV 0.85) and bob owes
+    // 2,000,000 (LTV 0.2). carol has no position
+    setup_markets(&mut deps, 1_000_000, 10_500_000);
+    save_position(
+        &mut deps,
+        "alice",
+        &Position {
+            bond_units: Uint128::new(500_000),
+            debt_units: Uint128::new(850_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+    save_position(
+        &mut deps,
+        "bob",
+        &Position {
+            bond_units: Uint128::new(500_000),
+            debt_units: Uint128::new(200_000),
+            unlocked_assets: AssetList::default(),
+        },
+    );
+
+    // only alice is liquidated, once; bob is healthy, carol's position is closed, and alice's second
+    // entry is a duplicate
+    let batch_msg = ExecuteMsg::LiquidateMany {
+        users: vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "carol".to_string(),
+            "alice".to_string(),
+        ],
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), batch_msg).unwrap();
+    assert!(res.attributes.contains(&attr("liquidated", "1")));
+    assert!(res.attributes.contains(&attr("skipped", "3")));
+    assert_eq!(res.events.len(), 1);
+    assert!(res.events[0].attributes.contains(&attr("user", "alice")));
+
+    // alice's LTV is below the full liquidation LTV, so she is liquidated partially
+    let config = crate::state::CONFIG.load(deps.as_ref().storage).unwrap();
+    let user_addr = Addr::unchecked("alice");
+    let contract_addr = mock_env().contract.address;
+    let repay_value = compute_partial_liquidation_value(
+        Uint128::new(8_500_000),
+        Uint128::new(10_000_000),
+        config.max_initial_ltv,
+        config.bonus_rate,
+        config.close_factor,
+    )
+    .unwrap();
+    let seize_value = repay_value + repay_value * config.bonus_rate;
+    let mut expected = vec![config.apollo_factory.update_rewards_msg(&user_addr).unwrap()];
+    expected.extend(
+        [
+            CallbackMsg::Unbond {
+                user_addr: user_addr.clone(),
+                bond_units_to_reduce: Uint128::new(500_000)
+                    .multiply_ratio(seize_value, Uint128::new(10_000_000)),
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: user_addr.clone(),
+            },
+            CallbackMsg::Cover {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(repay_value),
+                max_spread: None,
+            },
+            CallbackMsg::Repay {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(repay_value),
+            },
+            CallbackMsg::Refund {
+                user_addr,
+                recipient_addr: Addr::unchecked("liquidator"),
+                percentage: Decimal::one(),
+            },
+        ]
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&contract_addr).unwrap()),
+    );
+    assert_eq!(res.messages.iter().map(|submsg| submsg.msg.clone()).collect::<Vec<_>>(), expected);
+
+    // batch liquidations are subject to the same pause flag as individual ones
+    let pause_msg = ExecuteMsg::UpdatePauseFlags {
+        pause_flags_update: PauseFlagsUpdate {
+            liquidate: Some(true),
+            ..Default::default()
+        },
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("guardian", &[]), pause_msg).unwrap();
+    let res = execute(deps.as_mut(), mock_env(), mock_info("liquidator", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::ActionPaused {
+            action: "liquidate".to_string(),
+        })
+    );
+}
+
+#[test]
+fn checking_price_deviation() {
+    let oracle_price = Decimal::from_ratio(1u128, 20u128);
+    let tolerance = Decimal::from_ratio(5u128, 100u128);
+
+    // pool prices within 5% of the oracle price, in either direction, are accepted
+    assert_price_deviation(Decimal::from_ratio(21u128, 400u128), oracle_price, tolerance).unwrap();
+    assert_price_deviation(Decimal::from_ratio(19u128, 400u128), oracle_price, tolerance).unwrap();
+
+    let res = assert_price_deviation(Decimal::from_ratio(1u128, 10u128), oracle_price, tolerance);
+    assert_eq!(
+        res,
+        Err(ContractError::PriceDeviationTooHigh {
+            deviation: Decimal::one(),
+            max: tolerance,
+        })
+    );
+}
+
+#[test]
+fn computing_liquidation_price() {
+    let price = Decimal::from_ratio(20u128, 1u128);
+    let max_ltv = Decimal::from_ratio(8u128, 10u128);
+
+    // at 40% LTV, the bond value must halve for the LTV to reach 80%. as the bond value scales with
+    // the square root of the price, the price must drop to a quarter
+    let liquidation_price =
+        compute_liquidation_price(price, Uint128::new(10000), Uint128::new(4000), max_ltv);
+    assert_eq!(liquidation_price, Some(Decimal::from_ratio(5u128, 1u128)));
+
+    // positions without debt or bonded assets can't be liquidated
+    let res = compute_liquidation_price(price, Uint128::new(10000), Uint128::zero(), max_ltv);
+    assert_eq!(res, None);
+    let res = compute_liquidation_price(price, Uint128::zero(), Uint128::new(4000), max_ltv);
+    assert_eq!(res, None);
+}
+
+#[test]
+fn computing_twap() {
+    let mut deps = setup_test();
+
+    // no TWAP is available until two snapshots have been taken
+    let res = query(deps.as_ref(), mock_env(), QueryMsg::Twap {});
+    assert_eq!(res, Err(FieldsOfMarsError::TwapNotAvailable {}.into()));
+
+    // a price of 0.05 lasting for 600 seconds
+    let start = PriceSnapshot {
+        timestamp: 10000,
+        price_cumulative: Uint128::new(123_000000),
+    };
+    let end = PriceSnapshot {
+        timestamp: 10600,
+        price_cumulative: Uint128::new(153_000000),
+    };
+    assert_eq!(compute_twap(&start, &end), Ok(Decimal::from_ratio(1u128, 20u128)));
+
+    // the cumulative price may wrap around
+    let start = PriceSnapshot {
+        timestamp: 10000,
+        price_cumulative: Uint128::new(u128::MAX - 9_999999),
+    };
+    let end = PriceSnapshot {
+        timestamp: 10600,
+        price_cumulative: Uint128::new(20_000000),
+    };
+    assert_eq!(compute_twap(&start, &end), Ok(Decimal::from_ratio(1u128, 20u128)));
+
+    // the max price age must cover at least one TWAP window
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            max_price_age: Some(300),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "max price age",
+            "300; must be 0 or >= twap window 600",
+        )))
+    );
+}
+
+#[test]
+fn querying_prices() {
+    let mut deps = setup_test();
+
+    // the secondary asset's price is used to quote other assets, so it can't come from Astroport
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            secondary_price_source: Some(PriceSourceUnchecked::AstroportTwap {
+                pair: PairUnchecked {
+                    contract_addr: "uluna_uusd_pair".to_string(),
+                    liquidity_token: "uluna_uusd_lp_token".to_string(),
+                },
+            }),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "secondary price source",
+            "must be mars oracle or fixed price",
+        )))
+    );
+
+    let msg = ExecuteMsg::UpdateConfig {
+        config_update: ConfigUpdate {
+            secondary_price_source: Some(PriceSourceUnchecked::Fixed {
+                price: Decimal::zero(),
+            }),
+            ..Default::default()
+        },
+    };
+    let res = execute(deps.as_mut(), mock_env(), mock_info("governance", &[]), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::FieldsOfMars(FieldsOfMarsError::invalid_config(
+            "secondary price source",
+            "fixed price must be > 0",
+        )))
+    );
+
+    // fixed prices are returned as is
+    let mut config = crate::state::CONFIG.load(deps.as_ref().storage).unwrap();
+    let price = query_price(deps.as_ref(), &config, &AssetInfo::native("uusd")).unwrap();
+    assert_eq!(price, Decimal::one());
+
+    // price the primary asset by its TWAP in the primary pair, converted using the price of the
+    // secondary asset. the pair is set directly in storage, since validating it requires querying
+    config.primary_price_source = PriceSource::AstroportTwap {
+        pair: config.primary_pair.clone(),
+    };
+    crate::state::CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+    let res = query_price(deps.as_ref(), &config, &AssetInfo::native("uluna"));
+    assert_eq!(res, Err(FieldsOfMarsError::TwapNotAvailable {}.into()));
+
+    // a price of 0.05 lasting for 600 seconds
+    let snapshots = vec![
+        PriceSnapshot {
+            timestamp: 10000,
+            price_cumulative: Uint128::new(123_000000),
+        },
+        PriceSnapshot {
+            timestamp: 10600,
+            price_cumulative: Uint128::new(153_000000),
+        },
+    ];
+    let pair_addr = &config.primary_pair.contract_addr;
+    crate::state::PRICE_SNAPSHOTS.save(deps.as_mut().storage, pair_addr, &snapshots).unwrap();
+
+    let price = query_price(deps.as_ref(), &config, &AssetInfo::native("uluna")).unwrap();
+    assert_eq!(price, Decimal::from_ratio(1u128, 20u128));
+
+    // assets without a price source can't be priced
+    let res = query_price(deps.as_ref(), &config, &AssetInfo::native("ukrw"));
+    assert_eq!(
+        res,
+        Err(ContractError::NoPriceSource {
+            asset: "ukrw".to_string(),
+        })
+    );
+}
+
+#[test]
+fn enumerating_positions() {
+    let mut deps = setup_test();
+
+    // each bond unit is worth one liquidity token, i.e. 20 uusd, and each debt unit 10 uusd
+    setup_markets(&mut deps, 600, 4_000);
+    for (user, bond_units, debt_units) in
+        [("charlie", 300u128, 300u128), ("alice", 100, 100), ("bob", 200, 0)]
+    {
+        let position = Position {
+            bond_units: Uint128::new(bond_units),
+            debt_units: Uint128::new(debt_units),
+            unlocked_assets: AssetList::default(),
+        };
+        save_position(&mut deps, user, &position);
+    }
+
+    // positions are enumerated in order of the users' addresses
+    let msg = QueryMsg::Positions {
+        start_after: None,
+        limit: Some(2),
+    };
+    let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+    let positions: Vec<PositionResponse> = from_binary(&res).unwrap();
+    let users: Vec<&str> = positions.iter().map(|res| res.user.as_str()).collect();
+    assert_eq!(users, vec!["alice", "bob"]);
+    assert_eq!(positions[1].position.bond_units, Uint128::new(200));
+    assert_eq!(positions[1].position.debt_units, Uint128::zero());
+
+    let msg = QueryMsg::Positions {
+        start_after: Some("bob".to_string()),
+        limit: None,
+    };
+    let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+    let positions: Vec<PositionResponse> = from_binary(&res).unwrap();
+    assert_eq!(positions.len(), 1);
+    assert_eq!(positions[0].user, "charlie".to_string());
+
+    // positions filtered out by LTV count towards the limit. alice's and charlie's LTVs are 1,000 /
+    // 2,000 and 3,000 / 6,000 respectively, while bob has no debt
+    let query_positions_with_health = |deps: &MockDeps, start_after: Option<&str>| {
+        let msg = QueryMsg::PositionsWithHealth {
+            start_after: start_after.map(String::from),
+            limit: Some(2),
+            min_ltv: Some(Decimal::from_ratio(1u128, 10u128)),
+        };
+        let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+        let res: PositionsWithHealthResponse = from_binary(&res).unwrap();
+        let users: Vec<String> = res.positions.iter().map(|res| res.user.clone()).collect();
+        (users, res.last_scanned)
+    };
+    assert_eq!(
+        query_positions_with_health(&deps, None),
+        (vec!["alice".to_string()], Some("bob".to_string()))
+    );
+
+    // the enumeration continues from the last position scanned, until there are none left
+    let (users, last_scanned) = query_positions_with_health(&deps, Some("bob"));
+    assert_eq!(users, vec!["charlie".to_string()]);
+    assert_eq!(last_scanned, Some("charlie".to_string()));
+    assert_eq!(query_positions_with_health(&deps, Some("charlie")), (vec![], None));
+
+    let msg = QueryMsg::PositionsWithHealth {
+        start_after: Some("bob".to_string()),
+        limit: None,
+        min_ltv: None,
+    };
+    let res = query(deps.as_ref(), mock_env(), msg).unwrap();
+    let res: PositionsWithHealthResponse = from_binary(&res).unwrap();
+    assert_eq!(
+        res.positions[0].health,
+        Health {
+            bond_value: Uint128::new(6_000),
+            debt_value: Uint128::new(3_000),
+            ltv: Some(Decimal::from_ratio(1u128, 2u128)),
+        }
+    );
+}
+
+#[test]
+fn receiving_assets_in_replies() {
+    let mut deps = setup_test();
+    let contract_addr = mock_env().contract.address;
+    let user_addr = Addr::unchecked("larry");
+
+    // balances are recorded in the reply context before withdrawing liquidity
+    let balances = vec![Coin::new(100, "uluna"), Coin::new(200, "uusd")];
+    deps.querier.update_balance(contract_addr.clone(), balances);
+    let asset_infos = [&AssetInfo::native("uluna"), &AssetInfo::native("uusd")];
+    let deps_mut = deps.as_mut();
+    save_reply_context(
+        deps_mut.storage,
+        &deps_mut.querier,
+        &contract_addr,
+        ReplyId::WithdrawLiquidity,
+        Some(&user_addr),
+        &asset_infos,
+    )
+    .unwrap();
+
+    // another submessage can't be dispatched until the context is consumed
+    let deps_mut = deps.as_mut();
+    let res = save_reply_context(
+        deps_mut.storage,
+        &deps_mut.querier,
+        &contract_addr,
+        ReplyId::Swap,
+        None,
+        &asset_infos[1..],
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::ReplyContextNotConsumed {
+            id: 1,
+        })
+    );
+
+    // the context can only be consumed by the reply it was saved for
+    let empty_result = ContractResult::Ok(SubMsgExecutionResponse {
+        events: vec![],
+        data: None,
+    });
+    let msg = Reply {
+        id: 2,
+        result: empty_result.clone(),
+    };
+    let res = reply(deps.as_mut(), mock_env(), msg);
+    assert_eq!(
+        res,
+        Err(ContractError::InvalidReplyId {
+            id: 2,
+        })
+    );
+
+    // the amounts withdrawn are computed from the balance changes, even if Astroport's events
+    // can't be parsed
+    let balances = vec![Coin::new(150, "uluna"), Coin::new(260, "uusd")];
+    deps.querier.update_balance(contract_addr, balances);
+    let msg = Reply {
+        id: 1,
+        result: empty_result,
+    };
+    let res = reply(deps.as_mut(), mock_env(), msg).unwrap();
+    assert_eq!(
+        res.attributes,
+        vec![
+            attr("action", "martian_field/reply/after_withdraw_liquidity"),
+            attr("user", "larry"),
+            attr("primary_withdrawn", "50"),
+            attr("secondary_withdrawn", "60"),
+            attr("events_agree", "false"),
+        ]
+    );
+
+    let position = crate::state::POSITION.load(deps.as_ref().storage, &user_addr).unwrap();
+    assert_eq!(
+        position.unlocked_assets,
+        AssetList::from(vec![Asset::native("uluna", 50u128), Asset::native("uusd", 60u128)])
+    );
+
+    assert_eq!(crate::state::REPLY_CONTEXT.may_load(deps.as_ref().storage), Ok(None));
+}
+
+#[test]
+fn parsing_swap_events() {
+    let events = vec![Event::new("wasm")
+        .add_attribute("action", "swap")
+        .add_attribute("offer_asset", "uusd")
+        .add_attribute("ask_asset", "astro_token")
+        .add_attribute("return_amount", "1000")
+        .add_attribute("tax_amount", "10")];
+
+    // the ask asset is resolved among the known assets, regardless of the address prefix
+    let astro_token_info = AssetInfo::cw20(Addr::unchecked("astro_token"));
+    let known_asset_infos =
+        [&AssetInfo::native("uluna"), &AssetInfo::native("uusd"), &astro_token_info];
+    let returned_asset = Pair::parse_swap_events(&events, &known_asset_infos).unwrap();
+    assert_eq!(returned_asset, Asset::cw20(Addr::unchecked("astro_token"), 990u128));
+
+    let res = Pair::parse_swap_events(&events, &known_asset_infos[..2]);
+    assert_eq!(
+        res,
+        Err(FieldsOfMarsError::InvalidAttribute {
+            attribute: "ask_asset".to_string(),
+            value: "astro_token".to_string(),
+        })
+    );
+
+    // events of other actions are not mistaken for the swap
+    let res = Pair::parse_swap_events(&[Event::new("wasm").add_attribute("action", "send")], &[]);
+    assert_eq!(
+        res,
+        Err(FieldsOfMarsError::EventNotFound {
+            event: "swap".to_string(),
+        })
+    );
+
+    let res = Pair::parse_provide_events(&[Event::new("wasm")
+        .add_attribute("action", "provide_liquidity")
+        .add_attribute("assets", "100uluna, 100uusd")]);
+    assert_eq!(
+        res,
+        Err(FieldsOfMarsError::AttributeNotFound {
+            event: "provide_liquidity".to_string(),
+            attribute: "share".to_string(),
+        })
+    );
+}
+
+/// Simulate an Astroport XYK swap, returning the amount of ask asset received
+fn simulate_swap(offer: u128, offer_depth: u128, ask_depth: u128, commission_rate: u128) -> u128 {
+    let return_amount = ask_depth * offer / (offer_depth + offer);
+    return_amount - return_amount * commission_rate / 1000
+}
+
+#[test]
+fn computing_swap_amounts() {
+    let offer_depth = 1_000_000_000_000u128;
+    let ask_depth = 5_000_000_000_000u128;
+    let commission_rate = Decimal::from_ratio(3u128, 1000u128);
+
+    // swap returns match those of the constant product formula, up to rounding
+    let y = compute_swap_return(
+        Uint128::new(1_000_000_000),
+        Uint128::new(offer_depth),
+        Uint128::new(ask_depth),
+        commission_rate,
+    )
+    .unwrap();
+    let expected = simulate_swap(1_000_000_000, offer_depth, ask_depth, 3);
+    assert!(y.u128().abs_diff(expected) <= 1, "return amount {} != {}", y, expected);
+
+    // reverse-simulating the return gives back the offer amount
+    let x =
+        compute_swap_offer(y, Uint128::new(offer_depth), Uint128::new(ask_depth), commission_rate)
+            .unwrap();
+    assert!(x.u128().abs_diff(1_000_000_000) <= 1, "offer amount {} != 1000000000", x);
+
+    // the pool can't return more than its depth
+    let res = compute_swap_offer(
+        Uint128::new(ask_depth),
+        Uint128::new(offer_depth),
+        Uint128::new(ask_depth),
+        commission_rate,
+    );
+    assert_eq!(
+        res,
+        Err(ContractError::AskAmountExceedsDepth {
+            ask_amount: Uint128::new(ask_depth).multiply_ratio(1000u128, 997u128),
+            depth: Uint128::new(ask_depth),
+        })
+    );
+}
+
+#[test]
+fn computing_optimal_swap_amount() {
+    // we hold 1,000 ANC and 1,000 UST, while the pool has 1,000,000 ANC and 5,000,000 UST.
+    // ANC is in excess and should be swapped
+    let offer_amount = 1_000_000_000u128;
+    let ask_amount = 1_000_000_000u128;
+    let offer_depth = 1_000_000_000_000u128;
+    let ask_depth = 5_000_000_000_000u128;
+
+    let x = compute_optimal_swap_amount(
+        Uint128::new(offer_amount),
+        Uint128::new(ask_amount),
+        Uint128::new(offer_depth),
+        Uint128::new(ask_depth),
+        Decimal::from_ratio(3u128, 1000u128),
+    )
+    .unwrap()
+    .u128();
+
+    // after the swap, our holdings are in the same ratio as the pool depths
+    let y = simulate_swap(x, offer_depth, ask_depth, 3);
+    let lhs = (offer_amount - x) * (ask_depth - y);
+    let rhs = (offer_depth + x) * (ask_amount + y);
+    assert!(lhs.abs_diff(rhs) * 1_000_000 < lhs, "holdings not in pool ratio: {} != {}", lhs, rhs);
+
+    // nothing to swap if the offer asset is not in excess
+    let x = compute_optimal_swap_amount(
+        Uint128::new(ask_amount),
+        Uint128::new(offer_amount),
+        Uint128::new(ask_depth),
+        Uint128::new(offer_depth),
+        Decimal::from_ratio(3u128, 1000u128),
+    )
+    .unwrap();
+    assert_eq!(x, Uint128::zero());
+}
+
+#[test]
+fn computing_partial_liquidation_value() {
+    let target_ltv = Decimal::from_ratio(75u128, 100u128);
+    let bonus_rate = Decimal::from_ratio(5u128, 100u128);
+    let close_factor = Decimal::from_ratio(1u128, 2u128);
+
+    // debt 850, bonds 1000, LTV 0.85. repaying x = (850 - 750) / (1 - 0.75 * 1.05) ~= 470.59 and
+    // seizing x * 1.05 ~= 494.12 brings LTV to 379.41 / 505.88 ~= 0.75
+    let repay_value = compute_partial_liquidation_value(
+        Uint128::new(850_000000),
+        Uint128::new(1000_000000),
+        target_ltv,
+        bonus_rate,
+        Decimal::one(),
+    );
+    assert_eq!(repay_value, Some(Uint128::new(470_588235)));
+
+    // the repaid value is capped by the close factor
+    let repay_value = compute_partial_liquidation_value(
+        Uint128::new(850_000000),
+        Uint128::new(1000_000000),
+        target_ltv,
+        bonus_rate,
+        close_factor,
+    );
+    assert_eq!(repay_value, Some(Uint128::new(425_000000)));
+
+    // if the seized assets would exceed the bonded assets, the position is liquidated in full
+    let repay_value = compute_partial_liquidation_value(
+        Uint128::new(990_000000),
+        Uint128::new(1000_000000),
+        target_ltv,
+        bonus_rate,
+        Decimal::one(),
+    );
+    assert_eq!(repay_value, None);
+}
+
+#[test]
+fn accounting_position_pnl() {
+    let mut deps = setup_test();
+
+    // deposits are recorded at their value at the time of the deposit
+    let msg =
+        ExecuteMsg::UpdatePosition(vec![Action::Deposit(Asset::native("uusd", 12345u128).into())]);
+    execute(deps.as_mut(), mock_env(), mock_info("alice", &[Coin::new(12345, "uusd")]), msg)
+        .unwrap();
+
+    let accounting =
+        crate::state::ACCOUNTING.load(deps.as_ref().storage, &Addr::unchecked("alice")).unwrap();
+    assert_eq!(accounting.value_deposited, Uint128::new(12345));
+    assert_eq!(accounting.cost_basis, Uint128::new(12345));
+
+    // withdrawing a quarter of the equity releases a quarter of the cost basis
+    let mut accounting = PositionAccounting::default();
+    accounting.record_deposit(Uint128::new(1000));
+    accounting.record_withdrawal(Uint128::new(300), Uint128::new(1200));
+    assert_eq!(accounting.cost_basis, Uint128::new(750));
+    assert_eq!(accounting.realized_profit, Uint128::new(50));
+    assert_eq!(accounting.realized_loss, Uint128::zero());
+
+    // withdrawing all of the equity releases the remaining cost basis
+    accounting.record_withdrawal(Uint128::new(600), Uint128::new(600));
+    assert_eq!(accounting.value_withdrawn, Uint128::new(900));
+    assert_eq!(accounting.cost_basis, Uint128::zero());
+    assert_eq!(accounting.realized_loss, Uint128::new(150));
+
+    assert_eq!(
+        SignedValue::difference(accounting.realized_profit, accounting.realized_loss),
+        SignedValue {
+            amount: Uint128::new(100),
+            negative: true,
+        }
+    );
+
+    // bob has all 1,000,000 of the contract's bonded liquidity tokens, worth 20,000,000 uusd, and
+    // 3,000,000 uusd unlocked. he owes all of the contract's 5,000,000 uusd debt, and deposited
+    // 12,000,000 uusd worth of assets, i.e. his equity is 18,000,000 uusd
+    setup_markets(&mut deps, 1_000_000, 5_000_000);
+    save_position(
+        &mut deps,
+        "bob",
+        &Position {
+            bond_units: Uint128::new(1_000_000),
+            debt_units: Uint128::new(1_000_000),
+            unlocked_assets: AssetList::from(vec![Asset::native("uusd", 3_000_000u128)]),
+        },
+    );
+    let mut accounting = PositionAccounting::default();
+    accounting.record_deposit(Uint128::new(12_000_000));
+    crate::state::ACCOUNTING
+        .save(deps.as_mut().storage, &Addr::unchecked("bob"), &accounting)
+        .unwrap();
+
+    let query_pnl = |deps: &MockDeps| {
+        let msg = QueryMsg::PositionPnl {
+            user: "bob".to_string(),
+        };
+        from_binary::<PositionPnlResponse>(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+
+    // unlocked assets count towards the unrealized PnL
+    let pnl = query_pnl(&deps);
+    assert_eq!(pnl.realized_pnl, SignedValue::default());
+    assert_eq!(
+        pnl.unrealized_pnl,
+        SignedValue {
+            amount: Uint128::new(6_000_000),
+            negative: false,
+        }
+    );
+
+    // withdrawing half of the unlocked assets, 1,500,000 uusd out of the 18,000,000 uusd equity,
+    // releases 1,000,000 uusd of cost basis and realizes a profit of 500,000 uusd. the rest of the
+    // profit remains unrealized
+    let contract_addr = mock_env().contract.address;
+    let msg = ExecuteMsg::Callback(CallbackMsg::Refund {
+        user_addr: Addr::unchecked("bob"),
+        recipient_addr: Addr::unchecked("bob"),
+        percentage: Decimal::from_ratio(1u128, 2u128),
+    });
+    execute(deps.as_mut(), mock_env(), mock_info(contract_addr.as_str(), &[]), msg).unwrap();
+
+    let pnl = query_pnl(&deps);
+    assert_eq!(pnl.accounting.value_withdrawn, Uint128::new(1_500_000));
+    assert_eq!(pnl.accounting.cost_basis, Uint128::new(11_000_000));
+    assert_eq!(
+        pnl.realized_pnl,
+        SignedValue {
+            amount: Uint128::new(500_000),
+            negative: false,
+        }
+    );
+    assert_eq!(
+        pnl.unrealized_pnl,
+        SignedValue {
+            amount: Uint128::new(5_500_000),
+            negative: false,
+        }
+    );
 }
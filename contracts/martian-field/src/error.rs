@@ -0,0 +1,162 @@
+use cosmwasm_std::{Decimal, OverflowError, StdError, Uint128};
+use thiserror::Error;
+
+use fields_of_mars::error::FieldsOfMarsError;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("{0}")]
+    Overflow(#[from] OverflowError),
+
+    #[error("{0}")]
+    FieldsOfMars(#[from] FieldsOfMarsError),
+
+    #[error("unauthorized")]
+    Unauthorized {},
+
+    #[error("no pending governance proposal")]
+    NoPendingGovernance {},
+
+    #[error("governance proposal expired")]
+    GovernanceProposalExpired {},
+
+    #[error("{operator} is not an operator")]
+    OperatorNotFound {
+        operator: String,
+    },
+
+    #[error("cannot harvest until {next_harvest}")]
+    HarvestTooSoon {
+        next_harvest: u64,
+    },
+
+    #[error("{action} is paused")]
+    ActionPaused {
+        action: String,
+    },
+
+    #[error("position is already closed")]
+    PositionClosed {},
+
+    #[error("position is healthy")]
+    PositionHealthy {},
+
+    #[error(
+        "ltv greater than threshold: {}; max: {max}",
+        .ltv.map_or_else(|| "null".to_string(), |ltv| ltv.to_string())
+    )]
+    LtvTooHigh {
+        ltv: Option<Decimal>,
+        max: Decimal,
+    },
+
+    #[error("max spread {max_spread} greater than maximum of {max}")]
+    MaxSpreadTooHigh {
+        max_spread: Decimal,
+        max: Decimal,
+    },
+
+    #[error("pool price deviates from oracle price by {deviation}; max: {max}")]
+    PriceDeviationTooHigh {
+        deviation: Decimal,
+        max: Decimal,
+    },
+
+    #[error("price is stale; last updated at {last_updated}")]
+    StalePrice {
+        last_updated: u64,
+    },
+
+    #[error("no price source for asset {asset}")]
+    NoPriceSource {
+        asset: String,
+    },
+
+    #[error("twap period must be greater than zero")]
+    ZeroTwapPeriod {},
+
+    #[error("offer amount too large relative to pool depth")]
+    OfferAmountTooLarge {},
+
+    #[error("ask amount {ask_amount} exceeds pool depth {depth}")]
+    AskAmountExceedsDepth {
+        ask_amount: Uint128,
+        depth: Uint128,
+    },
+
+    #[error("position size {size} less than minimum size of {min}")]
+    PositionTooSmall {
+        size: Uint128,
+        min: Uint128,
+    },
+
+    #[error("extra funds received: {funds}")]
+    ExtraFundsReceived {
+        funds: String,
+    },
+
+    #[error("sent fund mismatch! expected: {expected}, received {received}")]
+    SentFundMismatch {
+        expected: String,
+        received: Uint128,
+    },
+
+    #[error("no {asset} available")]
+    AssetNotAvailable {
+        asset: String,
+    },
+
+    #[error("invalid offer asset: {asset}")]
+    InvalidOfferAsset {
+        asset: String,
+    },
+
+    #[error("invalid reply id: {id}")]
+    InvalidReplyId {
+        id: u64,
+    },
+
+    #[error("context of reply {id} is missing user address")]
+    ReplyContextMissingUser {
+        id: u64,
+    },
+
+    #[error("failed to parse address from storage key")]
+    InvalidAddressKey {},
+
+    #[error("context of reply {id} was not consumed")]
+    ReplyContextNotConsumed {
+        id: u64,
+    },
+
+    #[error("invalid contract version: {version}")]
+    InvalidVersion {
+        version: String,
+    },
+
+    #[error("cannot migrate from contract {actual}; expected {expected}")]
+    InvalidContractName {
+        expected: String,
+        actual: String,
+    },
+
+    #[error("cannot migrate from version {from} to older version {to}")]
+    DowngradeNotAllowed {
+        from: String,
+        to: String,
+    },
+
+    #[error("no migration path from version {version}")]
+    UnknownVersion {
+        version: String,
+    },
+
+    #[error("{param} must be provided in the config update when migrating from version {version}")]
+    MigrationParamMissing {
+        param: String,
+        version: String,
+    },
+}
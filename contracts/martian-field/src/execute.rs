@@ -1,55 +1,83 @@
+use std::cmp;
+
 use cosmwasm_std::{
-    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, MessageInfo, Response,
-    StdError, StdResult, Storage, SubMsg,
+    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, Fraction, MessageInfo,
+    Response, StdResult, Storage, SubMsg, Uint128,
 };
 
+use cw2::set_contract_version;
 use cw_asset::{Asset, AssetInfo, AssetList};
 
 use fields_of_mars::martian_field::msg::{Action, CallbackMsg};
-use fields_of_mars::martian_field::{Config, State};
+use fields_of_mars::martian_field::{
+    Config, ConfigUnchecked, ConfigUpdate, Health, OperatorInfo, PauseFlags, PauseFlagsUpdate,
+    PendingGovernance, Position, ReplyId, State,
+};
 
-use crate::health::compute_health;
-use crate::helpers::assert_sent_fund;
-use crate::state::{CONFIG, POSITION, STATE};
+use crate::accounting::{record_deposits, seed_accounting};
+use crate::error::ContractError;
+use crate::health::{compute_health, compute_position_health, compute_total_values, TotalValues};
+use crate::helpers::{
+    assert_action_not_paused, assert_not_paused, assert_sent_fund, config_diff_attrs,
+};
+use crate::math::compute_partial_liquidation_value;
+use crate::migrations::{CONTRACT_NAME, CONTRACT_VERSION};
+use crate::prices::{assert_price_valid, query_price, record_price_snapshot};
+use crate::state::{CONFIG, OPERATORS, PAUSE_FLAGS, PENDING_GOVERNANCE, POSITION, STATE};
 
-pub fn init_storage(deps: DepsMut, config: Config) -> StdResult<Response> {
+pub fn init_storage(deps: DepsMut, config: Config) -> Result<Response, ContractError> {
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     CONFIG.save(deps.storage, &config)?;
     STATE.save(deps.storage, &State::default())?;
+    PAUSE_FLAGS.save(deps.storage, &PauseFlags::default())?;
     Ok(Response::default())
 }
 
 pub fn update_position(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
     actions: Vec<Action>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let api = deps.api;
     let config = CONFIG.load(deps.storage)?;
+    let pause_flags = PAUSE_FLAGS.load(deps.storage)?;
+
+    // a position opened before v1.1.0 has its accounting seeded before any of the actions changes
+    // its value
+    seed_accounting(deps.branch(), &env, &config, &info.sender)?;
 
     let mut received_coins = AssetList::from(info.funds);
     let mut msgs: Vec<CosmosMsg> = vec![];
     let mut attrs: Vec<Attribute> = vec![];
     let mut callbacks: Vec<CallbackMsg> = vec![];
+    let mut deposits: Vec<Asset> = vec![];
 
     // compose a list of callback messages based on user-selected actions
     for action in actions {
+        assert_action_not_paused(&pause_flags, &action)?;
         match action {
-            Action::Deposit(asset) => handle_deposit(
-                deps.storage,
-                &env.contract.address,
-                &info.sender,
-                &mut received_coins,
-                &asset.check(api)?,
-                &mut msgs,
-                &mut attrs,
-            )?,
+            Action::Deposit(asset) => {
+                let asset = asset.check(api)?;
+                handle_deposit(
+                    deps.storage,
+                    &env.contract.address,
+                    &info.sender,
+                    &mut received_coins,
+                    &asset,
+                    &mut msgs,
+                    &mut attrs,
+                )?;
+                deposits.push(asset);
+            }
             Action::Borrow {
                 amount,
-            } => callbacks.push(CallbackMsg::Borrow {
-                user_addr: info.sender.clone(),
-                borrow_amount: amount,
-            }),
+            } => {
+                callbacks.push(CallbackMsg::Borrow {
+                    user_addr: info.sender.clone(),
+                    borrow_amount: amount,
+                });
+            }
             Action::Repay {
                 amount,
             } => callbacks.push(CallbackMsg::Repay {
@@ -91,12 +119,87 @@ pub fn update_position(
             Action::Swap {
                 offer_amount,
                 max_spread,
-            } => callbacks.push(CallbackMsg::Swap {
-                user_addr: Some(info.sender.clone()),
-                offer_asset_info: config.primary_asset_info.clone(),
-                offer_amount: Some(offer_amount),
+            } => {
+                callbacks.push(CallbackMsg::Swap {
+                    user_addr: Some(info.sender.clone()),
+                    offer_asset_info: config.primary_asset_info.clone(),
+                    offer_amount: Some(offer_amount),
+                    max_spread,
+                });
+            }
+            Action::Zap {
+                asset,
                 max_spread,
-            }),
+                slippage_tolerance,
+            } => {
+                // only the primary and secondary assets can be zapped
+                let asset = asset.check(api)?;
+                if asset.info != config.primary_asset_info
+                    && asset.info != config.secondary_asset_info
+                {
+                    return Err(ContractError::InvalidOfferAsset {
+                        asset: asset.info.to_string(),
+                    });
+                }
+
+                handle_deposit(
+                    deps.storage,
+                    &env.contract.address,
+                    &info.sender,
+                    &mut received_coins,
+                    &asset,
+                    &mut msgs,
+                    &mut attrs,
+                )?;
+                deposits.push(asset);
+
+                // Need to call Apollo Factory UpdateUserRewards before share change!
+                msgs.push(config.apollo_factory.update_rewards_msg(&info.sender)?);
+
+                callbacks.extend([
+                    CallbackMsg::Balance {
+                        user_addr: Some(info.sender.clone()),
+                        max_spread,
+                    },
+                    CallbackMsg::ProvideLiquidity {
+                        user_addr: Some(info.sender.clone()),
+                        slippage_tolerance,
+                    },
+                    CallbackMsg::Bond {
+                        user_addr: Some(info.sender.clone()),
+                    },
+                ]);
+            }
+            Action::SetLeverage {
+                target_ltv,
+                max_spread,
+                slippage_tolerance,
+            } => {
+                // Need to call Apollo Factory UpdateUserRewards before share change!
+                msgs.push(config.apollo_factory.update_rewards_msg(&info.sender)?);
+
+                // the amount to borrow or repay depends on the position after previous actions have
+                // been executed, so it is computed in a callback
+                callbacks.push(CallbackMsg::SetLeverage {
+                    user_addr: info.sender.clone(),
+                    target_ltv,
+                    max_spread,
+                    slippage_tolerance,
+                });
+            }
+            Action::ClosePosition {
+                max_spread,
+            } => {
+                // Need to call Apollo Factory UpdateUserRewards before share change!
+                msgs.push(config.apollo_factory.update_rewards_msg(&info.sender)?);
+
+                // the bond units to unbond depend on the position after previous actions have been
+                // executed, so they are read in a callback
+                callbacks.push(CallbackMsg::ClosePosition {
+                    user_addr: info.sender.clone(),
+                    max_spread,
+                });
+            }
         }
     }
 
@@ -104,12 +207,15 @@ pub fn update_position(
     // this way, we ensure that the user does not send any extra fund which will get lost in the
     // contract
     if received_coins.len() > 0 {
-        return Err(StdError::generic_err(format!(
-            "extra funds received: {}",
-            received_coins.to_string()
-        )));
+        return Err(ContractError::ExtraFundsReceived {
+            funds: received_coins.to_string(),
+        });
     }
 
+    // record the value of deposited assets in the user's cumulative accounting
+    deposits.retain(|asset| !asset.amount.is_zero());
+    record_deposits(deps, &config, &info.sender, &deposits)?;
+
     // after user selected actions, we executes two more callbacks:
     // - refund assets that are not deployed in the yield farm to user
     // - assert LTV is healthy; if not, throw error and revert all actions
@@ -147,7 +253,7 @@ fn handle_deposit(
     asset: &Asset,
     msgs: &mut Vec<CosmosMsg>,
     attrs: &mut Vec<Attribute>,
-) -> StdResult<()> {
+) -> Result<(), ContractError> {
     // if deposit amount is zero, we do nothing
     if asset.amount.is_zero() {
         return Ok(());
@@ -185,15 +291,33 @@ pub fn harvest(
     info: MessageInfo,
     max_spread: Option<Decimal>,
     slippage_tolerance: Option<Decimal>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
 
-    // only whitelisted operators can harvest
-    if !config.operators.contains(&info.sender) {
-        return Err(StdError::generic_err("caller is not a whitelisted operator"));
+    assert_not_paused(PAUSE_FLAGS.load(deps.storage)?.harvest, "harvest")?;
+
+    // unless harvesting is permissionless, only operators whose role has not expired can harvest
+    if !config.permissionless_harvest {
+        let operator_info = OPERATORS.may_load(deps.storage, &info.sender)?;
+        match operator_info {
+            Some(operator_info) if !operator_info.is_expired(env.block.time.seconds()) => (),
+            _ => return Err(ContractError::Unauthorized {}),
+        }
     }
 
+    // harvests must be at least `min_harvest_interval` seconds apart
+    let next_harvest = state.last_harvest + config.min_harvest_interval;
+    if env.block.time.seconds() < next_harvest {
+        return Err(ContractError::HarvestTooSoon {
+            next_harvest,
+        });
+    }
+    state.last_harvest = env.block.time.seconds();
+
+    // harvests are frequent enough to keep the TWAP fresh
+    record_price_snapshot(deps.storage, &deps.querier, &env, &config)?;
+
     // find how much reward is available to be claimed
     let rewards = config.astro_generator.query_rewards(
         &deps.querier,
@@ -209,14 +333,21 @@ pub fn harvest(
         state.pending_rewards.add_many(&rewards)?;
     }
 
-    // a portion of the pending rewards will be charged as fees
+    // a portion of the pending rewards will be charged as fees, and another portion paid to the
+    // caller as bounty
     let mut fees = state.pending_rewards.clone();
     fees.apply(|asset| asset.amount = asset.amount * config.performance_fee);
     fees.purge();
     msgs.extend(fees.transfer_msgs(&config.treasury)?);
 
-    // deduct fees from available rewards. the remaining amounts are to be reinvested
+    let mut bounty = state.pending_rewards.clone();
+    bounty.apply(|asset| asset.amount = asset.amount * config.harvest_bounty);
+    bounty.purge();
+    msgs.extend(bounty.transfer_msgs(&info.sender)?);
+
+    // deduct fees and bounty from available rewards. the remaining amounts are to be reinvested
     state.pending_rewards.deduct_many(&fees)?;
+    state.pending_rewards.deduct_many(&bounty)?;
     STATE.save(deps.storage, &state)?;
 
     // if there are ASTRO tokens available to be reinvested, we first swap it to the secondary asset
@@ -237,6 +368,7 @@ pub fn harvest(
     // 3. bond liquidity tokens (without increasing total bond units)
     callbacks.extend([
         CallbackMsg::Balance {
+            user_addr: None,
             max_spread,
         },
         CallbackMsg::ProvideLiquidity {
@@ -256,7 +388,9 @@ pub fn harvest(
     let event = Event::new("harvested")
         .add_attribute("time", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
-        .add_attribute("fees", fees.to_string());
+        .add_attribute("fees", fees.to_string())
+        .add_attribute("caller", &info.sender)
+        .add_attribute("bounty", bounty.to_string());
 
     Ok(Response::new()
         .add_messages(msgs)
@@ -266,67 +400,376 @@ pub fn harvest(
 }
 
 pub fn liquidate(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
     user_addr: Addr,
-) -> StdResult<Response> {
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
 
-    // position must be active (LTV is not `None`) and the LTV must be greater than `max_ltv`
-    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    assert_not_paused(PAUSE_FLAGS.load(deps.storage)?.liquidate, "liquidate")?;
+    assert_liquidation_max_spread(&config, max_spread)?;
+
+    let totals = compute_total_values(deps.as_ref(), &env, &config, &state)?;
+    let health = compute_position_health(&totals, &position);
+    assert_liquidatable(&config, &health)?;
+
+    // liquidations are refused if the oracle price can't be trusted
+    assert_price_valid(deps.as_ref(), &env, &config)?;
+
+    // a position opened before v1.1.0 has its accounting seeded first, so that the liquidation is
+    // reflected in its PnL
+    seed_accounting(deps.branch(), &env, &config, &user_addr)?;
+
+    let (submsgs, event) = liquidation_submsgs(
+        &env,
+        &config,
+        &totals,
+        &info.sender,
+        &user_addr,
+        &position,
+        max_spread,
+    )?;
+
+    Ok(Response::new()
+        .add_submessages(submsgs)
+        .add_attribute("action", "martian_field/execute/liquidate")
+        .add_event(event))
+}
+
+/// Liquidate multiple underfunded positions, in the same way as `liquidate`
+///
+/// The contract's total values are only computed once, and shared by all positions. Positions that
+/// are healthy or already closed are skipped, instead of failing the whole batch
+pub fn liquidate_many(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    user_addrs: Vec<Addr>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+
+    assert_not_paused(PAUSE_FLAGS.load(deps.storage)?.liquidate, "liquidate")?;
+
+    // liquidations are refused if the oracle price can't be trusted
+    assert_price_valid(deps.as_ref(), &env, &config)?;
+
+    let totals = compute_total_values(deps.as_ref(), &env, &config, &state)?;
+
+    let mut submsgs: Vec<SubMsg> = vec![];
+    let mut events: Vec<Event> = vec![];
+    let mut liquidated_addrs: Vec<Addr> = vec![];
+    let mut skipped_count = 0u32;
+    for user_addr in user_addrs {
+        // each position's liquidation is computed from the state before any of them is executed,
+        // so a position can only be liquidated once per batch
+        if liquidated_addrs.contains(&user_addr) {
+            skipped_count += 1;
+            continue;
+        }
+
+        let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+        let health = compute_position_health(&totals, &position);
+        match assert_liquidatable(&config, &health) {
+            Ok(()) => (),
+            Err(ContractError::PositionClosed {}) | Err(ContractError::PositionHealthy {}) => {
+                skipped_count += 1;
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+
+        seed_accounting(deps.branch(), &env, &config, &user_addr)?;
+
+        let (user_submsgs, event) =
+            liquidation_submsgs(&env, &config, &totals, &info.sender, &user_addr, &position, None)?;
+        submsgs.extend(user_submsgs);
+        events.push(event);
+        liquidated_addrs.push(user_addr);
+    }
+
+    Ok(Response::new()
+        .add_submessages(submsgs)
+        .add_attribute("action", "martian_field/execute/liquidate_many")
+        .add_attribute("liquidated", liquidated_addrs.len().to_string())
+        .add_attribute("skipped", skipped_count.to_string())
+        .add_events(events))
+}
+
+/// Liquidators may lower the max spread for selling the primary asset, but not raise it
+fn assert_liquidation_max_spread(
+    config: &Config,
+    max_spread: Option<Decimal>,
+) -> Result<(), ContractError> {
+    if let Some(max_spread) = max_spread {
+        if max_spread > config.liquidation_max_spread {
+            return Err(ContractError::MaxSpreadTooHigh {
+                max_spread,
+                max: config.liquidation_max_spread,
+            });
+        }
+    }
+    Ok(())
+}
 
+/// Position must be active (LTV is not `None`) and the LTV must be greater than `max_ltv`
+fn assert_liquidatable(config: &Config, health: &Health) -> Result<(), ContractError> {
     // if `health.ltv` is `Some`, it must be greater than `max_ltv`
     // if `health.ltv` is `None`, indicating the position is already closed, then it is not liquidatable
-    let ltv = health.ltv.ok_or_else(|| StdError::generic_err("position is already closed"))?;
+    let ltv = health.ltv.ok_or(ContractError::PositionClosed {})?;
     if ltv <= config.max_ltv {
-        return Err(StdError::generic_err("position is healthy"));
+        return Err(ContractError::PositionHealthy {});
     }
+    Ok(())
+}
 
-    // 1. unbond the user's liquidity tokens from Astro generator
-    // 2. burn liquidity tokens, withdraw primary + secondary assets from the pool
-    // 3. swap all primary assets to secondary assets
-    // 4. repay all debts
-    // 5. among all remaining assets, send the amount corresponding to `bonus_rate` to the liquidator
-    // 6. refund all assets that're left to the user
-    //
-    // NOTE: in the previous versions, we sell **all** primary assets, which is not optimal because
-    // this will incur bigger slippage, causing worse liquidation cascade, and be potentially lucrative
-    // for sandwich attackers
-    //
-    // now, we calculate how much additional secondary asset is needed to fully pay off debt, and
-    // reverse-simulate how much primary asset needs to be sold
-    let callbacks = [
-        CallbackMsg::Unbond {
-            user_addr: user_addr.clone(),
-            bond_units_to_reduce: position.bond_units,
-        },
-        CallbackMsg::WithdrawLiquidity {
+/// Build the submessages liquidating a position found to be liquidatable, along with the
+/// `liquidated` event
+fn liquidation_submsgs(
+    env: &Env,
+    config: &Config,
+    totals: &TotalValues,
+    liquidator_addr: &Addr,
+    user_addr: &Addr,
+    position: &Position,
+    max_spread: Option<Decimal>,
+) -> StdResult<(Vec<SubMsg>, Event)> {
+    let health = compute_position_health(totals, position);
+    let ltv = health.ltv.unwrap_or_default();
+
+    // below the full liquidation LTV, only enough debt is repaid to bring the position's LTV back
+    // to `max_initial_ltv`, if this is possible
+    let repay_value = if ltv < config.full_liquidation_ltv {
+        compute_partial_liquidation_value(
+            health.debt_value,
+            health.bond_value,
+            config.max_initial_ltv,
+            config.bonus_rate,
+            config.close_factor,
+        )
+    } else {
+        None
+    };
+
+    let (callbacks, bond_units_to_reduce) = if let Some(repay_value) = repay_value {
+        // 1. unbond liquidity tokens of value `repay_value * (1 + bonus_rate)` from Astro generator
+        // 2. burn liquidity tokens, withdraw primary + secondary assets from the pool
+        // 3. swap primary assets to secondary assets, only as much as needed to repay the debt
+        // 4. repay debt of value `repay_value`
+        // 5. send all remaining assets, i.e. the bonus, to the liquidator
+        let secondary_price = totals.secondary_price;
+        let repay_amount =
+            repay_value.multiply_ratio(secondary_price.denominator(), secondary_price.numerator());
+        let seize_value = repay_value + repay_value * config.bonus_rate;
+        let bond_units_to_reduce =
+            position.bond_units.multiply_ratio(seize_value, health.bond_value);
+        let callbacks = vec![
+            CallbackMsg::Unbond {
+                user_addr: user_addr.clone(),
+                bond_units_to_reduce,
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: user_addr.clone(),
+            },
+            CallbackMsg::Cover {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(repay_amount),
+                max_spread,
+            },
+            CallbackMsg::Repay {
+                user_addr: user_addr.clone(),
+                repay_amount: Some(repay_amount),
+            },
+            CallbackMsg::Refund {
+                user_addr: user_addr.clone(),
+                recipient_addr: liquidator_addr.clone(),
+                percentage: Decimal::one(),
+            },
+        ];
+        (callbacks, bond_units_to_reduce)
+    } else {
+        // 1. unbond the user's liquidity tokens from Astro generator
+        // 2. burn liquidity tokens, withdraw primary + secondary assets from the pool
+        // 3. swap all primary assets to secondary assets
+        // 4. repay all debts
+        // 5. among all remaining assets, send the amount corresponding to `bonus_rate` to the
+        //    liquidator
+        // 6. refund all assets that're left to the user
+        //
+        // NOTE: in the previous versions, we sell **all** primary assets, which is not optimal
+        // because this will incur bigger slippage, causing worse liquidation cascade, and be
+        // potentially lucrative for sandwich attackers
+        //
+        // now, we calculate how much additional secondary asset is needed to fully pay off debt,
+        // and reverse-simulate how much primary asset needs to be sold
+        let callbacks = vec![
+            CallbackMsg::Unbond {
+                user_addr: user_addr.clone(),
+                bond_units_to_reduce: position.bond_units,
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: user_addr.clone(),
+            },
+            CallbackMsg::Cover {
+                user_addr: user_addr.clone(),
+                repay_amount: None,
+                max_spread,
+            },
+            CallbackMsg::Repay {
+                user_addr: user_addr.clone(),
+                repay_amount: None,
+            },
+            CallbackMsg::Refund {
+                user_addr: user_addr.clone(),
+                recipient_addr: liquidator_addr.clone(),
+                percentage: config.bonus_rate,
+            },
+            CallbackMsg::Refund {
+                user_addr: user_addr.clone(),
+                recipient_addr: user_addr.clone(),
+                percentage: Decimal::one(),
+            },
+            CallbackMsg::ClearBadDebt {
+                user_addr: user_addr.clone(),
+            },
+        ];
+        (callbacks, position.bond_units)
+    };
+
+    let mut submsgs = vec![
+        // Need to call Apollo Factory UpdateUserRewards before share change!
+        // We add it as a submessage instead of a regular message, so that in case
+        // it fails, we can still liquidate the position.
+        SubMsg::reply_on_error(
+            config.apollo_factory.update_rewards_msg(user_addr)?,
+            ReplyId::ApolloRewardUpdate.into(),
+        ),
+    ];
+    for callback in &callbacks {
+        submsgs.push(SubMsg::new(callback.into_cosmos_msg(&env.contract.address)?));
+    }
+
+    let event = Event::new("liquidated")
+        .add_attribute("liquidator", liquidator_addr)
+        .add_attribute("user", user_addr)
+        .add_attribute("bond_units", position.bond_units)
+        .add_attribute("debt_units", position.debt_units)
+        .add_attribute("bond_value", health.bond_value)
+        .add_attribute("debt_value", health.debt_value)
+        .add_attribute("ltv", ltv.to_string())
+        .add_attribute("partial", repay_value.is_some().to_string())
+        .add_attribute("bond_units_reduced", bond_units_to_reduce);
+
+    Ok((submsgs, event))
+}
+
+pub fn liquidate_with_funds(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    user_addr: Addr,
+    repay_amount: Uint128,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+
+    assert_not_paused(PAUSE_FLAGS.load(deps.storage)?.liquidate, "liquidate")?;
+
+    // no swap is made when the liquidator provides the funds, but a max spread above the configured
+    // one is rejected all the same
+    assert_liquidation_max_spread(&config, max_spread)?;
+
+    // the liquidator must provide exactly `repay_amount` of the secondary asset, and nothing else.
+    // if it is a CW20 token, we transfer it from the liquidator's wallet
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    let repay_asset = Asset::new(config.secondary_asset_info.clone(), repay_amount);
+    let mut received_coins = AssetList::from(info.funds.clone());
+    match &repay_asset.info {
+        AssetInfo::Cw20(_) => {
+            msgs.push(repay_asset.transfer_from_msg(&info.sender, &env.contract.address)?);
+        }
+        AssetInfo::Native(_) => {
+            assert_sent_fund(&repay_asset, &received_coins)?;
+            received_coins.deduct(&repay_asset)?;
+        }
+    }
+    if !received_coins.is_empty() {
+        return Err(ContractError::ExtraFundsReceived {
+            funds: received_coins.to_string(),
+        });
+    }
+
+    let health = compute_health(deps.as_ref(), &env, &config, &state, &position)?;
+    assert_liquidatable(&config, &health)?;
+    let ltv = health.ltv.unwrap_or_default();
+
+    // liquidations are refused if the oracle price can't be trusted
+    assert_price_valid(deps.as_ref(), &env, &config)?;
+
+    seed_accounting(deps.branch(), &env, &config, &user_addr)?;
+
+    // the liquidator can repay up to `close_factor` of the debt, or all of it if the LTV is at or
+    // above `full_liquidation_ltv`
+    let total_debt_amount = config.red_bank.query_user_debt(
+        &deps.querier,
+        &env.contract.address,
+        &config.secondary_asset_info,
+    )?;
+    let debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
+    let max_repay_amount = if ltv >= config.full_liquidation_ltv {
+        debt_amount
+    } else {
+        debt_amount * config.close_factor
+    };
+    let repay_amount = cmp::min(repay_amount, max_repay_amount);
+
+    // the liquidator receives liquidity tokens worth the repaid debt plus `bonus_rate` of it, up to
+    // all of the user's liquidity tokens
+    let secondary_price = query_price(deps.as_ref(), &config, &config.secondary_asset_info)?;
+    let repay_value = repay_amount * secondary_price;
+    let seize_value = cmp::min(repay_value + repay_value * config.bonus_rate, health.bond_value);
+    let bond_units_to_reduce = position.bond_units.multiply_ratio(seize_value, health.bond_value);
+
+    // the provided funds are credited to the user, so that the `Repay` callback can use them. any
+    // amount in excess of what is repaid is returned to the liquidator along with the withdrawn
+    // assets
+    position.unlocked_assets.add(&repay_asset)?;
+    POSITION.save(deps.storage, &user_addr, &position)?;
+
+    // 1. repay debt using the liquidator's funds
+    // 2. unbond the seized liquidity tokens from Astro generator
+    // 3. burn liquidity tokens, withdraw primary + secondary assets from the pool
+    // 4. send all of the withdrawn assets to the liquidator
+    // 5. if all liquidity tokens are seized, waive the debt that is left, if any
+    let mut callbacks = vec![
+        CallbackMsg::Repay {
             user_addr: user_addr.clone(),
+            repay_amount: Some(repay_amount),
         },
-        CallbackMsg::Cover {
+        CallbackMsg::Unbond {
             user_addr: user_addr.clone(),
+            bond_units_to_reduce,
         },
-        CallbackMsg::Repay {
+        CallbackMsg::WithdrawLiquidity {
             user_addr: user_addr.clone(),
-            repay_amount: None,
         },
         CallbackMsg::Refund {
             user_addr: user_addr.clone(),
             recipient_addr: info.sender.clone(),
-            percentage: config.bonus_rate,
-        },
-        CallbackMsg::Refund {
-            user_addr: user_addr.clone(),
-            recipient_addr: user_addr.clone(),
             percentage: Decimal::one(),
         },
-        CallbackMsg::ClearBadDebt {
-            user_addr: user_addr.clone(),
-        },
     ];
+    if bond_units_to_reduce == position.bond_units {
+        callbacks.push(CallbackMsg::ClearBadDebt {
+            user_addr: user_addr.clone(),
+        });
+    }
 
     let callback_msgs = callbacks
         .iter()
@@ -340,31 +783,277 @@ pub fn liquidate(
         .add_attribute("debt_units", position.debt_units)
         .add_attribute("bond_value", health.bond_value)
         .add_attribute("debt_value", health.debt_value)
-        .add_attribute("ltv", ltv.to_string());
+        .add_attribute("ltv", ltv.to_string())
+        .add_attribute("repay_amount", repay_amount)
+        .add_attribute("bond_units_reduced", bond_units_to_reduce);
 
     Ok(Response::new()
+        .add_messages(msgs)
         // Need to call Apollo Factory UpdateUserRewards before share change!
         // We add it as a submessage instead of a regular message, so that in case
         // it fails, we can still liquidate the position.
         .add_submessage(SubMsg::reply_on_error(
             config.apollo_factory.update_rewards_msg(&user_addr)?,
-            3,
+            ReplyId::ApolloRewardUpdate.into(),
         ))
         .add_messages(callback_msgs)
-        .add_attribute("action", "martian_field/execute/liquidate")
+        .add_attribute("action", "martian_field/execute/liquidate_with_funds")
         .add_event(event))
 }
 
-pub fn update_config(deps: DepsMut, info: MessageInfo, new_config: Config) -> StdResult<Response> {
+pub fn update_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    config_update: ConfigUpdate,
+) -> Result<Response, ContractError> {
     // Only governance can update config
     let config = CONFIG.load(deps.storage)?;
     if info.sender != config.governance {
-        return Err(StdError::generic_err("only governance can update config"));
+        return Err(ContractError::Unauthorized {});
     }
 
+    let diff_attrs = apply_config_update(deps, config_update)?;
+    let event = Event::new("config_updated").add_attributes(diff_attrs);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/update_config")
+        .add_event(event))
+}
+
+/// Apply an update to the stored config and validate the resulting config. Returns attributes
+/// recording the values before and after the update of each parameter that has changed
+pub fn apply_config_update(
+    deps: DepsMut,
+    config_update: ConfigUpdate,
+) -> Result<Vec<Attribute>, ContractError> {
+    let old_config: ConfigUnchecked = CONFIG.load(deps.storage)?.into();
+    let updates_pairs = config_update.updates_pairs();
+
+    let mut new_config = old_config.clone();
+    new_config.apply_update(config_update);
+
     // New config must be valid
+    let config = new_config.check(deps.api)?;
     config.validate()?;
+    if updates_pairs {
+        config.validate_pairs(&deps.querier)?;
+    }
 
-    CONFIG.save(deps.storage, &new_config)?;
-    Ok(Response::default())
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(config_diff_attrs(&old_config, &new_config)?)
+}
+
+pub fn update_fee_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    performance_fee: Option<Decimal>,
+    treasury: Option<Addr>,
+) -> Result<Response, ContractError> {
+    // Only fee manager can update fee config
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.fee_manager {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if let Some(performance_fee) = performance_fee {
+        config.performance_fee = performance_fee;
+    }
+    if let Some(treasury) = treasury {
+        config.treasury = treasury;
+    }
+
+    // New config must be valid
+    config.validate()?;
+
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/update_fee_config")
+        .add_attribute("performance_fee", config.performance_fee.to_string())
+        .add_attribute("treasury", config.treasury))
+}
+
+pub fn add_operator(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    operator: Addr,
+    expires_in: Option<u64>,
+) -> Result<Response, ContractError> {
+    // Only operator manager can add operators
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.operator_manager {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let operator_info = OperatorInfo {
+        added_at_height: env.block.height,
+        expires_at: expires_in.map(|expires_in| env.block.time.seconds() + expires_in),
+    };
+    OPERATORS.save(deps.storage, &operator, &operator_info)?;
+
+    let expires_at_str = if let Some(expires_at) = operator_info.expires_at {
+        expires_at.to_string()
+    } else {
+        "null".to_string()
+    };
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/add_operator")
+        .add_attribute("operator", operator)
+        .add_attribute("expires_at", expires_at_str))
+}
+
+pub fn remove_operator(
+    deps: DepsMut,
+    info: MessageInfo,
+    operator: Addr,
+) -> Result<Response, ContractError> {
+    // Only operator manager can remove operators
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.operator_manager {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    if !OPERATORS.has(deps.storage, &operator) {
+        return Err(ContractError::OperatorNotFound {
+            operator: operator.into(),
+        });
+    }
+    OPERATORS.remove(deps.storage, &operator);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/remove_operator")
+        .add_attribute("operator", operator))
+}
+
+pub fn update_price_snapshot(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let recorded = record_price_snapshot(deps.storage, &deps.querier, &env, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/update_price_snapshot")
+        .add_attribute("recorded", recorded.to_string()))
+}
+
+pub fn propose_new_governance(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    new_governance: Addr,
+    expires_in: Option<u64>,
+) -> Result<Response, ContractError> {
+    // Only governance can propose a new governance
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let pending = PendingGovernance {
+        new_governance,
+        expires_at: expires_in.map(|expires_in| env.block.time.seconds() + expires_in),
+    };
+    PENDING_GOVERNANCE.save(deps.storage, &pending)?;
+
+    let expires_at_str = if let Some(expires_at) = pending.expires_at {
+        expires_at.to_string()
+    } else {
+        "null".to_string()
+    };
+
+    let event = Event::new("governance_proposed")
+        .add_attribute("governance", config.governance)
+        .add_attribute("new_governance", pending.new_governance)
+        .add_attribute("expires_at", expires_at_str);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/propose_new_governance")
+        .add_event(event))
+}
+
+pub fn accept_governance(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    let pending =
+        PENDING_GOVERNANCE.may_load(deps.storage)?.ok_or(ContractError::NoPendingGovernance {})?;
+
+    // Only the proposed account can accept governance, and only before the proposal expires
+    if info.sender != pending.new_governance {
+        return Err(ContractError::Unauthorized {});
+    }
+    if let Some(expires_at) = pending.expires_at {
+        if env.block.time.seconds() > expires_at {
+            return Err(ContractError::GovernanceProposalExpired {});
+        }
+    }
+
+    let previous_governance = config.governance;
+    config.governance = pending.new_governance;
+    CONFIG.save(deps.storage, &config)?;
+    PENDING_GOVERNANCE.remove(deps.storage);
+
+    let event = Event::new("governance_transferred")
+        .add_attribute("previous_governance", previous_governance)
+        .add_attribute("new_governance", &config.governance);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/accept_governance")
+        .add_event(event))
+}
+
+pub fn cancel_governance_proposal(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    // Only governance can cancel a proposal
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.governance {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let pending =
+        PENDING_GOVERNANCE.may_load(deps.storage)?.ok_or(ContractError::NoPendingGovernance {})?;
+    PENDING_GOVERNANCE.remove(deps.storage);
+
+    let event = Event::new("governance_proposal_cancelled")
+        .add_attribute("governance", config.governance)
+        .add_attribute("new_governance", pending.new_governance);
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/cancel_governance_proposal")
+        .add_event(event))
+}
+
+pub fn update_pause_flags(
+    deps: DepsMut,
+    info: MessageInfo,
+    pause_flags_update: PauseFlagsUpdate,
+) -> Result<Response, ContractError> {
+    // Only guardian or governance can pause or unpause actions
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.guardian && info.sender != config.governance {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let mut pause_flags = PAUSE_FLAGS.load(deps.storage)?;
+    let toggled = pause_flags.apply_update(pause_flags_update);
+    PAUSE_FLAGS.save(deps.storage, &pause_flags)?;
+
+    let events: Vec<Event> = toggled
+        .into_iter()
+        .map(|(flag, paused)| {
+            Event::new("pause_flag_updated")
+                .add_attribute("flag", flag)
+                .add_attribute("paused", paused.to_string())
+                .add_attribute("sender", &info.sender)
+        })
+        .collect();
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/execute/update_pause_flags")
+        .add_events(events))
 }
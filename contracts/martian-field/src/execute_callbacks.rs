@@ -1,25 +1,36 @@
 use std::cmp;
-use std::cmp::Ordering;
+use std::str::FromStr;
 
 use cosmwasm_std::{
-    attr, Addr, Attribute, Decimal, DepsMut, Env, Event, Response, StdError, StdResult, Uint128,
+    attr, Addr, Attribute, CosmosMsg, Decimal, DepsMut, Env, Event, Response, StdResult, Uint128,
 };
 
 use cw_asset::{Asset, AssetInfo, AssetList};
 
-use fields_of_mars::martian_field::{Position, Snapshot, State};
+use fields_of_mars::adapters::ASTROPORT_COMMISSION_RATE;
+use fields_of_mars::martian_field::msg::CallbackMsg;
+use fields_of_mars::martian_field::{Position, ReplyId, Snapshot, State};
 
-use crate::health::compute_health;
-use crate::state::{CACHED_USER_ADDR, CONFIG, POSITION, SNAPSHOT, STATE};
+use crate::accounting::{record_borrow, record_repay, record_withdrawal};
+use crate::error::ContractError;
+use crate::health::{
+    assert_position_healthy, assert_target_ltv, compute_health, compute_leverage_change,
+    LeverageChange,
+};
+use crate::helpers::{query_belief_price, save_reply_context};
+use crate::math::compute_optimal_swap_amount;
+use crate::prices::query_price;
+use crate::state::{CONFIG, PAUSE_FLAGS, POSITION, SNAPSHOT, STATE};
 
-static DEFAULT_BOND_UNITS_PER_SHARE_BONDED: Uint128 = Uint128::new(1_000_000);
-static DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED: Uint128 = Uint128::new(1_000_000);
+pub static DEFAULT_BOND_UNITS_PER_SHARE_BONDED: Uint128 = Uint128::new(1_000_000);
+pub static DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED: Uint128 = Uint128::new(1_000_000);
 
 pub fn provide_liquidity(
     deps: DepsMut,
+    env: Env,
     user_addr_option: Option<Addr>,
     slippage_tolerance: Option<Decimal>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
 
     // if `user_addr` is provided, we load the user's position and provide the user's unlocked assets
@@ -37,31 +48,45 @@ pub fn provide_liquidity(
 
     // we provide *all* available primary and secondary assets, assuming they are close in value.
     // it is strongly recommended to use `slippage_tolerance` parameter here
-    let primary_asset_to_provide = assets
-        .find(&config.primary_asset_info)
-        .cloned()
-        .ok_or_else(|| StdError::generic_err("no primary asset available"))?;
+    let primary_asset_to_provide =
+        assets.find(&config.primary_asset_info).cloned().ok_or_else(|| {
+            ContractError::AssetNotAvailable {
+                asset: "primary asset".to_string(),
+            }
+        })?;
     let secondary_asset_to_provide = assets
         .find(&config.secondary_asset_info)
         .cloned()
-        .ok_or_else(|| StdError::generic_err("no secondary asset available"))?;
+        .ok_or_else(|| ContractError::AssetNotAvailable {
+            asset: "secondary asset".to_string(),
+        })?;
 
     // deduct assets that will be provided from available asset list
     assets.deduct(&primary_asset_to_provide)?;
     assets.deduct(&secondary_asset_to_provide)?;
 
     // update storage
-    // if `user_addr` is provided, we cache it so that it can be accessed when handling the reply
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
-        CACHED_USER_ADDR.save(deps.storage, user_addr)?;
     } else {
         STATE.save(deps.storage, &state)?;
     }
 
+    // the user's address, if provided, is saved in the reply context, so that the liquidity tokens
+    // minted can be credited to them when handling the reply
+    let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
+    save_reply_context(
+        deps.storage,
+        &deps.querier,
+        &env.contract.address,
+        ReplyId::ProvideLiquidity,
+        user_addr_option.as_ref(),
+        &[&liquidity_token_info],
+    )?;
+
     Ok(Response::new()
         .add_submessages(config.primary_pair.provide_submsgs(
-            0,
+            ReplyId::ProvideLiquidity.into(),
             &[primary_asset_to_provide.clone(), secondary_asset_to_provide.clone()],
             slippage_tolerance,
         )?)
@@ -70,29 +95,50 @@ pub fn provide_liquidity(
         .add_attribute("secondary_provided", secondary_asset_to_provide.amount))
 }
 
-pub fn withdraw_liquidity(deps: DepsMut, user_addr: Addr) -> StdResult<Response> {
+pub fn withdraw_liquidity(
+    deps: DepsMut,
+    env: Env,
+    user_addr: Addr,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
 
     // We burn *all* of the user's unlocked liquidity tokens
     let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
-    let liquidity_token_to_burn = position
-        .unlocked_assets
-        .find(&liquidity_token_info)
-        .cloned()
-        .ok_or_else(|| StdError::generic_err("no unlocked share token available"))?;
+    let liquidity_token_to_burn =
+        position.unlocked_assets.find(&liquidity_token_info).cloned().ok_or_else(|| {
+            ContractError::AssetNotAvailable {
+                asset: "unlocked share token".to_string(),
+            }
+        })?;
 
     position.unlocked_assets.deduct(&liquidity_token_to_burn)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.save(deps.storage, &user_addr)?;
+    save_reply_context(
+        deps.storage,
+        &deps.querier,
+        &env.contract.address,
+        ReplyId::WithdrawLiquidity,
+        Some(&user_addr),
+        &[&config.primary_asset_info, &config.secondary_asset_info],
+    )?;
 
     Ok(Response::new()
-        .add_submessage(config.primary_pair.withdraw_submsg(1, liquidity_token_to_burn.amount)?)
+        .add_submessage(
+            config.primary_pair.withdraw_submsg(
+                ReplyId::WithdrawLiquidity.into(),
+                liquidity_token_to_burn.amount,
+            )?,
+        )
         .add_attribute("action", "martian_field/callback/withdraw_liquidity")
         .add_attribute("shares_burned", liquidity_token_to_burn.amount))
 }
 
-pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResult<Response> {
+pub fn bond(
+    deps: DepsMut,
+    env: Env,
+    user_addr_option: Option<Addr>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
 
@@ -109,10 +155,12 @@ pub fn bond(deps: DepsMut, env: Env, user_addr_option: Option<Addr>) -> StdResul
 
     // we bond *all* of the available liquidity tokens
     let liquidity_token_info = AssetInfo::cw20(config.primary_pair.liquidity_token.clone());
-    let liquidity_tokens_to_bond = assets
-        .find(&liquidity_token_info)
-        .cloned()
-        .ok_or_else(|| StdError::generic_err("no liquidity token available"))?;
+    let liquidity_tokens_to_bond =
+        assets.find(&liquidity_token_info).cloned().ok_or_else(|| {
+            ContractError::AssetNotAvailable {
+                asset: "liquidity token".to_string(),
+            }
+        })?;
 
     // query how many liquidity tokens is currently being bonded by us
     let total_bonded_amount = config.astro_generator.query_bonded_amount(
@@ -169,7 +217,7 @@ pub fn unbond(
     env: Env,
     user_addr: Addr,
     bond_units_to_deduct: Uint128,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
@@ -220,7 +268,7 @@ pub fn borrow(
     env: Env,
     user_addr: Addr,
     borrow_amount: Uint128,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
@@ -248,6 +296,9 @@ pub fn borrow(
     STATE.save(deps.storage, &state)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
 
+    let secondary_price = query_price(deps.as_ref(), &config, &config.secondary_asset_info)?;
+    record_borrow(deps.storage, &user_addr, borrow_amount * secondary_price)?;
+
     Ok(Response::new()
         .add_message(config.red_bank.borrow_msg(&secondary_asset_to_borrow)?)
         .add_attribute("action", "martian_field/callback/borrow")
@@ -260,7 +311,7 @@ pub fn repay(
     env: Env,
     user_addr: Addr,
     repay_amount: Option<Uint128>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
@@ -282,8 +333,12 @@ pub fn repay(
             .unwrap_or_else(Uint128::zero)
     });
 
-    // We only repay up to the debt amount
+    // We only repay up to the debt amount. Red Bank rejects zero-amount repayments, so if there is
+    // nothing to repay, we return without sending one
     let repay_amount = cmp::min(repay_amount, debt_amount);
+    if repay_amount.is_zero() {
+        return Ok(Response::default());
+    }
 
     // Calculate how by many the user's debt units should be deducted
     let debt_units_to_deduct = if debt_amount.is_zero() {
@@ -301,6 +356,9 @@ pub fn repay(
     STATE.save(deps.storage, &state)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
 
+    let secondary_price = query_price(deps.as_ref(), &config, &config.secondary_asset_info)?;
+    record_repay(deps.storage, &user_addr, repay_amount * secondary_price)?;
+
     Ok(Response::new()
         .add_message(config.red_bank.repay_msg(&secondary_asset_to_repay)?)
         .add_attribute("action", "martian_field/callback/repay")
@@ -310,11 +368,12 @@ pub fn repay(
 
 pub fn swap(
     deps: DepsMut,
+    env: Env,
     user_addr_option: Option<Addr>,
     offer_asset_info: AssetInfo,
     offer_amount_option: Option<Uint128>,
     max_spread: Option<Decimal>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
 
     // if `user_addr` is provided, we load the user's position and swap the user's unlocked assets
@@ -338,9 +397,9 @@ pub fn swap(
     } else if offer_asset_info == config.astro_token_info {
         &config.astro_pair
     } else {
-        return Err(StdError::generic_err(
-            format!("invalid offer asset: {}", offer_asset_info.to_string())
-        ));
+        return Err(ContractError::InvalidOfferAsset {
+            asset: offer_asset_info.to_string(),
+        });
     };
 
     // if swap amount is unspecified, we swap all that's available
@@ -356,95 +415,143 @@ pub fn swap(
     // deduct offer asset from the available amount
     assets.deduct(&offer_asset)?;
 
+    let belief_price = query_belief_price(
+        deps.as_ref(),
+        &config,
+        pair,
+        &offer_asset.info,
+        &config.secondary_asset_info,
+    )?;
+
     // update storage
-    // if `user_addr` is provided, we cache it so that it can be accessed when handling the reply
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
-        CACHED_USER_ADDR.save(deps.storage, user_addr)?;
     } else {
         STATE.save(deps.storage, &state)?;
     }
 
+    save_reply_context(
+        deps.storage,
+        &deps.querier,
+        &env.contract.address,
+        ReplyId::Swap,
+        user_addr_option.as_ref(),
+        &[&config.secondary_asset_info],
+    )?;
+
     Ok(Response::new()
-        .add_submessage(pair.swap_submsg(2, &offer_asset, None, max_spread)?)
+        .add_submessage(pair.swap_submsg(
+            ReplyId::Swap.into(),
+            &offer_asset,
+            belief_price,
+            max_spread,
+        )?)
         .add_attribute("action", "martian_field/callback/swap")
         .add_attribute("asset_offered", offer_asset.to_string()))
 }
 
 pub fn balance(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
+    user_addr_option: Option<Addr>,
     max_spread: Option<Decimal>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    let mut state = STATE.load(deps.storage)?;
+
+    // if `user_addr` is provided, we load the user's position and balance the user's unlocked assets
+    // if not provided, we load the state and balance the state's pending rewards
+    let mut state = State::default();
+    let mut position = Position::default();
+    let assets: &mut AssetList;
+    if let Some(user_addr) = &user_addr_option {
+        position = POSITION.load(deps.storage, user_addr).unwrap_or_default();
+        assets = &mut position.unlocked_assets;
+    } else {
+        state = STATE.load(deps.storage)?;
+        assets = &mut state.pending_rewards;
+    }
 
     // find the available amounts of primary and secondary assets
-    let primary_asset_amount = match state.pending_rewards.find(&config.primary_asset_info) {
+    let primary_asset_amount = match assets.find(&config.primary_asset_info) {
         Some(asset) => asset.amount,
         None => Uint128::zero(),
     };
-    let secondary_asset_amount = match state.pending_rewards.find(&config.secondary_asset_info) {
+    let secondary_asset_amount = match assets.find(&config.secondary_asset_info) {
         Some(asset) => asset.amount,
         None => Uint128::zero(),
     };
 
-    // query the prices of the two assets
-    let primary_asset_price =
-        config.oracle.query_price(&deps.querier, &config.primary_asset_info)?;
-    let secondary_asset_price =
-        config.oracle.query_price(&deps.querier, &config.secondary_asset_info)?;
-
-    // calculate the values of available assets
-    let primary_asset_value = primary_asset_amount * primary_asset_price;
-    let secondary_asset_value = secondary_asset_amount * secondary_asset_price;
-
-    // if primary_asset_value > secondary_asset_value, we swap primary >> secondary
-    // if secondary_asset_value > primary_asset_value, we swap secondary >> primary
-    // if equal, we skip
-    let (offer_asset_info, offer_asset_available_amount) =
-        match primary_asset_value.cmp(&secondary_asset_value)
-    {
-        Ordering::Greater => (config.primary_asset_info.clone(), primary_asset_amount),
-        Ordering::Less => (config.secondary_asset_info.clone(), secondary_asset_amount),
-        Ordering::Equal => return Ok(Response::default()),
-    };
+    // query the depths of the two assets in the pool
+    let (primary_depth, secondary_depth, _) = config.primary_pair.query_pool(
+        &deps.querier,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    )?;
 
-    // the amount to be swapped is the amount corresponding to half of the value difference
-    //
-    // e.g. we have $120 worth of UST and $100 worth of ANC. the diff in value is $20, so we swap
-    // $20 / 2 = $10 worth of UST to ANC. ideally, this should leave us $110 worth of each
+    // the amount to be swapped is such that after the swap, the two assets are in the same ratio as
+    // the pool depths, taking slippage and commission into account. this way, all of them can be
+    // provided to the pool without leaving any dust behind
     //
-    // in reality, considering slippage, commission, we will end up with $110 worth of UST and
-    // **slightly less than $110 worth** of ANC, so this method is not very optimized. the best way
-    // is to solve a quadratic function which contains terms describing slippage and commission rate
-    // to find the optimal swap amount. i have worked out the math somewhere else and will later
-    // implement it as a separate smart contract
-    //
-    // for the time being, the less optimial method is ok as long as we harvest frequently; that is,
-    // the amount that needs to be swapped is not very large at each harvest, so it should not incur
-    // too much slippage
-    let higher_value = cmp::max(primary_asset_value, secondary_asset_value);
-    let lower_value = cmp::min(primary_asset_value, secondary_asset_value);
-    let value_diff = higher_value.checked_sub(lower_value)?;
-    let value_to_swap = value_diff.multiply_ratio(1u128, 2u128);
-
-    let offer_asset = Asset::new(
-        offer_asset_info,
-        offer_asset_available_amount.multiply_ratio(value_to_swap, higher_value)
-    );
-
-    state.pending_rewards.deduct(&offer_asset)?;
-    STATE.save(deps.storage, &state)?;
+    // only the asset we hold in excess relative to the pool ratio is to be swapped. for the other
+    // asset, the computed amount is zero. if neither is in excess, we skip
+    let commission_rate = Decimal::from_str(ASTROPORT_COMMISSION_RATE)?;
+    let primary_offer_amount = compute_optimal_swap_amount(
+        primary_asset_amount,
+        secondary_asset_amount,
+        primary_depth,
+        secondary_depth,
+        commission_rate,
+    )?;
+    let offer_asset = if !primary_offer_amount.is_zero() {
+        Asset::new(config.primary_asset_info.clone(), primary_offer_amount)
+    } else {
+        let secondary_offer_amount = compute_optimal_swap_amount(
+            secondary_asset_amount,
+            primary_asset_amount,
+            secondary_depth,
+            primary_depth,
+            commission_rate,
+        )?;
+        Asset::new(config.secondary_asset_info.clone(), secondary_offer_amount)
+    };
+
+    assets.deduct(&offer_asset)?;
+
+    // update storage
+    if let Some(user_addr) = &user_addr_option {
+        POSITION.save(deps.storage, user_addr, &position)?;
+    } else {
+        STATE.save(deps.storage, &state)?;
+    }
 
     // if amount to swap is zero, we do nothing
     // if amount to swap is non-zero, we invoke the `Swap` callback
     let mut res = Response::new();
     if !offer_asset.amount.is_zero() {
+        let ask_asset_info = if offer_asset.info == config.primary_asset_info {
+            &config.secondary_asset_info
+        } else {
+            &config.primary_asset_info
+        };
+        let belief_price = query_belief_price(
+            deps.as_ref(),
+            &config,
+            &config.primary_pair,
+            &offer_asset.info,
+            ask_asset_info,
+        )?;
+        save_reply_context(
+            deps.storage,
+            &deps.querier,
+            &env.contract.address,
+            ReplyId::Swap,
+            user_addr_option.as_ref(),
+            &[ask_asset_info],
+        )?;
         res = res.add_submessage(config.primary_pair.swap_submsg(
-            2,
+            ReplyId::Swap.into(),
             &offer_asset,
-            None,
+            belief_price,
             max_spread,
         )?);
     }
@@ -453,16 +560,170 @@ pub fn balance(
         .add_attribute("action", "martian_field/callback/balance")
         .add_attribute("primary_amount", primary_asset_amount)
         .add_attribute("secondary_amount", secondary_asset_amount)
-        .add_attribute("primary_price", primary_asset_price.to_string())
-        .add_attribute("secondary_price", secondary_asset_price.to_string())
+        .add_attribute("primary_depth", primary_depth)
+        .add_attribute("secondary_depth", secondary_depth)
         .add_attribute("asset_offered", offer_asset.to_string()))
 }
 
+pub fn set_leverage(
+    deps: DepsMut,
+    env: Env,
+    user_addr: Addr,
+    target_ltv: Decimal,
+    max_spread: Option<Decimal>,
+    slippage_tolerance: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let pause_flags = PAUSE_FLAGS.load(deps.storage)?;
+    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+
+    assert_target_ltv(&config, target_ltv)?;
+
+    let health = compute_health(deps.as_ref(), &env, &config, &state, &position)?;
+    let primary_price = query_price(deps.as_ref(), &config, &config.primary_asset_info)?;
+    let secondary_price = query_price(deps.as_ref(), &config, &config.secondary_asset_info)?;
+    let (change, rebond) = compute_leverage_change(
+        &config,
+        &pause_flags,
+        &position,
+        &health,
+        primary_price,
+        secondary_price,
+        target_ltv,
+    )?;
+
+    let mut callbacks: Vec<CallbackMsg> = vec![];
+    let mut attrs: Vec<Attribute> = vec![];
+    match change {
+        LeverageChange::Borrow {
+            borrow_amount,
+        } => {
+            if !borrow_amount.is_zero() {
+                callbacks.push(CallbackMsg::Borrow {
+                    user_addr: user_addr.clone(),
+                    borrow_amount,
+                });
+            }
+            attrs.push(attr("secondary_borrowed", borrow_amount));
+        }
+        LeverageChange::Repay {
+            repay_amount,
+            bond_units_to_reduce,
+        } => {
+            if let Some(bond_units_to_reduce) = bond_units_to_reduce {
+                callbacks.extend([
+                    CallbackMsg::Unbond {
+                        user_addr: user_addr.clone(),
+                        bond_units_to_reduce,
+                    },
+                    CallbackMsg::WithdrawLiquidity {
+                        user_addr: user_addr.clone(),
+                    },
+                ]);
+            }
+            callbacks.extend([
+                CallbackMsg::Cover {
+                    user_addr: user_addr.clone(),
+                    repay_amount: Some(repay_amount),
+                    max_spread,
+                },
+                CallbackMsg::Repay {
+                    user_addr: user_addr.clone(),
+                    repay_amount: Some(repay_amount),
+                },
+            ]);
+            attrs.push(attr("secondary_repaid", repay_amount));
+        }
+    }
+
+    // provide and bond whatever unlocked assets are left
+    if rebond {
+        callbacks.extend([
+            CallbackMsg::Balance {
+                user_addr: Some(user_addr.clone()),
+                max_spread,
+            },
+            CallbackMsg::ProvideLiquidity {
+                user_addr: Some(user_addr.clone()),
+                slippage_tolerance,
+            },
+            CallbackMsg::Bond {
+                user_addr: Some(user_addr.clone()),
+            },
+        ]);
+    }
+
+    let callback_msgs = callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&env.contract.address))
+        .collect::<StdResult<Vec<CosmosMsg>>>()?;
+
+    Ok(Response::new()
+        .add_messages(callback_msgs)
+        .add_attribute("action", "martian_field/callback/set_leverage")
+        .add_attribute("target_ltv", target_ltv.to_string())
+        .add_attributes(attrs))
+}
+
+pub fn close_position(
+    deps: DepsMut,
+    env: Env,
+    user_addr: Addr,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+
+    // 1. unbond all of the user's liquidity tokens, if any, and withdraw them from the pool
+    // 2. sell enough primary asset to pay off the debt in full, if there is any
+    // 3. repay the debt
+    //
+    // the remaining unlocked assets are refunded to the user by the `Refund` callback that follows
+    // every `UpdatePosition` call
+    let mut callbacks: Vec<CallbackMsg> = vec![];
+    if !position.bond_units.is_zero() {
+        callbacks.extend([
+            CallbackMsg::Unbond {
+                user_addr: user_addr.clone(),
+                bond_units_to_reduce: position.bond_units,
+            },
+            CallbackMsg::WithdrawLiquidity {
+                user_addr: user_addr.clone(),
+            },
+        ]);
+    }
+    if !position.debt_units.is_zero() {
+        callbacks.extend([
+            CallbackMsg::Cover {
+                user_addr: user_addr.clone(),
+                repay_amount: None,
+                max_spread,
+            },
+            CallbackMsg::Repay {
+                user_addr: user_addr.clone(),
+                repay_amount: None,
+            },
+        ]);
+    }
+
+    let callback_msgs = callbacks
+        .iter()
+        .map(|callback| callback.into_cosmos_msg(&env.contract.address))
+        .collect::<StdResult<Vec<CosmosMsg>>>()?;
+
+    Ok(Response::new()
+        .add_messages(callback_msgs)
+        .add_attribute("action", "martian_field/callback/close_position")
+        .add_attribute("bond_units_reduced", position.bond_units))
+}
+
 pub fn cover(
     deps: DepsMut,
     env: Env,
     user_addr: Addr,
-) -> StdResult<Response> {
+    repay_amount: Option<Uint128>,
+    max_spread: Option<Decimal>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
@@ -482,19 +743,20 @@ pub fn cover(
         .cloned()
         .unwrap_or_else(|| Asset::new(config.secondary_asset_info.clone(), 0u128));
 
-    // calculate how much additional secondary asset is needed to fully pay off the user's debt
-    let secondary_needed_amount = if debt_amount > secondary_available.amount {
-        debt_amount.checked_sub(secondary_available.amount)?
+    // if `repay_amount` is provided, we only need to cover that amount, up to the debt amount
+    let cover_amount = repay_amount.map_or(debt_amount, |amount| cmp::min(amount, debt_amount));
+
+    // calculate how much additional secondary asset is needed to pay off the amount to be covered
+    let secondary_needed_amount = if cover_amount > secondary_available.amount {
+        cover_amount.checked_sub(secondary_available.amount)?
     } else {
         return Ok(Response::default());
     };
     let secondary_needed = Asset::new(config.secondary_asset_info.clone(), secondary_needed_amount);
 
     // reverse-simulate how much primary asset needs to be sold
-    let mut primary_sell_amount = config.primary_pair.query_reverse_simulate(
-        &deps.querier,
-        &secondary_needed
-    )?;
+    let mut primary_sell_amount =
+        config.primary_pair.query_reverse_simulate(&deps.querier, &secondary_needed)?;
 
     // NOTE: due to integer rounding, if we estimate offer amount using exactly the needed return
     // amount, the actual return amount may be one unit less than what we need
@@ -525,16 +787,33 @@ pub fn cover(
 
     let primary_to_sell = Asset::new(config.primary_asset_info.clone(), primary_sell_amount);
 
+    // the swap is anchored to the price sources, so that the pool can't be manipulated to make us
+    // sell at a bad price
+    let belief_price = query_belief_price(
+        deps.as_ref(),
+        &config,
+        &config.primary_pair,
+        &config.primary_asset_info,
+        &config.secondary_asset_info,
+    )?;
+
     position.unlocked_assets.deduct(&primary_to_sell)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.save(deps.storage, &user_addr)?;
+    save_reply_context(
+        deps.storage,
+        &deps.querier,
+        &env.contract.address,
+        ReplyId::Swap,
+        Some(&user_addr),
+        &[&config.secondary_asset_info],
+    )?;
 
     Ok(Response::new()
         .add_submessage(config.primary_pair.swap_submsg(
-            2,
+            ReplyId::Swap.into(),
             &primary_to_sell,
-            None,
-            Some(Decimal::from_ratio(1u128, 20u128)), // 5%. NOTE: switch this to 50% to pass the integration test
+            belief_price,
+            Some(max_spread.unwrap_or(config.liquidation_max_spread)),
         )?)
         .add_attribute("action", "martian_field/callback/cover")
         .add_attribute("debt_amount", debt_amount)
@@ -544,17 +823,34 @@ pub fn cover(
 }
 
 pub fn refund(
-    deps: DepsMut,
+    mut deps: DepsMut,
+    env: Env,
     user_addr: Addr,
     recipient_addr: Addr,
     percentage: Decimal,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
 
     // apply percentage and purge assets with zero amount
     let mut assets_to_refund = position.unlocked_assets.clone();
     assets_to_refund.apply(|asset| asset.amount = asset.amount * percentage).purge();
 
+    // only assets returned to the user themselves count as withdrawn; assets refunded to a
+    // liquidator are lost to the user, which is reflected in the position's unrealized PnL
+    if recipient_addr == user_addr {
+        let config = CONFIG.load(deps.storage)?;
+        let state = STATE.load(deps.storage)?;
+        record_withdrawal(
+            deps.branch(),
+            &env,
+            &config,
+            &state,
+            &user_addr,
+            &position,
+            &assets_to_refund.to_vec(),
+        )?;
+    }
+
     position.unlocked_assets.deduct_many(&assets_to_refund)?;
     POSITION.save(deps.storage, &user_addr, &position)?;
 
@@ -571,19 +867,12 @@ pub fn refund(
         .add_attributes(refund_attrs))
 }
 
-pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
+pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
-    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
-
-    // If ltv is Some(ltv), we assert it is no larger than `config.max_initial_ltv`
-    // If it is None, meaning `bond_value` is zero, we assert debt is also zero
-    let healthy = if let Some(ltv) = health.ltv {
-        ltv <= config.max_initial_ltv
-    } else {
-        health.debt_value.is_zero()
-    };
+    let health = compute_health(deps.as_ref(), &env, &config, &state, &position)?;
+    assert_position_healthy(&config, &position, &health)?;
 
     // Convert `ltv` to String so that it can be recorded in logs
     let ltv_str = if let Some(ltv) = health.ltv {
@@ -592,20 +881,6 @@ pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Resp
         "null".to_string()
     };
 
-    if !healthy {
-        return Err(StdError::generic_err(format!("ltv greater than threshold: {}", ltv_str)));
-    }
-
-    // Check minimum position size
-    if health.bond_value < config.min_position_size {
-        return Err(StdError::generic_err(
-            format!("position size {} less that minimum size of {}",
-            health.bond_value,
-            config.min_position_size)
-        ));
-    }
-
-
     let event = Event::new("position_changed")
         .add_attribute("timestamp", env.block.time.seconds().to_string())
         .add_attribute("height", env.block.height.to_string())
@@ -621,7 +896,7 @@ pub fn assert_health(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Resp
         .add_event(event))
 }
 
-pub fn clear_bad_debt(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
+pub fn clear_bad_debt(deps: DepsMut, env: Env, user_addr: Addr) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let mut state = STATE.load(deps.storage)?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
@@ -637,7 +912,8 @@ pub fn clear_bad_debt(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Res
         &env.contract.address,
         &config.secondary_asset_info,
     )?;
-    let bad_debt_amount = total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
+    let bad_debt_amount =
+        total_debt_amount.multiply_ratio(position.debt_units, state.total_debt_units);
     let bad_debt = Asset::new(config.secondary_asset_info, bad_debt_amount);
 
     // waive the user's debt
@@ -655,11 +931,11 @@ pub fn clear_bad_debt(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Res
     Ok(res.add_event(event))
 }
 
-pub fn snapshot(deps: DepsMut, env: Env, user_addr: Addr) -> StdResult<Response> {
+pub fn snapshot(deps: DepsMut, env: Env, user_addr: Addr) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
-    let health = compute_health(&deps.querier, &env, &config, &state, &position)?;
+    let health = compute_health(deps.as_ref(), &env, &config, &state, &position)?;
 
     let snapshot = Snapshot {
         time: env.block.time.seconds(),
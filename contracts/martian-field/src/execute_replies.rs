@@ -1,22 +1,65 @@
-use cosmwasm_std::{ContractResult, DepsMut, Response, StdResult, SubMsgExecutionResponse};
+use cosmwasm_std::{
+    Addr, ContractResult, DepsMut, Env, QuerierWrapper, Response, StdResult, Storage,
+    SubMsgExecutionResponse,
+};
 
 use cw_asset::{Asset, AssetList};
 
 use fields_of_mars::adapters::Pair;
-use fields_of_mars::martian_field::{Position, State};
+use fields_of_mars::martian_field::{Position, ReplyContext, ReplyId, State};
+
+use crate::error::ContractError;
+use crate::helpers::query_balance;
+use crate::state::{CONFIG, POSITION, REPLY_CONTEXT, STATE};
+
+/// Load the context saved by `helpers::save_reply_context` and remove it from storage, asserting
+/// that it belongs to the reply being handled
+fn consume_reply_context(
+    storage: &mut dyn Storage,
+    id: ReplyId,
+) -> Result<ReplyContext, ContractError> {
+    let context = REPLY_CONTEXT.load(storage)?;
+    if context.id != id {
+        return Err(ContractError::InvalidReplyId {
+            id: id.into(),
+        });
+    }
+    REPLY_CONTEXT.remove(storage);
+    Ok(context)
+}
 
-use crate::state::{CACHED_USER_ADDR, CONFIG, POSITION, STATE};
+/// Compute the amounts of assets received since the context was saved, in the order the assets
+/// were recorded
+///
+/// Amounts are computed from balance changes rather than parsed from the events emitted by
+/// Astroport, which are only used as a cross-check
+fn compute_received_assets(
+    querier: &QuerierWrapper,
+    contract_addr: &Addr,
+    context: &ReplyContext,
+) -> StdResult<Vec<Asset>> {
+    context
+        .balances_before
+        .to_vec()
+        .into_iter()
+        .map(|before| {
+            let balance_after = query_balance(querier, &before.info, contract_addr)?;
+            Ok(Asset::new(before.info, balance_after.checked_sub(before.amount)?))
+        })
+        .collect()
+}
 
 pub fn after_provide_liquidity(
     deps: DepsMut,
+    env: Env,
     response: SubMsgExecutionResponse,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
+    let context = consume_reply_context(deps.storage, ReplyId::ProvideLiquidity)?;
 
-    // if this is a user providing their unlocked assets, the user's address should have been cached
-    // if this is a reward harvesting operation, no user address should have been cached. `may_load`
-    // should return `None` in this case
-    let user_addr_option = CACHED_USER_ADDR.may_load(deps.storage)?;
+    // if this is a user providing their unlocked assets, the user's address is recorded in the
+    // context. if this is a reward harvesting operation, it is `None`
+    let user_addr_option = context.user_addr.clone();
 
     // if a user address is cached, we update the user's unlocked assets
     // if not, we update the state's pending rewards
@@ -31,10 +74,13 @@ pub fn after_provide_liquidity(
         assets = &mut state.pending_rewards;
     }
 
-    // parse event log to find the amount of liquidity tokens minted
-    let minted_amount = Pair::parse_provide_events(&response.events)?;
+    // the amount of liquidity tokens minted is the increase in the contract's balance
+    let received = compute_received_assets(&deps.querier, &env.contract.address, &context)?;
+    let minted_amount = received[0].amount;
     assets.add(&Asset::cw20(config.primary_pair.liquidity_token, minted_amount))?;
 
+    let events_agree = Pair::parse_provide_events(&response.events).ok() == Some(minted_amount);
+
     // save the updated state/position
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
@@ -42,49 +88,63 @@ pub fn after_provide_liquidity(
         STATE.save(deps.storage, &state)?;
     }
 
-    // finally, clear cached data
-    CACHED_USER_ADDR.remove(deps.storage);
-
     // `shares_minted` should really be `liquidity_token_minted` according to my naming convention,
     // but it's a bit too long and doesn't look very good on Terra Finder's UI, so I opt for a shorter one
     Ok(Response::new()
         .add_attribute("action", "martian_field/reply/after_provide_liquidity")
-        .add_attribute("shares_minted", minted_amount))
+        .add_attribute("shares_minted", minted_amount)
+        .add_attribute("events_agree", events_agree.to_string()))
 }
 
 pub fn after_withdraw_liquidity(
     deps: DepsMut,
+    env: Env,
     response: SubMsgExecutionResponse,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    let user_addr = CACHED_USER_ADDR.load(deps.storage)?;
+    let context = consume_reply_context(deps.storage, ReplyId::WithdrawLiquidity)?;
+    let user_addr = context.user_addr.clone().ok_or(ContractError::ReplyContextMissingUser {
+        id: ReplyId::WithdrawLiquidity.into(),
+    })?;
     let mut position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
 
-    // parse event log to find the amounts of assets returned
-    let (primary_asset_withdrawn, secondary_asset_withdrawn) = Pair::parse_withdraw_events(
+    // the amounts of assets returned are the increases in the contract's balances
+    let received = compute_received_assets(&deps.querier, &env.contract.address, &context)?;
+    let primary_asset_withdrawn = received[0].clone();
+    let secondary_asset_withdrawn = received[1].clone();
+
+    let parsed_assets = Pair::parse_withdraw_events(
         &response.events,
         &config.primary_asset_info,
         &config.secondary_asset_info,
-    )?;
+    );
+    let events_agree = parsed_assets.ok()
+        == Some((primary_asset_withdrawn.clone(), secondary_asset_withdrawn.clone()));
 
     position.unlocked_assets.add(&primary_asset_withdrawn)?;
     position.unlocked_assets.add(&secondary_asset_withdrawn)?;
 
     POSITION.save(deps.storage, &user_addr, &position)?;
-    CACHED_USER_ADDR.remove(deps.storage);
 
     Ok(Response::new()
         .add_attribute("action", "martian_field/reply/after_withdraw_liquidity")
         .add_attribute("user", user_addr)
         .add_attribute("primary_withdrawn", primary_asset_withdrawn.amount)
-        .add_attribute("secondary_withdrawn", secondary_asset_withdrawn.amount))
+        .add_attribute("secondary_withdrawn", secondary_asset_withdrawn.amount)
+        .add_attribute("events_agree", events_agree.to_string()))
 }
 
-pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult<Response> {
-    // if this is a user swapping their unlocked assets, the user's address should have been cached
-    // if this is a reward harvesting operation, no user address should have been cached. `may_load`
-    // should return `None` in this case
-    let user_addr_option = CACHED_USER_ADDR.may_load(deps.storage)?;
+pub fn after_swap(
+    deps: DepsMut,
+    env: Env,
+    response: SubMsgExecutionResponse,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let context = consume_reply_context(deps.storage, ReplyId::Swap)?;
+
+    // if this is a user swapping their unlocked assets, the user's address is recorded in the
+    // context. if this is a reward harvesting operation, it is `None`
+    let user_addr_option = context.user_addr.clone();
 
     // if a user address is cached, we update the user's unlocked assets
     // if not, we update the state's pending rewards
@@ -99,11 +159,16 @@ pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult
         assets = &mut state.pending_rewards;
     }
 
-    // parse Astroport's event log to find out how much asset was returned from the swap
-    let returned_asset_unchecked = Pair::parse_swap_events(&response.events)?;
-    let returned_asset = returned_asset_unchecked.check(deps.api)?;
+    // the amount of ask asset returned from the swap is the increase in the contract's balance
+    let received = compute_received_assets(&deps.querier, &env.contract.address, &context)?;
+    let returned_asset = received[0].clone();
     assets.add(&returned_asset)?;
 
+    let known_asset_infos =
+        [&config.primary_asset_info, &config.secondary_asset_info, &config.astro_token_info];
+    let events_agree = Pair::parse_swap_events(&response.events, &known_asset_infos).ok()
+        == Some(returned_asset.clone());
+
     // save the updated state/position
     if let Some(user_addr) = &user_addr_option {
         POSITION.save(deps.storage, user_addr, &position)?;
@@ -111,17 +176,15 @@ pub fn after_swap(deps: DepsMut, response: SubMsgExecutionResponse) -> StdResult
         STATE.save(deps.storage, &state)?;
     }
 
-    // finally, clear cached data
-    CACHED_USER_ADDR.remove(deps.storage);
-
     Ok(Response::new()
         .add_attribute("action", "martian_field/reply/after_swap")
-        .add_attribute("returned_asset", returned_asset.to_string()))
+        .add_attribute("returned_asset", returned_asset.to_string())
+        .add_attribute("events_agree", events_agree.to_string()))
 }
 
 pub fn failed_apollo_reward_update(
     result: ContractResult<SubMsgExecutionResponse>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     match result {
         ContractResult::Ok(_) => {
             // Should not happen, since we only add this submsg with reply_on_error.
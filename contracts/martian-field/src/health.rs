@@ -1,18 +1,13 @@
-use cosmwasm_std::{Decimal, Env, QuerierWrapper, StdResult, Uint128};
+use std::cmp;
 
-use fields_of_mars::martian_field::{Config, Health, Position, State};
+use cosmwasm_std::{Decimal, Deps, Env, Fraction, Uint128};
 
-/// This module is purely a workaround that lets us ignore lints for all the code the `construct_uint!`
-/// macro generates
-#[allow(clippy::all)]
-mod uints {
-    uint::construct_uint! {
-        pub struct U256(4);
-    }
-}
+use fields_of_mars::martian_field::{Config, Health, PauseFlags, Position, State};
 
-/// Used internally - we don't want to leak this type since we might change the implementation in the future
-use uints::U256;
+use crate::error::ContractError;
+use crate::helpers::assert_leverage_increase_not_paused;
+use crate::math::U256;
+use crate::prices::query_price;
 
 /// Compute the value of the lp token used in this strategy.
 ///
@@ -20,26 +15,39 @@ use uints::U256;
 /// since we also need this value in compute_health and don't want to perform the
 /// expensive SmartQuery twice.
 pub fn compute_value_per_lp_token(
-    querier: &QuerierWrapper,
+    deps: Deps,
     config: &Config,
     primary_price: Option<Decimal>,
     secondary_price: Option<Decimal>,
-) -> StdResult<Decimal> {
+) -> Result<Decimal, ContractError> {
     let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_pool(
-        querier,
+        &deps.querier,
         &config.primary_asset_info,
         &config.secondary_asset_info,
     )?;
 
-    let primary_price = primary_price.map_or_else(
-        || config.oracle.query_price(querier, &config.primary_asset_info),
-        |x| Ok(x),
-    )?;
-    let secondary_price = secondary_price.map_or_else(
-        || config.oracle.query_price(querier, &config.secondary_asset_info),
-        |x| Ok(x),
-    )?;
+    let primary_price = primary_price
+        .map_or_else(|| query_price(deps, config, &config.primary_asset_info), |x| Ok(x))?;
+    let secondary_price = secondary_price
+        .map_or_else(|| query_price(deps, config, &config.secondary_asset_info), |x| Ok(x))?;
 
+    Ok(compute_lp_value(
+        primary_depth,
+        secondary_depth,
+        total_shares,
+        primary_price,
+        secondary_price,
+    ))
+}
+
+/// Compute the value of the lp token from given pool depths and asset prices
+pub fn compute_lp_value(
+    primary_depth: Uint128,
+    secondary_depth: Uint128,
+    total_shares: Uint128,
+    primary_price: Decimal,
+    secondary_price: Decimal,
+) -> Decimal {
     // RE the calculation of the value of liquidity token, see:
     // https://blog.alphafinance.io/fair-lp-token-pricing/
     // this formulation avoids a potential sandwich attack that distorts asset prices by a flashloan
@@ -51,55 +59,72 @@ pub fn compute_value_per_lp_token(
 
     let pool_value_u128 = Uint128::new(pool_value.as_u128());
 
-    let lp_value = if total_shares.is_zero() {
+    if total_shares.is_zero() {
         Decimal::zero()
     } else {
         Decimal::from_ratio(pool_value_u128, total_shares)
-    };
+    }
+}
 
-    Ok(lp_value)
+/// Values of the contract's total bonded liquidity tokens and total debt, along with the units they
+/// are divided into, from which the health of every position is derived
+pub struct TotalValues {
+    pub bonded_value: Uint128,
+    pub debt_value: Uint128,
+    pub bond_units: Uint128,
+    pub debt_units: Uint128,
+    pub secondary_price: Decimal,
 }
 
-/// Compute the health of a user's position
-pub fn compute_health(
-    querier: &QuerierWrapper,
+/// Compute the values of the contract's total bonded liquidity tokens and total debt
+///
+/// Health of multiple positions can be computed from the same values, without repeating the
+/// queries to Astro generator, Red Bank, and the price sources
+pub fn compute_total_values(
+    deps: Deps,
     env: &Env,
     config: &Config,
     state: &State,
-    position: &Position,
-) -> StdResult<Health> {
+) -> Result<TotalValues, ContractError> {
     let total_bonded_amount = config.astro_generator.query_bonded_amount(
-        querier,
+        &deps.querier,
         &env.contract.address,
         &config.primary_pair.liquidity_token,
     )?;
 
     let total_debt_amount = config.red_bank.query_user_debt(
-        querier,
+        &deps.querier,
         &env.contract.address,
         &config.secondary_asset_info,
     )?;
 
-    let secondary_price = config.oracle.query_price(querier, &config.secondary_asset_info)?;
-    let lp_value = compute_value_per_lp_token(querier, config, None, Some(secondary_price))?;
-
-    let total_bonded_value = total_bonded_amount * lp_value;
+    let secondary_price = query_price(deps, config, &config.secondary_asset_info)?;
+    let lp_value = compute_value_per_lp_token(deps, config, None, Some(secondary_price))?;
 
-    // compute the value of the contract's total debt
-    let total_debt_value = total_debt_amount * secondary_price;
+    Ok(TotalValues {
+        bonded_value: total_bonded_amount * lp_value,
+        // compute the value of the contract's total debt
+        debt_value: total_debt_amount * secondary_price,
+        bond_units: state.total_bond_units,
+        debt_units: state.total_debt_units,
+        secondary_price,
+    })
+}
 
+/// Compute the health of a user's position from the contract's total values
+pub fn compute_position_health(totals: &TotalValues, position: &Position) -> Health {
     // compute the value of the user's bonded liquidity tokens
-    let bond_value = if state.total_bond_units.is_zero() {
+    let bond_value = if totals.bond_units.is_zero() {
         Uint128::zero()
     } else {
-        total_bonded_value.multiply_ratio(position.bond_units, state.total_bond_units)
+        totals.bonded_value.multiply_ratio(position.bond_units, totals.bond_units)
     };
 
     // compute the value of the user's debt
-    let debt_value = if state.total_debt_units.is_zero() {
+    let debt_value = if totals.debt_units.is_zero() {
         Uint128::zero()
     } else {
-        total_debt_value.multiply_ratio(position.debt_units, state.total_debt_units)
+        totals.debt_value.multiply_ratio(position.debt_units, totals.debt_units)
     };
 
     // compute LTV
@@ -112,9 +137,163 @@ pub fn compute_health(
         Some(Decimal::from_ratio(debt_value, bond_value))
     };
 
-    Ok(Health {
+    Health {
         bond_value,
         debt_value,
         ltv,
-    })
+    }
+}
+
+/// Compute the health of a user's position
+pub fn compute_health(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    state: &State,
+    position: &Position,
+) -> Result<Health, ContractError> {
+    let totals = compute_total_values(deps, env, config, state)?;
+    Ok(compute_position_health(&totals, position))
+}
+
+/// Assert that a position is healthy after being updated: its LTV must not exceed `max_initial_ltv`,
+/// a position without bonded liquidity tokens must not have debt, and an open position must be at
+/// least `min_position_size` in value
+pub fn assert_position_healthy(
+    config: &Config,
+    position: &Position,
+    health: &Health,
+) -> Result<(), ContractError> {
+    // If ltv is Some(ltv), we assert it is no larger than `config.max_initial_ltv`
+    // If it is None, meaning `bond_value` is zero, we assert debt is also zero
+    let healthy = if let Some(ltv) = health.ltv {
+        ltv <= config.max_initial_ltv
+    } else {
+        health.debt_value.is_zero()
+    };
+
+    if !healthy {
+        return Err(ContractError::LtvTooHigh {
+            ltv: health.ltv,
+            max: config.max_initial_ltv,
+        });
+    }
+
+    // Check minimum position size. A closed position, i.e. one with neither bond nor debt units,
+    // has no size to check
+    let closed = position.bond_units.is_zero() && position.debt_units.is_zero();
+    if !closed && health.bond_value < config.min_position_size {
+        return Err(ContractError::PositionTooSmall {
+            size: health.bond_value,
+            min: config.min_position_size,
+        });
+    }
+
+    Ok(())
+}
+
+/// How the debt of a position is changed to bring it to a target LTV
+#[derive(Debug, PartialEq)]
+pub enum LeverageChange {
+    /// Borrow the given amount of secondary asset
+    Borrow {
+        borrow_amount: Uint128,
+    },
+    /// Repay the given amount of secondary asset, unbonding the given bond units first if the
+    /// position's unlocked assets are not enough to cover the repayment
+    Repay {
+        repay_amount: Uint128,
+        bond_units_to_reduce: Option<Uint128>,
+    },
+}
+
+/// Assert that a position can be brought to `target_ltv`
+///
+/// The target LTV must not exceed the maximum initial LTV, otherwise `AssertHealth` will fail
+/// anyways. This also ensures that `1 - target_ltv` is non-zero
+pub fn assert_target_ltv(config: &Config, target_ltv: Decimal) -> Result<(), ContractError> {
+    if target_ltv > config.max_initial_ltv {
+        return Err(ContractError::LtvTooHigh {
+            ltv: Some(target_ltv),
+            max: config.max_initial_ltv,
+        });
+    }
+    Ok(())
+}
+
+/// Compute how to bring a position to `target_ltv`, which must have been checked with
+/// `assert_target_ltv`, along with whether the position's unlocked assets are to be provided and
+/// bonded afterwards
+///
+/// The user's unlocked primary and secondary assets, e.g. ones deposited in the same transaction,
+/// are to be bonded as well, so they are included in the position's value
+pub fn compute_leverage_change(
+    config: &Config,
+    pause_flags: &PauseFlags,
+    position: &Position,
+    health: &Health,
+    primary_price: Decimal,
+    secondary_price: Decimal,
+    target_ltv: Decimal,
+) -> Result<(LeverageChange, bool), ContractError> {
+    let unlocked_amount = |asset_info| {
+        position.unlocked_assets.find(asset_info).map(|asset| asset.amount).unwrap_or_default()
+    };
+    let unlocked_value = unlocked_amount(&config.primary_asset_info) * primary_price
+        + unlocked_amount(&config.secondary_asset_info) * secondary_price;
+    let total_value = health.bond_value + unlocked_value;
+    let target_debt_value = total_value * target_ltv;
+
+    // borrowing (or repaying) secondary asset of value `x` and bonding it (or unbonding liquidity
+    // tokens of that value) changes debt and asset values by the same amount, so we need to solve
+    //
+    // (debt_value + x) / (total_value + x) = target_ltv
+    //
+    // which gives x = (target_ltv * total_value - debt_value) / (1 - target_ltv)
+    let one_minus_target_ltv = Decimal::one() - target_ltv;
+    if target_debt_value >= health.debt_value {
+        assert_leverage_increase_not_paused(pause_flags)?;
+
+        let borrow_value = (target_debt_value - health.debt_value)
+            .multiply_ratio(one_minus_target_ltv.denominator(), one_minus_target_ltv.numerator());
+        let borrow_amount =
+            borrow_value.multiply_ratio(secondary_price.denominator(), secondary_price.numerator());
+
+        // unlocked assets are rebonded along with the borrowed amount. if there is neither, e.g. the
+        // position is already at the target LTV, there is nothing to provide
+        let rebond = !borrow_amount.is_zero() || !unlocked_value.is_zero();
+        Ok((
+            LeverageChange::Borrow {
+                borrow_amount,
+            },
+            rebond,
+        ))
+    } else {
+        let repay_value = (health.debt_value - target_debt_value)
+            .multiply_ratio(one_minus_target_ltv.denominator(), one_minus_target_ltv.numerator());
+        let repay_amount =
+            repay_value.multiply_ratio(secondary_price.denominator(), secondary_price.numerator());
+
+        // repaying is never paused. assets are only left over if the unlocked ones exceed the
+        // repayment, in which case they are rebonded if swapping and bonding are not paused, and
+        // otherwise refunded to the user
+        let rebond = unlocked_value > repay_value && !pause_flags.swap && !pause_flags.bond;
+
+        // if unlocked assets are not enough to cover the repayment, unbond liquidity tokens of the
+        // value of the shortfall
+        let bond_units_to_reduce = if repay_value > unlocked_value && !health.bond_value.is_zero() {
+            let unbond_value = cmp::min(repay_value - unlocked_value, health.bond_value);
+            Some(position.bond_units.multiply_ratio(unbond_value, health.bond_value))
+        } else {
+            None
+        };
+
+        Ok((
+            LeverageChange::Repay {
+                repay_amount,
+                bond_units_to_reduce,
+            },
+            rebond,
+        ))
+    }
 }
@@ -1,6 +1,19 @@
-use cosmwasm_std::{Reply, StdError, StdResult, SubMsgExecutionResponse, Uint128};
+use cosmwasm_std::{
+    attr, to_vec, Addr, Attribute, Decimal, Deps, Fraction, QuerierWrapper, Reply, StdError,
+    StdResult, Storage, SubMsgExecutionResponse, Uint128,
+};
+use cw20::{BalanceResponse, Cw20QueryMsg};
+use serde::Serialize;
 
-use cw_asset::{Asset, AssetList};
+use cw_asset::{Asset, AssetInfo, AssetList};
+
+use fields_of_mars::adapters::Pair;
+use fields_of_mars::martian_field::msg::Action;
+use fields_of_mars::martian_field::{Config, ConfigUnchecked, PauseFlags, ReplyContext, ReplyId};
+
+use crate::error::ContractError;
+use crate::prices::query_price;
+use crate::state::REPLY_CONTEXT;
 
 /// Extract response from reply
 pub fn unwrap_reply(reply: Reply) -> StdResult<SubMsgExecutionResponse> {
@@ -8,7 +21,7 @@ pub fn unwrap_reply(reply: Reply) -> StdResult<SubMsgExecutionResponse> {
 }
 
 /// Assert that fund of exactly the same type and amount was sent along with a message
-pub fn assert_sent_fund(expected: &Asset, received_coins: &AssetList) -> StdResult<()> {
+pub fn assert_sent_fund(expected: &Asset, received_coins: &AssetList) -> Result<(), ContractError> {
     let received_amount = if let Some(coin) = received_coins.find(&expected.info) {
         coin.amount
     } else {
@@ -16,10 +29,304 @@ pub fn assert_sent_fund(expected: &Asset, received_coins: &AssetList) -> StdResu
     };
 
     if received_amount != expected.amount {
-        return Err(StdError::generic_err(
-            format!("sent fund mismatch! expected: {}, received {}",expected, received_amount)
-        ));
-    } 
+        return Err(ContractError::SentFundMismatch {
+            expected: expected.to_string(),
+            received: received_amount,
+        });
+    }
 
     Ok(())
 }
+
+/// Compute the belief price of a swap, i.e. the amount of offer asset expected to be paid per unit
+/// of ask asset, from the prices of the two assets
+///
+/// Returns `None` if either asset has a price of zero
+pub fn compute_belief_price(offer_price: Decimal, ask_price: Decimal) -> Option<Decimal> {
+    if offer_price.is_zero() || ask_price.is_zero() {
+        return None;
+    }
+    Some(Decimal::from_ratio(ask_price.numerator(), offer_price.numerator()))
+}
+
+/// Derive the belief price of a swap in `pair` from the prices of the offer and ask assets,
+/// and assert that the pool's spot price does not deviate from it by more than
+/// `price_deviation_tolerance`
+pub fn query_belief_price(
+    deps: Deps,
+    config: &Config,
+    pair: &Pair,
+    offer_asset_info: &AssetInfo,
+    ask_asset_info: &AssetInfo,
+) -> Result<Option<Decimal>, ContractError> {
+    let offer_price = query_price(deps, config, offer_asset_info)?;
+    let ask_price = query_price(deps, config, ask_asset_info)?;
+    let belief_price = match compute_belief_price(offer_price, ask_price) {
+        Some(belief_price) => belief_price,
+        None => return Ok(None),
+    };
+
+    // the pool's spot price, in the same unit as the belief price. if the pool is empty, the swap
+    // fails anyways
+    let (offer_depth, ask_depth, _) =
+        pair.query_pool(&deps.querier, offer_asset_info, ask_asset_info)?;
+    if !ask_depth.is_zero() {
+        let pool_price = Decimal::from_ratio(offer_depth, ask_depth);
+        assert_price_deviation(pool_price, belief_price, config.price_deviation_tolerance)?;
+    }
+
+    Ok(Some(belief_price))
+}
+
+/// Assert that `pool_price` deviates from `oracle_price` by no more than `tolerance`, relative to
+/// the oracle price
+pub fn assert_price_deviation(
+    pool_price: Decimal,
+    oracle_price: Decimal,
+    tolerance: Decimal,
+) -> Result<(), ContractError> {
+    let diff = if pool_price > oracle_price {
+        pool_price - oracle_price
+    } else {
+        oracle_price - pool_price
+    };
+    let deviation = Decimal::from_ratio(diff.numerator(), oracle_price.numerator());
+
+    if deviation > tolerance {
+        return Err(ContractError::PriceDeviationTooHigh {
+            deviation,
+            max: tolerance,
+        });
+    }
+
+    Ok(())
+}
+
+/// Assert that an action is not paused
+pub fn assert_not_paused(paused: bool, action: &str) -> Result<(), ContractError> {
+    if paused {
+        return Err(ContractError::ActionPaused {
+            action: action.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Assert that none of the operations an action consists of is paused
+///
+/// Repaying and unbonding are never paused, so that users can always close their positions. Whether
+/// `SetLeverage` borrows or repays is only known in its callback, which checks the flags itself
+pub fn assert_action_not_paused(
+    pause_flags: &PauseFlags,
+    action: &Action,
+) -> Result<(), ContractError> {
+    match action {
+        Action::Deposit(_) => assert_not_paused(pause_flags.deposit, "deposit"),
+        Action::Borrow {
+            ..
+        } => assert_not_paused(pause_flags.borrow, "borrow"),
+        Action::Bond {
+            ..
+        } => assert_not_paused(pause_flags.bond, "bond"),
+        Action::Swap {
+            ..
+        } => assert_not_paused(pause_flags.swap, "swap"),
+        Action::Zap {
+            ..
+        } => {
+            assert_not_paused(pause_flags.deposit, "deposit")?;
+            assert_not_paused(pause_flags.swap, "swap")?;
+            assert_not_paused(pause_flags.bond, "bond")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Increasing leverage is subject to the same pause flags as the `Borrow`, `Swap` and `Bond` actions
+pub fn assert_leverage_increase_not_paused(pause_flags: &PauseFlags) -> Result<(), ContractError> {
+    assert_not_paused(pause_flags.borrow, "borrow")?;
+    assert_not_paused(pause_flags.swap, "swap")?;
+    assert_not_paused(pause_flags.bond, "bond")
+}
+
+/// Parse a user's address from the key of a map keyed by address
+pub fn parse_user_addr(k: Vec<u8>) -> Result<Addr, ContractError> {
+    String::from_utf8(k).map(Addr::unchecked).map_err(|_| ContractError::InvalidAddressKey {})
+}
+
+/// Query the amount of an asset held by an account
+pub fn query_balance(
+    querier: &QuerierWrapper,
+    asset_info: &AssetInfo,
+    address: &Addr,
+) -> StdResult<Uint128> {
+    match asset_info {
+        AssetInfo::Cw20(contract_addr) => {
+            let response: BalanceResponse = querier.query_wasm_smart(
+                contract_addr,
+                &Cw20QueryMsg::Balance {
+                    address: address.to_string(),
+                },
+            )?;
+            Ok(response.balance)
+        }
+        AssetInfo::Native(denom) => Ok(querier.query_balance(address, denom)?.amount),
+    }
+}
+
+/// Save the context of a submessage about to be dispatched, including the contract's balances of
+/// the assets it is expected to return, so that the reply handler can compute the amounts received
+/// from the balance changes
+///
+/// Fails if the context of a previous submessage has not been consumed by its reply handler
+pub fn save_reply_context(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    contract_addr: &Addr,
+    id: ReplyId,
+    user_addr: Option<&Addr>,
+    expected_asset_infos: &[&AssetInfo],
+) -> Result<(), ContractError> {
+    if let Some(context) = REPLY_CONTEXT.may_load(storage)? {
+        return Err(ContractError::ReplyContextNotConsumed {
+            id: context.id.into(),
+        });
+    }
+
+    let balances_before = expected_asset_infos
+        .iter()
+        .map(|info| Ok(Asset::new((*info).clone(), query_balance(querier, info, contract_addr)?)))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    let context = ReplyContext {
+        id,
+        user_addr: user_addr.cloned(),
+        balances_before: AssetList::from(balances_before),
+    };
+    Ok(REPLY_CONTEXT.save(storage, &context)?)
+}
+
+/// Compare two configs. For each parameter that differs, return two attributes recording its values
+/// before and after
+pub fn config_diff_attrs(
+    old_config: &ConfigUnchecked,
+    new_config: &ConfigUnchecked,
+) -> StdResult<Vec<Attribute>> {
+    let old = old_config;
+    let new = new_config;
+    let mut attrs: Vec<Attribute> = vec![];
+
+    push_diff_attrs(
+        &mut attrs,
+        "primary_asset_info",
+        &old.primary_asset_info,
+        &new.primary_asset_info,
+    )?;
+    push_diff_attrs(
+        &mut attrs,
+        "secondary_asset_info",
+        &old.secondary_asset_info,
+        &new.secondary_asset_info,
+    )?;
+    push_diff_attrs(&mut attrs, "astro_token_info", &old.astro_token_info, &new.astro_token_info)?;
+    push_diff_attrs(&mut attrs, "primary_pair", &old.primary_pair, &new.primary_pair)?;
+    push_diff_attrs(&mut attrs, "astro_pair", &old.astro_pair, &new.astro_pair)?;
+    push_diff_attrs(&mut attrs, "astro_generator", &old.astro_generator, &new.astro_generator)?;
+    push_diff_attrs(&mut attrs, "red_bank", &old.red_bank, &new.red_bank)?;
+    push_diff_attrs(
+        &mut attrs,
+        "primary_price_source",
+        &old.primary_price_source,
+        &new.primary_price_source,
+    )?;
+    push_diff_attrs(
+        &mut attrs,
+        "secondary_price_source",
+        &old.secondary_price_source,
+        &new.secondary_price_source,
+    )?;
+    push_diff_attrs(
+        &mut attrs,
+        "astro_price_source",
+        &old.astro_price_source,
+        &new.astro_price_source,
+    )?;
+    push_diff_attrs(&mut attrs, "treasury", &old.treasury, &new.treasury)?;
+    push_diff_attrs(&mut attrs, "governance", &old.governance, &new.governance)?;
+    push_diff_attrs(&mut attrs, "guardian", &old.guardian, &new.guardian)?;
+    push_diff_attrs(&mut attrs, "fee_manager", &old.fee_manager, &new.fee_manager)?;
+    push_diff_attrs(&mut attrs, "operator_manager", &old.operator_manager, &new.operator_manager)?;
+    push_diff_attrs(
+        &mut attrs,
+        "permissionless_harvest",
+        &old.permissionless_harvest,
+        &new.permissionless_harvest,
+    )?;
+    push_diff_attrs(&mut attrs, "max_ltv", &old.max_ltv, &new.max_ltv)?;
+    push_diff_attrs(&mut attrs, "max_initial_ltv", &old.max_initial_ltv, &new.max_initial_ltv)?;
+    push_diff_attrs(&mut attrs, "performance_fee", &old.performance_fee, &new.performance_fee)?;
+    push_diff_attrs(&mut attrs, "harvest_bounty", &old.harvest_bounty, &new.harvest_bounty)?;
+    push_diff_attrs(
+        &mut attrs,
+        "min_harvest_interval",
+        &old.min_harvest_interval,
+        &new.min_harvest_interval,
+    )?;
+    push_diff_attrs(&mut attrs, "bonus_rate", &old.bonus_rate, &new.bonus_rate)?;
+    push_diff_attrs(&mut attrs, "close_factor", &old.close_factor, &new.close_factor)?;
+    push_diff_attrs(
+        &mut attrs,
+        "full_liquidation_ltv",
+        &old.full_liquidation_ltv,
+        &new.full_liquidation_ltv,
+    )?;
+    push_diff_attrs(
+        &mut attrs,
+        "liquidation_max_spread",
+        &old.liquidation_max_spread,
+        &new.liquidation_max_spread,
+    )?;
+    push_diff_attrs(
+        &mut attrs,
+        "price_deviation_tolerance",
+        &old.price_deviation_tolerance,
+        &new.price_deviation_tolerance,
+    )?;
+    push_diff_attrs(&mut attrs, "twap_window", &old.twap_window, &new.twap_window)?;
+    push_diff_attrs(&mut attrs, "max_price_age", &old.max_price_age, &new.max_price_age)?;
+    push_diff_attrs(
+        &mut attrs,
+        "apr_query_adapter",
+        &old.apr_query_adapter,
+        &new.apr_query_adapter,
+    )?;
+    push_diff_attrs(&mut attrs, "apollo_factory", &old.apollo_factory, &new.apollo_factory)?;
+    push_diff_attrs(
+        &mut attrs,
+        "min_position_size",
+        &old.min_position_size,
+        &new.min_position_size,
+    )?;
+
+    Ok(attrs)
+}
+
+fn push_diff_attrs<T: Serialize + PartialEq>(
+    attrs: &mut Vec<Attribute>,
+    key: &str,
+    old_value: &T,
+    new_value: &T,
+) -> StdResult<()> {
+    if old_value != new_value {
+        attrs.push(attr(format!("{}_before", key), to_attr_value(old_value)?));
+        attrs.push(attr(format!("{}_after", key), to_attr_value(new_value)?));
+    }
+    Ok(())
+}
+
+/// Serialize a value to JSON so that it can be recorded in logs. Quotes around strings are removed
+fn to_attr_value<T: Serialize>(value: &T) -> StdResult<String> {
+    let json = String::from_utf8(to_vec(value)?)
+        .map_err(|_| StdError::generic_err("failed to serialize attribute value"))?;
+    Ok(json.trim_matches('"').to_string())
+}
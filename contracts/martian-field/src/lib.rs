@@ -1,12 +1,18 @@
+pub mod accounting;
 #[cfg(not(feature = "library"))]
 pub mod contract;
+pub mod error;
 pub mod execute;
 pub mod execute_callbacks;
 pub mod execute_replies;
 pub mod health;
 pub mod helpers;
+pub mod math;
+pub mod migrations;
+pub mod prices;
 pub mod queries;
+pub mod simulation;
 pub mod state;
 
 #[cfg(test)]
-mod contract_tests;
\ No newline at end of file
+mod contract_tests;
@@ -0,0 +1,233 @@
+use std::cmp;
+
+use cosmwasm_std::{Decimal, Fraction, Uint128};
+
+use fields_of_mars::adapters::ASTROPORT_TWAP_PRECISION;
+use fields_of_mars::martian_field::PriceSnapshot;
+
+use crate::error::ContractError;
+
+/// This module is purely a workaround that lets us ignore lints for all the code the `construct_uint!`
+/// macro generates
+#[allow(clippy::all)]
+mod uints {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+
+    uint::construct_uint! {
+        pub struct U512(8);
+    }
+}
+
+/// Used internally - we don't want to leak these types since we might change the implementation in
+/// the future
+pub(crate) use uints::{U256, U512};
+
+const DECIMAL_FRACTIONAL: Uint128 = Uint128::new(1_000_000_000_000_000_000);
+
+/// Compute how much of the offer asset should be swapped, such that after the swap, the amounts of
+/// offer and ask assets we hold are in the same ratio as the pool depths. This way, all of them can
+/// be provided to the pool without leaving any leftover
+///
+/// Denote the amounts of offer and ask assets we hold as `a` and `b`, the pool depths as `A` and
+/// `B`, and the commission rate as `c`. Swapping `x` units of the offer asset returns
+///
+/// ```plain
+/// y = (1 - c) * B * x / (A + x)
+/// ```
+///
+/// and the commission stays in the pool, so the depths become `A + x` and `B - y`. We look for `x`
+/// such that `(a - x) / (b + y) = (A + x) / (B - y)`. Substituting `y` and rearranging gives the
+/// quadratic equation `α * x^2 + β * x - γ = 0`, where
+///
+/// ```plain
+/// α = b + B
+/// β = 2 * A * (b + B) - c * B * (A + a)
+/// γ = A * (a * B - b * A)
+/// ```
+///
+/// The positive root is `x = 2γ / (β + sqrt(β^2 + 4αγ))`. We scale `β` and `γ` by the decimal
+/// fractional so that the commission rate can be handled as an integer, and use 512-bit integers as
+/// the intermediate terms overflow 256 bits for pools with 18-decimal tokens
+///
+/// NOTE: the caller must make sure we hold the offer asset in excess, i.e. `a * B >= b * A`;
+/// otherwise zero is returned
+pub fn compute_optimal_swap_amount(
+    offer_amount: Uint128,
+    ask_amount: Uint128,
+    offer_depth: Uint128,
+    ask_depth: Uint128,
+    commission_rate: Decimal,
+) -> Result<Uint128, ContractError> {
+    let a = U512::from(offer_amount.u128());
+    let b = U512::from(ask_amount.u128());
+    let pool_a = U512::from(offer_depth.u128());
+    let pool_b = U512::from(ask_depth.u128());
+    let c = U512::from((DECIMAL_FRACTIONAL * commission_rate).u128());
+    let d = U512::from(DECIMAL_FRACTIONAL.u128());
+
+    // if we don't hold the offer asset in excess, there is nothing to swap
+    if a * pool_b <= b * pool_a {
+        return Ok(Uint128::zero());
+    }
+
+    let alpha = b + pool_b;
+    let beta = (U512::from(2) * pool_a * alpha * d)
+        .checked_sub(c * pool_b * (pool_a + a))
+        .ok_or(ContractError::OfferAmountTooLarge {})?;
+    let gamma = pool_a * (a * pool_b - b * pool_a) * d;
+
+    let discriminant = beta * beta + U512::from(4) * alpha * gamma * d;
+    let x = U512::from(2) * gamma / (beta + discriminant.integer_sqrt());
+
+    // due to rounding, the computed amount may slightly exceed what we hold
+    Ok(Uint128::new(x.min(a).as_u128()))
+}
+
+/// Compute the amount of ask asset returned by swapping in an Astroport constant product pool, after
+/// the commission is deducted. Same as Astroport's `Simulation` query, but against given depths
+pub fn compute_swap_return(
+    offer_amount: Uint128,
+    offer_depth: Uint128,
+    ask_depth: Uint128,
+    commission_rate: Decimal,
+) -> Result<Uint128, ContractError> {
+    let offer_depth_after = offer_depth.checked_add(offer_amount)?;
+    if offer_depth_after.is_zero() {
+        return Ok(Uint128::zero());
+    }
+
+    // return_amount = ask_depth - offer_depth * ask_depth / (offer_depth + offer_amount)
+    let return_amount =
+        ask_depth.checked_sub(ask_depth.multiply_ratio(offer_depth, offer_depth_after))?;
+    Ok(return_amount.checked_sub(return_amount * commission_rate)?)
+}
+
+/// Compute the amount of offer asset needed for a swap in an Astroport constant product pool to
+/// return a given amount of ask asset, after the commission is deducted. Same as Astroport's
+/// `ReverseSimulation` query, but against given depths
+pub fn compute_swap_offer(
+    ask_amount: Uint128,
+    offer_depth: Uint128,
+    ask_depth: Uint128,
+    commission_rate: Decimal,
+) -> Result<Uint128, ContractError> {
+    // the amount to be returned before the commission is deducted
+    let one_minus_commission_rate = Decimal::one() - commission_rate;
+    let ask_amount_before_commission = ask_amount.multiply_ratio(
+        one_minus_commission_rate.denominator(),
+        one_minus_commission_rate.numerator(),
+    );
+    if ask_amount_before_commission >= ask_depth {
+        return Err(ContractError::AskAmountExceedsDepth {
+            ask_amount: ask_amount_before_commission,
+            depth: ask_depth,
+        });
+    }
+
+    // offer_amount = offer_depth * ask_depth / (ask_depth - ask_amount) - offer_depth
+    let offer_depth_after =
+        offer_depth.multiply_ratio(ask_depth, ask_depth - ask_amount_before_commission);
+    Ok(offer_depth_after.checked_sub(offer_depth)?)
+}
+
+/// Compute the value of debt to be repaid in a partial liquidation
+///
+/// A partial liquidation repays debt of value `x`, and seizes bonded assets of value
+/// `x * (1 + bonus_rate)`, the excess being the liquidator's bonus. For the position's LTV to
+/// become `target_ltv`, we need
+///
+/// ```plain
+/// (debt_value - x) / (bond_value - x * (1 + bonus_rate)) = target_ltv
+/// ```
+///
+/// which gives `x = (debt_value - target_ltv * bond_value) / (1 - target_ltv * (1 + bonus_rate))`.
+/// The repaid value is capped at `close_factor` of the debt value
+///
+/// Returns `None` if the position can't be partially liquidated, i.e. if it is already below the
+/// target LTV, or seizing the required assets would take all of its bonded assets. In this case
+/// the position should be liquidated in full
+pub fn compute_partial_liquidation_value(
+    debt_value: Uint128,
+    bond_value: Uint128,
+    target_ltv: Decimal,
+    bonus_rate: Decimal,
+    close_factor: Decimal,
+) -> Option<Uint128> {
+    let excess_value = debt_value.checked_sub(bond_value * target_ltv).ok()?;
+    if excess_value.is_zero() {
+        return None;
+    }
+
+    // scale the denominator by the decimal fractional so that it can be handled as an integer
+    let target_ltv_scaled = DECIMAL_FRACTIONAL * target_ltv;
+    let denominator = DECIMAL_FRACTIONAL
+        .checked_sub(target_ltv_scaled)
+        .ok()?
+        .checked_sub(target_ltv_scaled * bonus_rate)
+        .ok()?;
+    if denominator.is_zero() {
+        return None;
+    }
+
+    let repay_value = excess_value.multiply_ratio(DECIMAL_FRACTIONAL, denominator);
+    let repay_value = cmp::min(repay_value, debt_value * close_factor);
+
+    let seize_value = repay_value + repay_value * bonus_rate;
+    if seize_value >= bond_value {
+        return None;
+    }
+
+    Some(repay_value)
+}
+
+/// Compute the price of the primary asset at which a position's LTV reaches `max_ltv`, all else
+/// being equal
+///
+/// Liquidity tokens are valued as `2 * sqrt(primary_depth * primary_price * secondary_depth *
+/// secondary_price) / total_shares`, so the bond value scales with the square root of the primary
+/// asset's price, while the debt value does not depend on it. The LTV reaching `max_ltv` thus gives
+///
+/// ```plain
+/// liquidation_price = primary_price * (debt_value / (bond_value * max_ltv))^2
+/// ```
+///
+/// Returns `None` if the position has no debt or no bonded assets, in which case it can't be
+/// liquidated at any price, or if the price is too large to be represented
+pub fn compute_liquidation_price(
+    primary_price: Decimal,
+    bond_value: Uint128,
+    debt_value: Uint128,
+    max_ltv: Decimal,
+) -> Option<Decimal> {
+    let max_debt_value = bond_value * max_ltv;
+    if debt_value.is_zero() || max_debt_value.is_zero() {
+        return None;
+    }
+
+    // NOTE: the intermediate terms may overflow 256 bits, so we use 512-bit integers
+    let debt_value = U512::from(debt_value.u128());
+    let max_debt_value = U512::from(max_debt_value.u128());
+    let numerator = U512::from(primary_price.numerator()) * debt_value * debt_value
+        / (max_debt_value * max_debt_value);
+    if numerator > U512::from(u128::MAX) {
+        return None;
+    }
+    Some(Decimal::from_ratio(numerator.as_u128(), primary_price.denominator()))
+}
+
+/// Compute the time-weighted average price between two snapshots of an Astroport pair's cumulative
+/// price
+///
+/// NOTE: the cumulative price may overflow and wrap around between the two snapshots, in which case
+/// wrapping subtraction still gives the correct difference
+pub fn compute_twap(start: &PriceSnapshot, end: &PriceSnapshot) -> Result<Decimal, ContractError> {
+    let period = end.timestamp.saturating_sub(start.timestamp);
+    if period == 0 {
+        return Err(ContractError::ZeroTwapPeriod {});
+    }
+
+    let price_delta = end.price_cumulative.u128().wrapping_sub(start.price_cumulative.u128());
+    Ok(Decimal::from_ratio(price_delta, u128::from(period) * ASTROPORT_TWAP_PRECISION))
+}
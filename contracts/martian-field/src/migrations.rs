@@ -0,0 +1,241 @@
+use cosmwasm_std::{Decimal, DepsMut, Env, Event, Response, StdResult, Storage};
+use cw2::{set_contract_version, ContractVersion, CONTRACT};
+use cw_storage_plus::Item;
+use semver::Version;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use fields_of_mars::adapters::PriceSource;
+use fields_of_mars::martian_field::msg::MigrateMsg;
+use fields_of_mars::martian_field::{Config, OperatorInfo, PauseFlags, State};
+
+use crate::error::ContractError;
+use crate::execute::apply_config_update;
+use crate::state::{CONFIG, OPERATORS, PAUSE_FLAGS, STATE};
+
+pub const CONTRACT_NAME: &str = "crates.io:martian-field";
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Contracts deployed before versioning was introduced don't have a version stored. All of them are
+/// running v1.0.0
+const UNVERSIONED_CONTRACT_VERSION: &str = "1.0.0";
+
+/// A migration step rewrites storage from one schema version to the next. Parameters introduced by
+/// the new version that have no safe default are read from the migrate message
+type MigrationStep = fn(DepsMut, &Env, &MigrateMsg) -> Result<(), ContractError>;
+
+/// Known schema versions, as 3-tuples of (from_version, to_version, step), in ascending order
+///
+/// Steps are applied one after another, starting from the stored version, until storage reaches
+/// `CONTRACT_VERSION`. A stored version not listed here cannot be migrated from
+const MIGRATIONS: &[(&str, &str, MigrationStep)] = &[("1.0.0", "1.1.0", migrate_v1_0_0_to_v1_1_0)];
+
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = CONTRACT.may_load(deps.storage)?.unwrap_or_else(|| ContractVersion {
+        contract: CONTRACT_NAME.to_string(),
+        version: UNVERSIONED_CONTRACT_VERSION.to_string(),
+    });
+
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::InvalidContractName {
+            expected: CONTRACT_NAME.to_string(),
+            actual: stored.contract,
+        });
+    }
+
+    if parse_version(&stored.version)? > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::DowngradeNotAllowed {
+            from: stored.version,
+            to: CONTRACT_VERSION.to_string(),
+        });
+    }
+
+    // migrate storage one schema version at a time
+    let mut version = stored.version.clone();
+    while version != CONTRACT_VERSION {
+        let (_, to_version, step) = MIGRATIONS
+            .iter()
+            .find(|(from_version, _, _)| *from_version == version)
+            .ok_or_else(|| ContractError::UnknownVersion {
+                version: version.clone(),
+            })?;
+        step(deps.branch(), &env, &msg)?;
+        version = to_version.to_string();
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    // the config update, if provided, is validated the same way as in `UpdateConfig`
+    let mut event = Event::new("config_updated");
+    if let Some(config_update) = msg.config_update {
+        event = event.add_attributes(apply_config_update(deps, config_update)?);
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "martian_field/migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_event(event))
+}
+
+fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version).map_err(|_| ContractError::InvalidVersion {
+        version: version.to_string(),
+    })
+}
+
+/// Rewrite an item stored as the legacy type `O` to the new type `N`
+pub fn migrate_item<O, N, F>(
+    storage: &mut dyn Storage,
+    old_item: Item<O>,
+    new_item: Item<N>,
+    f: F,
+) -> StdResult<()>
+where
+    O: Serialize + DeserializeOwned,
+    N: Serialize + DeserializeOwned,
+    F: Fn(O) -> StdResult<N>,
+{
+    let old_value = old_item.load(storage)?;
+    new_item.save(storage, &f(old_value)?)
+}
+
+/// Storage layouts used in v1.0.0
+pub(crate) mod v1_0_0 {
+    use cosmwasm_std::{Addr, Decimal, Uint128};
+    use cw_asset::{AssetInfo, AssetList};
+    use cw_storage_plus::Item;
+    use serde::{Deserialize, Serialize};
+
+    use fields_of_mars::adapters::{ApolloFactory, Generator, Oracle, Pair, RedBank};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Config {
+        pub primary_asset_info: AssetInfo,
+        pub secondary_asset_info: AssetInfo,
+        pub astro_token_info: AssetInfo,
+        pub primary_pair: Pair,
+        pub astro_pair: Pair,
+        pub astro_generator: Generator,
+        pub red_bank: RedBank,
+        pub oracle: Oracle,
+        pub treasury: Addr,
+        pub governance: Addr,
+        pub operators: Vec<Addr>,
+        pub max_ltv: Decimal,
+        pub max_initial_ltv: Decimal,
+        pub performance_fee: Decimal,
+        pub bonus_rate: Decimal,
+        pub apr_query_adapter: Addr,
+        pub apollo_factory: ApolloFactory,
+        pub min_position_size: Uint128,
+    }
+
+    pub const CONFIG: Item<Config> = Item::new("config");
+
+    #[derive(Serialize, Deserialize)]
+    pub struct State {
+        pub total_bond_units: Uint128,
+        pub total_debt_units: Uint128,
+        pub pending_rewards: AssetList,
+    }
+
+    pub const STATE: Item<State> = Item::new("state");
+}
+
+fn migrate_v1_0_0_to_v1_1_0(
+    deps: DepsMut,
+    env: &Env,
+    msg: &MigrateMsg,
+) -> Result<(), ContractError> {
+    let config_update = msg.config_update.as_ref();
+    let param_missing = |param: &str| ContractError::MigrationParamMissing {
+        param: param.to_string(),
+        version: "1.0.0".to_string(),
+    };
+
+    // v1.0.0 didn't check prices for staleness. disabling the check by default would leave migrated
+    // contracts exposed to stale prices, so the max price age must be set explicitly
+    let max_price_age = config_update
+        .and_then(|config_update| config_update.max_price_age)
+        .ok_or_else(|| param_missing("max_price_age"))?;
+
+    // v1.0.0 swapped ASTRO rewards without a belief price, so it never needed a price for ASTRO. the
+    // oracle may not have one, so where it is read from must be set explicitly
+    let astro_price_source = config_update
+        .and_then(|config_update| config_update.astro_price_source.as_ref())
+        .ok_or_else(|| param_missing("astro_price_source"))?
+        .check(deps.api)?;
+
+    // operators are moved from config to their own map. they are recorded as having been added at
+    // the migration height, and their roles don't expire
+    let operators = v1_0_0::CONFIG.load(deps.storage)?.operators;
+    for operator in &operators {
+        let operator_info = OperatorInfo {
+            added_at_height: env.block.height,
+            expires_at: None,
+        };
+        OPERATORS.save(deps.storage, operator, &operator_info)?;
+    }
+
+    // roles introduced in v1.1.0 are all assigned to governance. harvesting remains permissioned.
+    // the full liquidation LTV defaults to the max LTV, i.e. positions are still liquidated in full,
+    // and the liquidation max spread defaults to 5%, the value previously hardcoded. pool prices may
+    // deviate from the oracle by up to 5% as well. prices of the primary and secondary assets are
+    // still read from the Mars oracle
+    migrate_item(deps.storage, v1_0_0::CONFIG, CONFIG, |config| {
+        Ok(Config {
+            primary_asset_info: config.primary_asset_info,
+            secondary_asset_info: config.secondary_asset_info,
+            astro_token_info: config.astro_token_info,
+            primary_pair: config.primary_pair,
+            astro_pair: config.astro_pair,
+            astro_generator: config.astro_generator,
+            red_bank: config.red_bank,
+            primary_price_source: PriceSource::MarsOracle {
+                oracle: config.oracle.clone(),
+            },
+            secondary_price_source: PriceSource::MarsOracle {
+                oracle: config.oracle,
+            },
+            astro_price_source: astro_price_source.clone(),
+            treasury: config.treasury,
+            guardian: config.governance.clone(),
+            fee_manager: config.governance.clone(),
+            operator_manager: config.governance.clone(),
+            governance: config.governance,
+            permissionless_harvest: false,
+            max_ltv: config.max_ltv,
+            max_initial_ltv: config.max_initial_ltv,
+            performance_fee: config.performance_fee,
+            harvest_bounty: Decimal::zero(),
+            min_harvest_interval: 0,
+            bonus_rate: config.bonus_rate,
+            close_factor: Decimal::from_ratio(1u128, 2u128),
+            full_liquidation_ltv: config.max_ltv,
+            liquidation_max_spread: Decimal::from_ratio(1u128, 20u128),
+            price_deviation_tolerance: Decimal::from_ratio(1u128, 20u128),
+            twap_window: 1800,
+            max_price_age,
+            apr_query_adapter: config.apr_query_adapter,
+            apollo_factory: config.apollo_factory,
+            min_position_size: config.min_position_size,
+        })
+    })?;
+
+    migrate_item(deps.storage, v1_0_0::STATE, STATE, |state| {
+        Ok(State {
+            total_bond_units: state.total_bond_units,
+            total_debt_units: state.total_debt_units,
+            pending_rewards: state.pending_rewards,
+            last_harvest: 0,
+        })
+    })?;
+
+    // all actions are initially unpaused
+    PAUSE_FLAGS.save(deps.storage, &PauseFlags::default())?;
+
+    // positions opened in v1.0.0 have no accounting. rather than seeding all of them here, which
+    // could run out of gas, each is seeded the first time it is touched after the migration
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use cosmwasm_std::{Addr, Decimal, Deps, Env, Fraction, QuerierWrapper, Storage};
+
+use cw_asset::AssetInfo;
+
+use fields_of_mars::adapters::{AstroportQuote, Pair, PriceSource};
+use fields_of_mars::error::FieldsOfMarsError;
+use fields_of_mars::martian_field::{Config, PriceSnapshot, TwapResponse};
+
+use crate::error::ContractError;
+use crate::helpers::assert_price_deviation;
+use crate::math::compute_twap;
+use crate::state::{CONFIG, PRICE_SNAPSHOTS};
+
+/// Pairs whose cumulative prices are tracked, along with the asset priced in each: the primary pair,
+/// and the pairs used by Astroport price sources
+fn tracked_pairs(config: &Config) -> Vec<(&Pair, &AssetInfo)> {
+    let mut pairs = vec![(&config.primary_pair, &config.primary_asset_info)];
+    for (source, asset_info) in [
+        (&config.primary_price_source, &config.primary_asset_info),
+        (&config.astro_price_source, &config.astro_token_info),
+    ] {
+        if let Some(pair) = source.pair() {
+            if !pairs.iter().any(|(tracked, _)| tracked.contract_addr == pair.contract_addr) {
+                pairs.push((pair, asset_info));
+            }
+        }
+    }
+    pairs
+}
+
+/// Take a snapshot of each tracked pair's cumulative price, if at least `twap_window` seconds have
+/// elapsed since its last one. Only the two most recent snapshots of each pair are kept
+///
+/// Returns whether any snapshot was taken
+pub fn record_price_snapshot(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+) -> Result<bool, ContractError> {
+    let now = env.block.time.seconds();
+    let mut recorded = false;
+
+    for (pair, asset_info) in tracked_pairs(config) {
+        let mut snapshots =
+            PRICE_SNAPSHOTS.may_load(storage, &pair.contract_addr)?.unwrap_or_default();
+
+        if let Some(latest) = snapshots.last() {
+            if now < latest.timestamp + config.twap_window {
+                continue;
+            }
+        }
+
+        let price_cumulative = pair.query_cumulative_price(querier, asset_info)?;
+        snapshots.push(PriceSnapshot {
+            timestamp: now,
+            price_cumulative,
+        });
+        if snapshots.len() > 2 {
+            snapshots.remove(0);
+        }
+
+        PRICE_SNAPSHOTS.save(storage, &pair.contract_addr, &snapshots)?;
+        recorded = true;
+    }
+
+    Ok(recorded)
+}
+
+/// Compute the TWAP of a pair's non-secondary asset over the period between the two most recent
+/// snapshots
+pub fn query_pair_twap(
+    storage: &dyn Storage,
+    pair_addr: &Addr,
+) -> Result<TwapResponse, ContractError> {
+    let snapshots = PRICE_SNAPSHOTS.may_load(storage, pair_addr)?.unwrap_or_default();
+    match snapshots.as_slice() {
+        [start, end] => Ok(TwapResponse {
+            price: compute_twap(start, end)?,
+            last_updated: end.timestamp,
+        }),
+        _ => Err(FieldsOfMarsError::TwapNotAvailable {}.into()),
+    }
+}
+
+/// Compute the TWAP of the primary asset in the primary pair
+pub fn query_twap(deps: Deps) -> Result<TwapResponse, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    query_pair_twap(deps.storage, &config.primary_pair.contract_addr)
+}
+
+/// Query the price of an asset from the price source configured for it
+pub fn query_price(
+    deps: Deps,
+    config: &Config,
+    asset_info: &AssetInfo,
+) -> Result<Decimal, ContractError> {
+    let source = price_source(config, asset_info)?;
+
+    // Astroport sources quote assets in terms of the secondary asset, whose price source is never an
+    // Astroport one, as asserted by `Config::validate`
+    let quote = match source.pair() {
+        Some(pair) => Some(AstroportQuote {
+            asset_info: config.secondary_asset_info.clone(),
+            price: config.secondary_price_source.query_price(
+                &deps.querier,
+                &config.secondary_asset_info,
+                None,
+            )?,
+            twap: query_pair_twap(deps.storage, &pair.contract_addr).ok().map(|twap| twap.price),
+        }),
+        None => None,
+    };
+
+    Ok(source.query_price(&deps.querier, asset_info, quote.as_ref())?)
+}
+
+fn price_source<'a>(
+    config: &'a Config,
+    asset_info: &AssetInfo,
+) -> Result<&'a PriceSource, ContractError> {
+    if asset_info == &config.primary_asset_info {
+        Ok(&config.primary_price_source)
+    } else if asset_info == &config.secondary_asset_info {
+        Ok(&config.secondary_price_source)
+    } else if asset_info == &config.astro_token_info {
+        Ok(&config.astro_price_source)
+    } else {
+        Err(ContractError::NoPriceSource {
+            asset: asset_info.to_string(),
+        })
+    }
+}
+
+/// Assert that neither the TWAP of the primary asset nor the prices reported by the price sources are
+/// stale, and that the price implied by the price sources does not deviate from the TWAP by more than
+/// `price_deviation_tolerance`
+///
+/// The check is skipped if `max_price_age` is zero
+pub fn assert_price_valid(deps: Deps, env: &Env, config: &Config) -> Result<(), ContractError> {
+    if config.max_price_age == 0 {
+        return Ok(());
+    }
+
+    // until two snapshots have been taken, there is no TWAP to check the prices against
+    let twap = query_pair_twap(deps.storage, &config.primary_pair.contract_addr).map_err(|_| {
+        ContractError::StalePrice {
+            last_updated: 0,
+        }
+    })?;
+    let now = env.block.time.seconds();
+    if now > twap.last_updated + config.max_price_age {
+        return Err(ContractError::StalePrice {
+            last_updated: twap.last_updated,
+        });
+    }
+
+    // price of the primary asset in terms of the secondary asset, as implied by the price sources
+    let primary_price = query_price(deps, config, &config.primary_asset_info)?;
+    let secondary_price = query_price(deps, config, &config.secondary_asset_info)?;
+    if secondary_price.is_zero() || twap.price.is_zero() {
+        return Err(FieldsOfMarsError::ZeroPrice {}.into());
+    }
+    let implied_price = Decimal::from_ratio(primary_price.numerator(), secondary_price.numerator());
+
+    assert_price_deviation(implied_price, twap.price, config.price_deviation_tolerance)
+}
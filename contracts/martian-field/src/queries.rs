@@ -1,13 +1,25 @@
-use cosmwasm_std::{Deps, Env, StdResult};
+use cosmwasm_std::{Decimal, Deps, Env, Fraction, Order, StdResult};
+use cw_storage_plus::Bound;
 
 use fields_of_mars::martian_field::msg::QueryMsg;
 use fields_of_mars::martian_field::{
-    AprResponse, ConfigUnchecked, Health, PositionUnchecked, Snapshot, State, StrategyInfoResponse,
-    TvlResponse, UserInfoResponse,
+    AprResponse, ConfigUnchecked, Health, LiquidationPriceResponse, OperatorResponse, PauseFlags,
+    PendingGovernanceUnchecked, PositionResponse, PositionUnchecked, PositionWithHealthResponse,
+    PositionsWithHealthResponse, Snapshot, State, StrategyInfoResponse, TvlResponse,
+    UserInfoResponse,
 };
 
-use crate::health::{compute_health, compute_value_per_lp_token};
-use crate::state::{CONFIG, POSITION, SNAPSHOT, STATE};
+use crate::error::ContractError;
+use crate::health::{
+    compute_health, compute_position_health, compute_total_values, compute_value_per_lp_token,
+};
+use crate::helpers::parse_user_addr;
+use crate::math::compute_liquidation_price;
+use crate::prices::query_price;
+use crate::state::{CONFIG, OPERATORS, PAUSE_FLAGS, PENDING_GOVERNANCE, POSITION, SNAPSHOT, STATE};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 pub fn query_config(deps: Deps, _env: Env) -> StdResult<ConfigUnchecked> {
     let config = CONFIG.load(deps.storage)?;
@@ -18,18 +30,150 @@ pub fn query_state(deps: Deps, _env: Env) -> StdResult<State> {
     STATE.load(deps.storage)
 }
 
+pub fn query_pending_governance(deps: Deps) -> StdResult<Option<PendingGovernanceUnchecked>> {
+    let pending = PENDING_GOVERNANCE.may_load(deps.storage)?;
+    Ok(pending.map(Into::into))
+}
+
+pub fn query_operators(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<Vec<OperatorResponse>, ContractError> {
+    let start = start_after.map(|operator| Bound::exclusive(operator.as_bytes()));
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    OPERATORS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (k, info) = item?;
+            let operator = String::from_utf8(k).map_err(|_| ContractError::InvalidAddressKey {})?;
+            Ok(OperatorResponse {
+                operator,
+                info,
+            })
+        })
+        .collect()
+}
+
+pub fn query_pause_flags(deps: Deps) -> StdResult<PauseFlags> {
+    PAUSE_FLAGS.load(deps.storage)
+}
+
 pub fn query_position(deps: Deps, _env: Env, user: String) -> StdResult<PositionUnchecked> {
     let user_addr = deps.api.addr_validate(&user)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
     Ok(position.into())
 }
 
-pub fn query_health(deps: Deps, env: Env, user: String) -> StdResult<Health> {
+pub fn query_health(deps: Deps, env: Env, user: String) -> Result<Health, ContractError> {
     let user_addr = deps.api.addr_validate(&user)?;
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
-    compute_health(&deps.querier, &env, &config, &state, &position)
+    compute_health(deps, &env, &config, &state, &position)
+}
+
+pub fn query_liquidation_price(
+    deps: Deps,
+    env: Env,
+    user: String,
+) -> Result<LiquidationPriceResponse, ContractError> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
+
+    let health = compute_health(deps, &env, &config, &state, &position)?;
+    let current_price = query_price(deps, &config, &config.primary_asset_info)?;
+    let liquidation_price = compute_liquidation_price(
+        current_price,
+        health.bond_value,
+        health.debt_value,
+        config.max_ltv,
+    );
+
+    // the buffer is the relative drop from the current price to the liquidation price
+    let buffer = liquidation_price.map(|liquidation_price| {
+        if liquidation_price >= current_price {
+            Decimal::zero()
+        } else {
+            Decimal::one()
+                - Decimal::from_ratio(liquidation_price.numerator(), current_price.numerator())
+        }
+    });
+
+    Ok(LiquidationPriceResponse {
+        current_price,
+        liquidation_price,
+        buffer,
+    })
+}
+
+pub fn query_positions(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<Vec<PositionResponse>, ContractError> {
+    let start = start_after.map(|user| Bound::exclusive(user.as_bytes()));
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    POSITION
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (k, position) = item?;
+            Ok(PositionResponse {
+                user: parse_user_addr(k)?.into(),
+                position: position.into(),
+            })
+        })
+        .collect()
+}
+
+pub fn query_positions_with_health(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+    min_ltv: Option<Decimal>,
+) -> Result<PositionsWithHealthResponse, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let state = STATE.load(deps.storage)?;
+    let totals = compute_total_values(deps, &env, &config, &state)?;
+
+    let start = start_after.map(|user| Bound::exclusive(user.as_bytes()));
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+
+    // positions filtered out count towards the limit, so that the amount of work done is bounded.
+    // the client continues from the last position scanned, instead of the last one returned
+    let mut positions = vec![];
+    let mut last_scanned = None;
+    for item in POSITION.range(deps.storage, start, None, Order::Ascending).take(limit) {
+        let (k, position) = item?;
+        let user = parse_user_addr(k)?.to_string();
+        let health = compute_position_health(&totals, &position);
+        last_scanned = Some(user.clone());
+
+        // a position without bonded assets has no LTV, and is never returned when filtering by LTV
+        if let Some(min_ltv) = min_ltv {
+            if !matches!(health.ltv, Some(ltv) if ltv >= min_ltv) {
+                continue;
+            }
+        }
+
+        positions.push(PositionWithHealthResponse {
+            user,
+            position: position.into(),
+            health,
+        });
+    }
+
+    Ok(PositionsWithHealthResponse {
+        positions,
+        last_scanned,
+    })
 }
 
 pub fn query_snapshot(deps: Deps, user: String) -> StdResult<Snapshot> {
@@ -37,7 +181,11 @@ pub fn query_snapshot(deps: Deps, user: String) -> StdResult<Snapshot> {
     Ok(SNAPSHOT.load(deps.storage, &user_addr).unwrap_or_default())
 }
 
-pub fn query_user_info(deps: Deps, env: Env, user: String) -> StdResult<UserInfoResponse> {
+pub fn query_user_info(
+    deps: Deps,
+    env: Env,
+    user: String,
+) -> Result<UserInfoResponse, ContractError> {
     let user_addr = deps.api.addr_validate(&user)?;
     let position = POSITION.load(deps.storage, &user_addr).unwrap_or_default();
     let config = CONFIG.load(deps.storage)?;
@@ -58,7 +206,7 @@ pub fn query_user_info(deps: Deps, env: Env, user: String) -> StdResult<UserInfo
     })
 }
 
-pub fn query_strategy_info(deps: Deps, env: Env) -> StdResult<StrategyInfoResponse> {
+pub fn query_strategy_info(deps: Deps, env: Env) -> Result<StrategyInfoResponse, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     let state = STATE.load(deps.storage)?;
 
@@ -74,7 +222,7 @@ pub fn query_strategy_info(deps: Deps, env: Env) -> StdResult<StrategyInfoRespon
     })
 }
 
-pub fn query_tvl(deps: Deps, env: Env) -> StdResult<TvlResponse> {
+pub fn query_tvl(deps: Deps, env: Env) -> Result<TvlResponse, ContractError> {
     let config = CONFIG.load(deps.storage)?;
 
     let total_bonded_amount = config.astro_generator.query_bonded_amount(
@@ -83,7 +231,7 @@ pub fn query_tvl(deps: Deps, env: Env) -> StdResult<TvlResponse> {
         &config.primary_pair.liquidity_token,
     )?;
 
-    let lp_value = compute_value_per_lp_token(&deps.querier, &config, None, None)?;
+    let lp_value = compute_value_per_lp_token(deps, &config, None, None)?;
     let total_bonded_value = total_bonded_amount * lp_value;
 
     Ok(TvlResponse {
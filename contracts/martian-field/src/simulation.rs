@@ -0,0 +1,538 @@
+use std::cmp;
+use std::str::FromStr;
+
+use cosmwasm_std::{Addr, Api, Coin, Decimal, Deps, Env, Uint128};
+
+use cw_asset::{Asset, AssetInfo, AssetList};
+
+use fields_of_mars::adapters::ASTROPORT_COMMISSION_RATE;
+use fields_of_mars::martian_field::msg::Action;
+use fields_of_mars::martian_field::{
+    Config, Health, PauseFlags, Position, SimulateUpdatePositionResponse,
+};
+
+use crate::error::ContractError;
+use crate::execute_callbacks::{
+    DEFAULT_BOND_UNITS_PER_SHARE_BONDED, DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED,
+};
+use crate::health::{
+    assert_position_healthy, assert_target_ltv, compute_leverage_change, compute_lp_value,
+    compute_position_health, LeverageChange, TotalValues,
+};
+use crate::helpers::{assert_action_not_paused, assert_sent_fund};
+use crate::math::{compute_optimal_swap_amount, compute_swap_offer, compute_swap_return, U256};
+use crate::prices::query_price;
+use crate::state::{CONFIG, PAUSE_FLAGS, POSITION, STATE};
+
+/// Simulate an `UpdatePosition` call by `user` sending `funds`, and return the resulting position
+/// and its health, along with the error the call would fail with, if any
+///
+/// The callbacks are replayed in memory against the current pool depths, generator and Red Bank
+/// state, which are updated as the actions are simulated. Swaps are computed with the same formulas
+/// as Astroport's `Simulation` and `ReverseSimulation` queries, but against the simulated depths.
+/// Slippage and spread checks, as well as rewards claimed when bonding or unbonding, are not
+/// simulated
+pub fn query_simulate_update_position(
+    deps: Deps,
+    env: Env,
+    user: String,
+    actions: Vec<Action>,
+    funds: Vec<Coin>,
+) -> Result<SimulateUpdatePositionResponse, ContractError> {
+    let user_addr = deps.api.addr_validate(&user)?;
+    let mut simulation = Simulation::new(deps, &env, &user_addr)?;
+
+    // if the call would fail, the position is returned as it is at the point of failure
+    let error = simulation.run(actions, funds).err().map(|err| err.to_string());
+
+    Ok(SimulateUpdatePositionResponse {
+        health: simulation.health(),
+        position: simulation.position.into(),
+        error,
+    })
+}
+
+/// In-memory copy of the states an `UpdatePosition` call reads and writes
+struct Simulation<'a> {
+    api: &'a dyn Api,
+    config: Config,
+    pause_flags: PauseFlags,
+    position: Position,
+    total_bond_units: Uint128,
+    total_debt_units: Uint128,
+    /// Amount of liquidity tokens bonded by the contract in Astro generator
+    total_bonded_amount: Uint128,
+    /// Amount of secondary asset borrowed by the contract from Red Bank
+    total_debt_amount: Uint128,
+    primary_depth: Uint128,
+    secondary_depth: Uint128,
+    total_shares: Uint128,
+    primary_price: Decimal,
+    secondary_price: Decimal,
+    commission_rate: Decimal,
+}
+
+impl<'a> Simulation<'a> {
+    fn new(deps: Deps<'a>, env: &Env, user_addr: &Addr) -> Result<Self, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let state = STATE.load(deps.storage)?;
+
+        let total_bonded_amount = config.astro_generator.query_bonded_amount(
+            &deps.querier,
+            &env.contract.address,
+            &config.primary_pair.liquidity_token,
+        )?;
+        let total_debt_amount = config.red_bank.query_user_debt(
+            &deps.querier,
+            &env.contract.address,
+            &config.secondary_asset_info,
+        )?;
+        let (primary_depth, secondary_depth, total_shares) = config.primary_pair.query_pool(
+            &deps.querier,
+            &config.primary_asset_info,
+            &config.secondary_asset_info,
+        )?;
+
+        Ok(Simulation {
+            api: deps.api,
+            pause_flags: PAUSE_FLAGS.load(deps.storage)?,
+            position: POSITION.load(deps.storage, user_addr).unwrap_or_default(),
+            total_bond_units: state.total_bond_units,
+            total_debt_units: state.total_debt_units,
+            total_bonded_amount,
+            total_debt_amount,
+            primary_depth,
+            secondary_depth,
+            total_shares,
+            primary_price: query_price(deps, &config, &config.primary_asset_info)?,
+            secondary_price: query_price(deps, &config, &config.secondary_asset_info)?,
+            commission_rate: Decimal::from_str(ASTROPORT_COMMISSION_RATE)?,
+            config,
+        })
+    }
+
+    /// Simulate the actions in the same order as `execute::update_position` and the callbacks it
+    /// dispatches: deposits are handled first, then the callbacks of each action in order
+    fn run(&mut self, actions: Vec<Action>, funds: Vec<Coin>) -> Result<(), ContractError> {
+        let mut received_coins = AssetList::from(funds);
+
+        for action in &actions {
+            assert_action_not_paused(&self.pause_flags, action)?;
+            match action {
+                Action::Deposit(asset) => {
+                    self.deposit(&mut received_coins, &asset.check(self.api)?)?;
+                }
+                Action::Zap {
+                    asset,
+                    ..
+                } => {
+                    let asset = asset.check(self.api)?;
+                    if asset.info != self.config.primary_asset_info
+                        && asset.info != self.config.secondary_asset_info
+                    {
+                        return Err(ContractError::InvalidOfferAsset {
+                            asset: asset.info.to_string(),
+                        });
+                    }
+                    self.deposit(&mut received_coins, &asset)?;
+                }
+                _ => (),
+            }
+        }
+
+        if !received_coins.is_empty() {
+            return Err(ContractError::ExtraFundsReceived {
+                funds: received_coins.to_string(),
+            });
+        }
+
+        for action in actions {
+            match action {
+                Action::Deposit(_) => (),
+                Action::Borrow {
+                    amount,
+                } => self.borrow(amount)?,
+                Action::Repay {
+                    amount,
+                } => self.repay(Some(amount))?,
+                Action::Bond {
+                    ..
+                } => {
+                    self.provide_liquidity()?;
+                    self.bond()?;
+                }
+                Action::Unbond {
+                    bond_units_to_reduce,
+                } => {
+                    self.unbond(bond_units_to_reduce)?;
+                    self.withdraw_liquidity()?;
+                }
+                Action::Swap {
+                    offer_amount,
+                    ..
+                } => {
+                    self.swap(&Asset::new(self.config.primary_asset_info.clone(), offer_amount))?
+                }
+                Action::Zap {
+                    ..
+                } => {
+                    self.balance()?;
+                    self.provide_liquidity()?;
+                    self.bond()?;
+                }
+                Action::SetLeverage {
+                    target_ltv,
+                    ..
+                } => self.set_leverage(target_ltv)?,
+                Action::ClosePosition {
+                    ..
+                } => self.close_position()?,
+            }
+        }
+
+        // unlocked assets are refunded to the user, and the position must be healthy afterwards
+        self.position.unlocked_assets = AssetList::default();
+        self.assert_health()
+    }
+
+    fn health(&self) -> Health {
+        let lp_value = compute_lp_value(
+            self.primary_depth,
+            self.secondary_depth,
+            self.total_shares,
+            self.primary_price,
+            self.secondary_price,
+        );
+        let totals = TotalValues {
+            bonded_value: self.total_bonded_amount * lp_value,
+            debt_value: self.total_debt_amount * self.secondary_price,
+            bond_units: self.total_bond_units,
+            debt_units: self.total_debt_units,
+            secondary_price: self.secondary_price,
+        };
+        compute_position_health(&totals, &self.position)
+    }
+
+    fn unlocked_amount(&self, asset_info: &AssetInfo) -> Uint128 {
+        self.position
+            .unlocked_assets
+            .find(asset_info)
+            .map(|asset| asset.amount)
+            .unwrap_or_else(Uint128::zero)
+    }
+
+    fn deposit(
+        &mut self,
+        received_coins: &mut AssetList,
+        asset: &Asset,
+    ) -> Result<(), ContractError> {
+        if asset.amount.is_zero() {
+            return Ok(());
+        }
+
+        // CW20 deposits are assumed to have been approved
+        if let AssetInfo::Native(_) = &asset.info {
+            assert_sent_fund(asset, received_coins)?;
+            received_coins.deduct(asset)?;
+        }
+
+        self.position.unlocked_assets.add(asset)?;
+        Ok(())
+    }
+
+    fn borrow(&mut self, borrow_amount: Uint128) -> Result<(), ContractError> {
+        let debt_units_to_add = if self.total_debt_amount.is_zero() {
+            borrow_amount.checked_mul(DEFAULT_DEBT_UNITS_PER_ASSET_BORROWED)?
+        } else {
+            self.total_debt_units.multiply_ratio(borrow_amount, self.total_debt_amount)
+        };
+
+        self.total_debt_units = self.total_debt_units.checked_add(debt_units_to_add)?;
+        self.total_debt_amount = self.total_debt_amount.checked_add(borrow_amount)?;
+        self.position.debt_units = self.position.debt_units.checked_add(debt_units_to_add)?;
+        self.position
+            .unlocked_assets
+            .add(&Asset::new(self.config.secondary_asset_info.clone(), borrow_amount))?;
+        Ok(())
+    }
+
+    fn repay(&mut self, repay_amount: Option<Uint128>) -> Result<(), ContractError> {
+        let debt_amount = self.debt_amount();
+        let repay_amount =
+            repay_amount.unwrap_or_else(|| self.unlocked_amount(&self.config.secondary_asset_info));
+        let repay_amount = cmp::min(repay_amount, debt_amount);
+
+        let debt_units_to_deduct = if debt_amount.is_zero() {
+            Uint128::zero()
+        } else {
+            self.position.debt_units.multiply_ratio(repay_amount, debt_amount)
+        };
+
+        self.total_debt_units = self.total_debt_units.checked_sub(debt_units_to_deduct)?;
+        self.total_debt_amount = self.total_debt_amount.checked_sub(repay_amount)?;
+        self.position.debt_units = self.position.debt_units.checked_sub(debt_units_to_deduct)?;
+        self.position
+            .unlocked_assets
+            .deduct(&Asset::new(self.config.secondary_asset_info.clone(), repay_amount))?;
+        Ok(())
+    }
+
+    fn debt_amount(&self) -> Uint128 {
+        if self.total_debt_units.is_zero() {
+            Uint128::zero()
+        } else {
+            self.total_debt_amount.multiply_ratio(self.position.debt_units, self.total_debt_units)
+        }
+    }
+
+    fn provide_liquidity(&mut self) -> Result<(), ContractError> {
+        let primary_asset = self
+            .position
+            .unlocked_assets
+            .find(&self.config.primary_asset_info)
+            .cloned()
+            .ok_or_else(|| ContractError::AssetNotAvailable {
+                asset: "primary asset".to_string(),
+            })?;
+        let secondary_asset = self
+            .position
+            .unlocked_assets
+            .find(&self.config.secondary_asset_info)
+            .cloned()
+            .ok_or_else(|| ContractError::AssetNotAvailable {
+                asset: "secondary asset".to_string(),
+            })?;
+
+        // same as the amount of liquidity tokens minted by Astroport's constant product pair
+        let minted_amount = if self.total_shares.is_zero() {
+            let product =
+                U256::from(primary_asset.amount.u128()) * U256::from(secondary_asset.amount.u128());
+            Uint128::new(product.integer_sqrt().as_u128())
+        } else {
+            cmp::min(
+                primary_asset.amount.multiply_ratio(self.total_shares, self.primary_depth),
+                secondary_asset.amount.multiply_ratio(self.total_shares, self.secondary_depth),
+            )
+        };
+
+        self.position.unlocked_assets.deduct(&primary_asset)?;
+        self.position.unlocked_assets.deduct(&secondary_asset)?;
+        self.primary_depth = self.primary_depth.checked_add(primary_asset.amount)?;
+        self.secondary_depth = self.secondary_depth.checked_add(secondary_asset.amount)?;
+        self.total_shares = self.total_shares.checked_add(minted_amount)?;
+        self.position
+            .unlocked_assets
+            .add(&Asset::cw20(self.config.primary_pair.liquidity_token.clone(), minted_amount))?;
+        Ok(())
+    }
+
+    fn withdraw_liquidity(&mut self) -> Result<(), ContractError> {
+        let liquidity_token_info =
+            AssetInfo::cw20(self.config.primary_pair.liquidity_token.clone());
+        let liquidity_token_to_burn =
+            self.position.unlocked_assets.find(&liquidity_token_info).cloned().ok_or_else(
+                || ContractError::AssetNotAvailable {
+                    asset: "unlocked share token".to_string(),
+                },
+            )?;
+
+        let primary_amount =
+            self.primary_depth.multiply_ratio(liquidity_token_to_burn.amount, self.total_shares);
+        let secondary_amount =
+            self.secondary_depth.multiply_ratio(liquidity_token_to_burn.amount, self.total_shares);
+
+        self.position.unlocked_assets.deduct(&liquidity_token_to_burn)?;
+        self.primary_depth = self.primary_depth.checked_sub(primary_amount)?;
+        self.secondary_depth = self.secondary_depth.checked_sub(secondary_amount)?;
+        self.total_shares = self.total_shares.checked_sub(liquidity_token_to_burn.amount)?;
+        self.position.unlocked_assets.add_many(&AssetList::from(vec![
+            Asset::new(self.config.primary_asset_info.clone(), primary_amount),
+            Asset::new(self.config.secondary_asset_info.clone(), secondary_amount),
+        ]))?;
+        Ok(())
+    }
+
+    fn bond(&mut self) -> Result<(), ContractError> {
+        let liquidity_token_info =
+            AssetInfo::cw20(self.config.primary_pair.liquidity_token.clone());
+        let liquidity_tokens_to_bond =
+            self.position.unlocked_assets.find(&liquidity_token_info).cloned().ok_or_else(
+                || ContractError::AssetNotAvailable {
+                    asset: "liquidity token".to_string(),
+                },
+            )?;
+
+        let bond_units_to_add = if self.total_bonded_amount.is_zero() {
+            liquidity_tokens_to_bond.amount.checked_mul(DEFAULT_BOND_UNITS_PER_SHARE_BONDED)?
+        } else {
+            self.total_bond_units
+                .multiply_ratio(liquidity_tokens_to_bond.amount, self.total_bonded_amount)
+        };
+
+        self.position.unlocked_assets.deduct(&liquidity_tokens_to_bond)?;
+        self.total_bonded_amount =
+            self.total_bonded_amount.checked_add(liquidity_tokens_to_bond.amount)?;
+        self.total_bond_units = self.total_bond_units.checked_add(bond_units_to_add)?;
+        self.position.bond_units = self.position.bond_units.checked_add(bond_units_to_add)?;
+        Ok(())
+    }
+
+    fn unbond(&mut self, bond_units_to_deduct: Uint128) -> Result<(), ContractError> {
+        let amount_to_unbond =
+            self.total_bonded_amount.multiply_ratio(bond_units_to_deduct, self.total_bond_units);
+
+        self.total_bond_units = self.total_bond_units.checked_sub(bond_units_to_deduct)?;
+        self.total_bonded_amount = self.total_bonded_amount.checked_sub(amount_to_unbond)?;
+        self.position.bond_units = self.position.bond_units.checked_sub(bond_units_to_deduct)?;
+        self.position.unlocked_assets.add(&Asset::cw20(
+            self.config.primary_pair.liquidity_token.clone(),
+            amount_to_unbond,
+        ))?;
+        Ok(())
+    }
+
+    /// Swap the primary asset for the secondary asset in the primary pair, or vice versa
+    fn swap(&mut self, offer_asset: &Asset) -> Result<(), ContractError> {
+        self.position.unlocked_assets.deduct(offer_asset)?;
+
+        let (ask_asset_info, return_amount) = if offer_asset.info == self.config.primary_asset_info
+        {
+            let return_amount = compute_swap_return(
+                offer_asset.amount,
+                self.primary_depth,
+                self.secondary_depth,
+                self.commission_rate,
+            )?;
+            self.primary_depth = self.primary_depth.checked_add(offer_asset.amount)?;
+            self.secondary_depth = self.secondary_depth.checked_sub(return_amount)?;
+            (self.config.secondary_asset_info.clone(), return_amount)
+        } else {
+            let return_amount = compute_swap_return(
+                offer_asset.amount,
+                self.secondary_depth,
+                self.primary_depth,
+                self.commission_rate,
+            )?;
+            self.secondary_depth = self.secondary_depth.checked_add(offer_asset.amount)?;
+            self.primary_depth = self.primary_depth.checked_sub(return_amount)?;
+            (self.config.primary_asset_info.clone(), return_amount)
+        };
+
+        self.position.unlocked_assets.add(&Asset::new(ask_asset_info, return_amount))?;
+        Ok(())
+    }
+
+    fn balance(&mut self) -> Result<(), ContractError> {
+        let primary_amount = self.unlocked_amount(&self.config.primary_asset_info);
+        let secondary_amount = self.unlocked_amount(&self.config.secondary_asset_info);
+
+        let primary_offer_amount = compute_optimal_swap_amount(
+            primary_amount,
+            secondary_amount,
+            self.primary_depth,
+            self.secondary_depth,
+            self.commission_rate,
+        )?;
+        let offer_asset = if !primary_offer_amount.is_zero() {
+            Asset::new(self.config.primary_asset_info.clone(), primary_offer_amount)
+        } else {
+            let secondary_offer_amount = compute_optimal_swap_amount(
+                secondary_amount,
+                primary_amount,
+                self.secondary_depth,
+                self.primary_depth,
+                self.commission_rate,
+            )?;
+            Asset::new(self.config.secondary_asset_info.clone(), secondary_offer_amount)
+        };
+
+        if !offer_asset.amount.is_zero() {
+            self.swap(&offer_asset)?;
+        }
+        Ok(())
+    }
+
+    fn cover(&mut self, repay_amount: Option<Uint128>) -> Result<(), ContractError> {
+        let debt_amount = self.debt_amount();
+        let secondary_available = self.unlocked_amount(&self.config.secondary_asset_info);
+        let cover_amount = repay_amount.map_or(debt_amount, |amount| cmp::min(amount, debt_amount));
+        if cover_amount <= secondary_available {
+            return Ok(());
+        }
+
+        // as in the `Cover` callback, one more unit is offered than reverse-simulated, to account
+        // for rounding
+        let primary_sell_amount = compute_swap_offer(
+            cover_amount - secondary_available,
+            self.primary_depth,
+            self.secondary_depth,
+            self.commission_rate,
+        )?
+        .checked_add(Uint128::new(1))?;
+        let primary_sell_amount =
+            cmp::min(primary_sell_amount, self.unlocked_amount(&self.config.primary_asset_info));
+
+        self.swap(&Asset::new(self.config.primary_asset_info.clone(), primary_sell_amount))
+    }
+
+    /// Same as the `SetLeverage` callback
+    fn set_leverage(&mut self, target_ltv: Decimal) -> Result<(), ContractError> {
+        assert_target_ltv(&self.config, target_ltv)?;
+
+        let (change, rebond) = compute_leverage_change(
+            &self.config,
+            &self.pause_flags,
+            &self.position,
+            &self.health(),
+            self.primary_price,
+            self.secondary_price,
+            target_ltv,
+        )?;
+
+        match change {
+            LeverageChange::Borrow {
+                borrow_amount,
+            } => {
+                if !borrow_amount.is_zero() {
+                    self.borrow(borrow_amount)?;
+                }
+            }
+            LeverageChange::Repay {
+                repay_amount,
+                bond_units_to_reduce,
+            } => {
+                if let Some(bond_units_to_reduce) = bond_units_to_reduce {
+                    self.unbond(bond_units_to_reduce)?;
+                    self.withdraw_liquidity()?;
+                }
+                self.cover(Some(repay_amount))?;
+                self.repay(Some(repay_amount))?;
+            }
+        }
+
+        if rebond {
+            self.balance()?;
+            self.provide_liquidity()?;
+            self.bond()?;
+        }
+        Ok(())
+    }
+
+    /// Same as the `ClosePosition` callback
+    fn close_position(&mut self) -> Result<(), ContractError> {
+        if !self.position.bond_units.is_zero() {
+            self.unbond(self.position.bond_units)?;
+            self.withdraw_liquidity()?;
+        }
+        if !self.position.debt_units.is_zero() {
+            self.cover(None)?;
+            self.repay(None)?;
+        }
+        Ok(())
+    }
+
+    /// Same as the `AssertHealth` callback
+    fn assert_health(&self) -> Result<(), ContractError> {
+        assert_position_healthy(&self.config, &self.position, &self.health())
+    }
+}
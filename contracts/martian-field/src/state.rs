@@ -1,15 +1,29 @@
-use cosmwasm_std::{Addr};
+use cosmwasm_std::Addr;
 use cw_storage_plus::{Item, Map};
 
-use fields_of_mars::martian_field::{Config, Position, Snapshot, State};
+use fields_of_mars::martian_field::{
+    Config, OperatorInfo, PauseFlags, PendingGovernance, Position, PositionAccounting,
+    PriceSnapshot, ReplyContext, Snapshot, State,
+};
 
 pub const CONFIG: Item<Config> = Item::new("config");
 pub const STATE: Item<State> = Item::new("state");
+pub const PENDING_GOVERNANCE: Item<PendingGovernance> = Item::new("pending_governance");
+pub const OPERATORS: Map<&Addr, OperatorInfo> = Map::new("operators");
+pub const PAUSE_FLAGS: Item<PauseFlags> = Item::new("pause_flags");
 pub const POSITION: Map<&Addr, Position> = Map::new("position");
 
-// save user address temporarily between callbacks
-pub const CACHED_USER_ADDR: Item<Addr> = Item::new("cached_user_addr");
+// the two most recent snapshots of each tracked pair's cumulative price, oldest first, indexed by
+// the pair's contract address
+pub const PRICE_SNAPSHOTS: Map<&Addr, Vec<PriceSnapshot>> = Map::new("price_snapshots");
+
+// context of the submessage whose reply is pending, saved right before it is dispatched and consumed
+// by the reply handler
+pub const REPLY_CONTEXT: Item<ReplyContext> = Item::new("reply_context");
+
+// cumulative accounting of each user's position, from which its PnL is derived
+pub const ACCOUNTING: Map<&Addr, PositionAccounting> = Map::new("accounting");
 
 // snapshot is used by the frontend calculate user PnL. once we build a transaction indexer that can
 // calculate PnL without relying on on-chain snapshots, this will be removed
-pub const SNAPSHOT: Map<&Addr, Snapshot> = Map::new("snapshot");
\ No newline at end of file
+pub const SNAPSHOT: Map<&Addr, Snapshot> = Map::new("snapshot");
@@ -5,7 +5,7 @@ use cosmwasm_std::{
 use cw20::Cw20ReceiveMsg;
 
 use astroport::generator::{
-    Cw20HookMsg, ExecuteMsg, PendingTokenResponse, QueryMsg, RewardInfoResponse
+    Cw20HookMsg, ExecuteMsg, PendingTokenResponse, QueryMsg, RewardInfoResponse,
 };
 
 use cw_asset::Asset;
@@ -46,9 +46,10 @@ pub fn execute(
             amount,
         } => execute_withdraw(deps, info.sender, lp_token, amount),
 
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented execute: {}", String::from_utf8(to_vec(&msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented execute: {}",
+            String::from_utf8(to_vec(&msg)?)?
+        ))),
     }
 }
 
@@ -81,13 +82,10 @@ fn execute_deposit(
     let config = CONFIG.load(deps.storage)?;
 
     if liquidity_token != config.liquidity_token {
-        return Err(StdError::generic_err(
-            format!(
-                "[mock] invalid liquidity token! expected: {}, received: {}",
-                config.liquidity_token, 
-                liquidity_token
-            )
-        ));
+        return Err(StdError::generic_err(format!(
+            "[mock] invalid liquidity token! expected: {}, received: {}",
+            config.liquidity_token, liquidity_token
+        )));
     }
 
     let mut deposit = DEPOSIT.load(deps.storage, &user_addr).unwrap_or_else(|_| Uint128::zero());
@@ -107,13 +105,10 @@ fn execute_withdraw(
     let config = CONFIG.load(deps.storage)?;
 
     if liquidity_token != config.liquidity_token {
-        return Err(StdError::generic_err(
-            format!(
-                "[mock] invalid liquidity token! expected: {}, received: {}",
-                config.liquidity_token, 
-                liquidity_token
-            )
-        ));
+        return Err(StdError::generic_err(format!(
+            "[mock] invalid liquidity token! expected: {}, received: {}",
+            config.liquidity_token, liquidity_token
+        )));
     }
 
     let mut deposit = DEPOSIT.load(deps.storage, &user_addr).unwrap_or_else(|_| Uint128::zero());
@@ -165,9 +160,10 @@ pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
             user: _, // this mock contract returns fixed amount of rewards regardless of user deposit
         } => to_binary(&query_pending_token(deps, lp_token)?),
 
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented query: {}", String::from_utf8(to_vec(&msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented query: {}",
+            String::from_utf8(to_vec(&msg)?)?
+        ))),
     }
 }
 
@@ -175,7 +171,7 @@ fn query_reward_info(deps: Deps) -> StdResult<RewardInfoResponse> {
     let config = CONFIG.load(deps.storage)?;
     Ok(RewardInfoResponse {
         base_reward_token: config.astro_token,
-        proxy_reward_token: config.proxy_reward_token
+        proxy_reward_token: config.proxy_reward_token,
     })
 }
 
@@ -195,13 +191,10 @@ fn query_pending_token(deps: Deps, liquidity_token: Addr) -> StdResult<PendingTo
     let pending = if liquidity_token == config.liquidity_token {
         MOCK_ASTRO_REWARD_AMOUNT
     } else {
-        return Err(StdError::generic_err(
-            format!(
-                "[mock] invalid liquidity token! expected: {}, received: {}",
-                config.liquidity_token, 
-                liquidity_token
-            )
-        ));
+        return Err(StdError::generic_err(format!(
+            "[mock] invalid liquidity token! expected: {}, received: {}",
+            config.liquidity_token, liquidity_token
+        )));
     };
 
     let pending_on_proxy = if config.proxy_reward_token.is_some() {
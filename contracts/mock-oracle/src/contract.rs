@@ -40,9 +40,10 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             price_source,
         } => execute_set_asset(deps, env, info, asset, price_source),
 
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented execute: {}", String::from_utf8(to_vec(&msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented execute: {}",
+            String::from_utf8(to_vec(&msg)?)?
+        ))),
     }
 }
 
@@ -68,9 +69,10 @@ fn execute_set_asset(
         },
 
         ps => {
-            return Err(StdError::generic_err(
-                format!("[mock] unimplemented price source: {}", String::from_utf8(to_vec(&ps)?)?)
-            ));
+            return Err(StdError::generic_err(format!(
+                "[mock] unimplemented price source: {}",
+                String::from_utf8(to_vec(&ps)?)?
+            )));
         }
     };
 
@@ -91,9 +93,10 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             asset_reference,
         } => to_binary(&query_asset_price(deps, env, &asset_reference)?),
 
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented query: {}", String::from_utf8(to_vec(&msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented query: {}",
+            String::from_utf8(to_vec(&msg)?)?
+        ))),
     }
 }
 
@@ -129,9 +132,10 @@ fn query_asset_price(deps: Deps, _env: Env, asset_reference: &[u8]) -> StdResult
         }
 
         ps => {
-            return Err(StdError::generic_err(
-                format!("[mock] unimplemented price source: {}", String::from_utf8(to_vec(&ps)?)?)
-            ))
+            return Err(StdError::generic_err(format!(
+                "[mock] unimplemented price source: {}",
+                String::from_utf8(to_vec(&ps)?)?
+            )))
         }
     };
 
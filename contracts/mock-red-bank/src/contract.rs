@@ -1,5 +1,5 @@
 use cosmwasm_std::{
-    entry_point, from_binary, to_binary, to_vec, Addr, Binary, Deps, DepsMut, Empty, Env, 
+    entry_point, from_binary, to_binary, to_vec, Addr, Binary, Deps, DepsMut, Empty, Env,
     MessageInfo, Response, StdError, StdResult, Uint128,
 };
 use cw20::Cw20ReceiveMsg;
@@ -60,14 +60,17 @@ pub fn execute_receive_cw20(
     cw20_msg: Cw20ReceiveMsg,
 ) -> StdResult<Response> {
     match from_binary(&cw20_msg.msg)? {
-        ReceiveMsg::RepayCw20 { .. } => {
+        ReceiveMsg::RepayCw20 {
+            ..
+        } => {
             let repayer_addr = deps.api.addr_validate(&cw20_msg.sender)?;
             let denom = info.sender.to_string();
             execute_repay(deps, env, info, repayer_addr, &denom, cw20_msg.amount)
         }
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented receiver: {}", String::from_utf8(to_vec(&cw20_msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented receiver: {}",
+            String::from_utf8(to_vec(&cw20_msg)?)?
+        ))),
     }
 }
 
@@ -143,9 +146,10 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
             asset,
         } => to_binary(&query_debt(deps, env, user_address, asset)?),
 
-        _ => Err(StdError::generic_err(
-            format!("[mock] unimplemented query: {}", String::from_utf8(to_vec(&msg)?)?)
-        )),
+        _ => Err(StdError::generic_err(format!(
+            "[mock] unimplemented query: {}",
+            String::from_utf8(to_vec(&msg)?)?
+        ))),
     }
 }
 
@@ -1,17 +1,30 @@
 use std::str::FromStr;
 
 use cosmwasm_std::{
-    to_binary, Addr, Api, Coin, CosmosMsg, Decimal, Event, QuerierWrapper, QueryRequest, StdError,
-    StdResult, SubMsg, Uint128, WasmMsg, WasmQuery,
+    to_binary, Addr, Api, Coin, CosmosMsg, Decimal, Event, QuerierWrapper, QueryRequest, StdResult,
+    SubMsg, Uint128, WasmMsg, WasmQuery,
 };
 use cw20::Cw20ExecuteMsg;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use astroport::pair::{Cw20HookMsg, ExecuteMsg, PoolResponse, QueryMsg, ReverseSimulationResponse};
+use astroport::pair::{
+    CumulativePricesResponse, Cw20HookMsg, ExecuteMsg, PoolResponse, QueryMsg,
+    ReverseSimulationResponse,
+};
+
+use cw_asset::{Asset, AssetInfo};
+
+use crate::error::FieldsOfMarsError;
 
-use cw_asset::{Asset, AssetInfo, AssetUnchecked};
+/// Commission rate charged by Astroport XYK pairs. The commission is deducted from the return amount
+/// of each swap and stays in the pool
+pub const ASTROPORT_COMMISSION_RATE: &str = "0.003";
+
+/// Astroport XYK pairs scale cumulative prices by this factor, so that prices below 1 are not
+/// rounded down to zero
+pub const ASTROPORT_TWAP_PRECISION: u128 = 1_000_000;
 
 //--------------------------------------------------------------------------------------------------
 // Pair
@@ -161,7 +174,7 @@ impl Pair {
         querier: &QuerierWrapper,
         primary_asset_info: &AssetInfo,
         secondary_asset_info: &AssetInfo,
-    ) -> StdResult<(Uint128, Uint128, Uint128)> {
+    ) -> Result<(Uint128, Uint128, Uint128), FieldsOfMarsError> {
         let response: PoolResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
             contract_addr: self.contract_addr.to_string(),
             msg: to_binary(&QueryMsg::Pool {})?,
@@ -171,19 +184,53 @@ impl Pair {
             .assets
             .iter()
             .find(|asset| asset.info == *primary_asset_info)
-            .ok_or_else(|| StdError::generic_err("cannot find primary asset in pool response"))?
+            .ok_or_else(|| self.asset_not_in_pool(primary_asset_info))?
             .amount;
 
         let secondary_asset_depth = response
             .assets
             .iter()
             .find(|asset| asset.info == *secondary_asset_info)
-            .ok_or_else(|| StdError::generic_err("cannot find secondary asset in pool response"))?
+            .ok_or_else(|| self.asset_not_in_pool(secondary_asset_info))?
             .amount;
 
         Ok((primary_asset_depth, secondary_asset_depth, response.total_share))
     }
 
+    /// Query the cumulative price of an asset in the pool, i.e. the sum of its price in terms of the
+    /// other asset multiplied by the number of seconds the price has lasted, scaled by
+    /// `ASTROPORT_TWAP_PRECISION`
+    ///
+    /// The time-weighted average price over a period is the difference in cumulative prices between
+    /// its start and end, divided by its length. NOTE: the cumulative price may overflow and wrap
+    /// around, so the difference must be computed with wrapping subtraction
+    pub fn query_cumulative_price(
+        &self,
+        querier: &QuerierWrapper,
+        asset_info: &AssetInfo,
+    ) -> Result<Uint128, FieldsOfMarsError> {
+        let response: CumulativePricesResponse =
+            querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: self.contract_addr.to_string(),
+                msg: to_binary(&QueryMsg::CumulativePrices {})?,
+            }))?;
+
+        if response.assets[0].info == *asset_info {
+            Ok(response.price0_cumulative_last)
+        } else if response.assets[1].info == *asset_info {
+            Ok(response.price1_cumulative_last)
+        } else {
+            Err(self.asset_not_in_pool(asset_info))
+        }
+    }
+
+    fn asset_not_in_pool(&self, asset_info: &AssetInfo) -> FieldsOfMarsError {
+        FieldsOfMarsError::AssetNotInPool {
+            pair: self.contract_addr.to_string(),
+            asset: asset_info.to_string(),
+        }
+    }
+
     /// Calculate how much offer asset is needed to return a specified amount of ask asset
     pub fn query_reverse_simulate(
         &self,
@@ -201,66 +248,39 @@ impl Pair {
     }
 
     /// Find the return amount when swapping in an Astroport pool
-    pub fn parse_swap_events(events: &[Event]) -> StdResult<AssetUnchecked> {
-        let event = events
-            .iter()
-            .find(|event| event_contains_attr(event, "action", "swap"))
-            .ok_or_else(|| StdError::generic_err("cannot find `swap` event"))?;
-
-        let ask_asset_str = event
-            .attributes
-            .iter()
-            .cloned()
-            .find(|attr| attr.key == "ask_asset")
-            .ok_or_else(|| StdError::generic_err("cannot find `ask_asset` attribute"))?
-            .value;
-
-        let return_amount_str = event
-            .attributes
-            .iter()
-            .cloned()
-            .find(|attr| attr.key == "return_amount")
-            .ok_or_else(|| StdError::generic_err("cannot find `return_amount` attribute"))?
-            .value;
-
-        let tax_amount_str = event
-            .attributes
-            .iter()
-            .cloned()
-            .find(|attr| attr.key == "tax_amount")
-            .ok_or_else(|| StdError::generic_err("cannot find `tax_amount` attribute"))?
-            .value;
+    ///
+    /// The ask asset is identified by comparing its label against `known_asset_infos`, rather than
+    /// guessing its type from the label, which would depend on the chain's address prefix
+    pub fn parse_swap_events(
+        events: &[Event],
+        known_asset_infos: &[&AssetInfo],
+    ) -> Result<Asset, FieldsOfMarsError> {
+        let event = find_event(events, "swap")?;
+        let ask_asset_str = find_attr_value(event, "swap", "ask_asset")?;
+        let return_amount_str = find_attr_value(event, "swap", "return_amount")?;
+        let tax_amount_str = find_attr_value(event, "swap", "tax_amount")?;
 
         let return_amount = Uint128::from_str(&return_amount_str)?;
         let tax_amount = Uint128::from_str(&tax_amount_str)?;
         let return_amount_after_tax = return_amount.checked_sub(tax_amount)?;
 
-        // If the asset's label starts with `terra` then we assume it is a CW20
-        // Otherwise, assume it is a native
-        // Not a very clean way of doin this, but ok
-        if ask_asset_str.starts_with("terra") {
-            Ok(AssetUnchecked::cw20(ask_asset_str, return_amount_after_tax))
-        } else {
-            Ok(AssetUnchecked::native(ask_asset_str, return_amount_after_tax))
-        }
+        let ask_asset_info = known_asset_infos
+            .iter()
+            .find(|info| info.to_string() == ask_asset_str)
+            .ok_or(FieldsOfMarsError::InvalidAttribute {
+                attribute: "ask_asset".to_string(),
+                value: ask_asset_str,
+            })?;
+
+        Ok(Asset::new((*ask_asset_info).clone(), return_amount_after_tax))
     }
 
     /// Find the amount of share tokens minted when providing liquidity to an Astroport pool
-    pub fn parse_provide_events(events: &[Event]) -> StdResult<Uint128> {
-        let event = events
-            .iter()
-            .find(|event| event_contains_attr(event, "action", "provide_liquidity"))
-            .ok_or_else(|| StdError::generic_err("cannot find `provide_liquidity` event"))?;
-
-        let share_str = event
-            .attributes
-            .iter()
-            .cloned()
-            .find(|attr| attr.key == "share")
-            .ok_or_else(|| StdError::generic_err("cannot find `share` attribute"))?
-            .value;
+    pub fn parse_provide_events(events: &[Event]) -> Result<Uint128, FieldsOfMarsError> {
+        let event = find_event(events, "provide_liquidity")?;
+        let share_str = find_attr_value(event, "provide_liquidity", "share")?;
 
-        Uint128::from_str(&share_str)
+        Ok(Uint128::from_str(&share_str)?)
     }
 
     /// Find the amount of assets refunded when withdrawing liquidity from an Astroport pool
@@ -269,34 +289,28 @@ impl Pair {
         events: &[Event],
         primary_asset_info: &AssetInfo,
         secondary_asset_info: &AssetInfo,
-    ) -> StdResult<(Asset, Asset)> {
-        let event = events
-            .iter()
-            .find(|event| event_contains_attr(event, "action", "withdraw_liquidity"))
-            .ok_or_else(|| StdError::generic_err("cannot find `withdraw_liquidity` event"))?;
-
-        let asset_strs: Vec<&str> = event
-            .attributes
-            .iter()
-            .find(|attr| attr.key == "refund_assets")
-            .ok_or_else(|| StdError::generic_err("cannot find `refund_assets` attribute"))?
-            .value
-            .split(", ")
-            .collect();
+    ) -> Result<(Asset, Asset), FieldsOfMarsError> {
+        let event = find_event(events, "withdraw_liquidity")?;
+        let refund_assets_str = find_attr_value(event, "withdraw_liquidity", "refund_assets")?;
+        let asset_strs: Vec<&str> = refund_assets_str.split(", ").collect();
+        let invalid_refund_assets = || FieldsOfMarsError::InvalidAttribute {
+            attribute: "refund_assets".to_string(),
+            value: refund_assets_str.clone(),
+        };
 
         let primary_asset_label = primary_asset_info.to_string();
         let primary_withdrawn_amount_str = asset_strs
             .iter()
             .find(|asset_str| asset_str.contains(&primary_asset_label))
             .map(|asset_str| asset_str.replace(&primary_asset_label, ""))
-            .ok_or_else(|| StdError::generic_err("failed to parse primary withdrawn amount"))?;
+            .ok_or_else(invalid_refund_assets)?;
 
         let secondary_asset_label = secondary_asset_info.to_string();
         let secondary_withdrawn_amount_str = asset_strs
             .iter()
             .find(|asset_str| asset_str.contains(&secondary_asset_label))
             .map(|asset_str| asset_str.replace(&secondary_asset_label, ""))
-            .ok_or_else(|| StdError::generic_err("failed to parse secondary withdrawn amount"))?;
+            .ok_or_else(invalid_refund_assets)?;
 
         let primary_asset_withdrawn = Asset::new(
             primary_asset_info.clone(),
@@ -314,3 +328,20 @@ impl Pair {
 fn event_contains_attr(event: &Event, key: &str, value: &str) -> bool {
     event.attributes.iter().any(|attr| attr.key == key && attr.value == value)
 }
+
+fn find_event<'a>(events: &'a [Event], action: &str) -> Result<&'a Event, FieldsOfMarsError> {
+    events.iter().find(|event| event_contains_attr(event, "action", action)).ok_or_else(|| {
+        FieldsOfMarsError::EventNotFound {
+            event: action.to_string(),
+        }
+    })
+}
+
+fn find_attr_value(event: &Event, action: &str, key: &str) -> Result<String, FieldsOfMarsError> {
+    event.attributes.iter().find(|attr| attr.key == key).map(|attr| attr.value.clone()).ok_or_else(
+        || FieldsOfMarsError::AttributeNotFound {
+            event: action.to_string(),
+            attribute: key.to_string(),
+        },
+    )
+}
@@ -2,10 +2,12 @@ mod apollo_factory;
 mod astro_generator;
 mod astro_pair;
 mod oracle;
+mod price_source;
 mod red_bank;
 
 pub use self::apollo_factory::*;
 pub use self::astro_generator::*;
 pub use self::astro_pair::*;
 pub use self::oracle::*;
+pub use self::price_source::*;
 pub use self::red_bank::*;
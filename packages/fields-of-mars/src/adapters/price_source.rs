@@ -0,0 +1,198 @@
+use cosmwasm_std::{Addr, Api, Decimal, Fraction, QuerierWrapper, StdResult, Uint128};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use cw_asset::AssetInfo;
+
+use crate::adapters::{OracleBase, Pair, PairBase};
+use crate::error::FieldsOfMarsError;
+
+/// Where the price of an asset is read from
+///
+/// Astroport sources quote the asset in terms of the other asset in the pair, which is then
+/// converted using that asset's own price. The caller is responsible for providing the quote asset's
+/// price, as well as the time-weighted average price (TWAP) of the asset, computed from snapshots of
+/// the pair's cumulative prices
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSourceBase<T> {
+    /// Price reported by the Mars Protocol oracle
+    MarsOracle {
+        oracle: OracleBase<T>,
+    },
+    /// A fixed price, e.g. for a stablecoin used as the unit of account
+    Fixed {
+        price: Decimal,
+    },
+    /// Spot price of the asset in an Astroport pair
+    ///
+    /// To guard against manipulation, the spot price must not deviate from the pair's TWAP by more
+    /// than `max_deviation`, relative to the TWAP
+    AstroportSpot {
+        pair: PairBase<T>,
+        max_deviation: Decimal,
+    },
+    /// Time-weighted average price of the asset in an Astroport pair
+    AstroportTwap {
+        pair: PairBase<T>,
+    },
+}
+
+pub type PriceSourceUnchecked = PriceSourceBase<String>;
+pub type PriceSource = PriceSourceBase<Addr>;
+
+impl From<PriceSource> for PriceSourceUnchecked {
+    fn from(source: PriceSource) -> Self {
+        match source {
+            PriceSource::MarsOracle {
+                oracle,
+            } => PriceSourceUnchecked::MarsOracle {
+                oracle: oracle.into(),
+            },
+            PriceSource::Fixed {
+                price,
+            } => PriceSourceUnchecked::Fixed {
+                price,
+            },
+            PriceSource::AstroportSpot {
+                pair,
+                max_deviation,
+            } => PriceSourceUnchecked::AstroportSpot {
+                pair: pair.into(),
+                max_deviation,
+            },
+            PriceSource::AstroportTwap {
+                pair,
+            } => PriceSourceUnchecked::AstroportTwap {
+                pair: pair.into(),
+            },
+        }
+    }
+}
+
+impl PriceSourceUnchecked {
+    pub fn check(&self, api: &dyn Api) -> StdResult<PriceSource> {
+        Ok(match self {
+            PriceSourceUnchecked::MarsOracle {
+                oracle,
+            } => PriceSource::MarsOracle {
+                oracle: oracle.check(api)?,
+            },
+            PriceSourceUnchecked::Fixed {
+                price,
+            } => PriceSource::Fixed {
+                price: *price,
+            },
+            PriceSourceUnchecked::AstroportSpot {
+                pair,
+                max_deviation,
+            } => PriceSource::AstroportSpot {
+                pair: pair.check(api)?,
+                max_deviation: *max_deviation,
+            },
+            PriceSourceUnchecked::AstroportTwap {
+                pair,
+            } => PriceSource::AstroportTwap {
+                pair: pair.check(api)?,
+            },
+        })
+    }
+}
+
+/// Data needed to evaluate an Astroport price source, provided by the caller
+#[derive(Clone, Debug, PartialEq)]
+pub struct AstroportQuote {
+    /// The other asset in the pair, in terms of which the pair quotes the asset's price
+    pub asset_info: AssetInfo,
+    /// Price of the quote asset
+    pub price: Decimal,
+    /// TWAP of the asset in terms of the quote asset; `None` if not enough snapshots have been taken
+    pub twap: Option<Decimal>,
+}
+
+impl PriceSource {
+    /// The Astroport pair the price is derived from, if any
+    pub fn pair(&self) -> Option<&Pair> {
+        match self {
+            PriceSource::AstroportSpot {
+                pair,
+                ..
+            }
+            | PriceSource::AstroportTwap {
+                pair,
+            } => Some(pair),
+            _ => None,
+        }
+    }
+
+    /// Query the price of an asset
+    ///
+    /// `quote` must be provided for Astroport sources, and is ignored otherwise
+    pub fn query_price(
+        &self,
+        querier: &QuerierWrapper,
+        asset_info: &AssetInfo,
+        quote: Option<&AstroportQuote>,
+    ) -> Result<Decimal, FieldsOfMarsError> {
+        match self {
+            PriceSource::MarsOracle {
+                oracle,
+            } => Ok(oracle.query_price(querier, asset_info)?),
+
+            PriceSource::Fixed {
+                price,
+            } => Ok(*price),
+
+            PriceSource::AstroportSpot {
+                pair,
+                max_deviation,
+            } => {
+                let quote = unwrap_quote(quote)?;
+                let twap = quote.twap.ok_or(FieldsOfMarsError::TwapNotAvailable {})?;
+
+                let (depth, quote_depth, _) =
+                    pair.query_pool(querier, asset_info, &quote.asset_info)?;
+                if depth.is_zero() || twap.is_zero() {
+                    return Err(FieldsOfMarsError::ZeroPrice {});
+                }
+                let spot_price = Decimal::from_ratio(quote_depth, depth);
+
+                let diff = if spot_price > twap {
+                    spot_price - twap
+                } else {
+                    twap - spot_price
+                };
+                let deviation = Decimal::from_ratio(diff.numerator(), twap.numerator());
+                if deviation > *max_deviation {
+                    return Err(FieldsOfMarsError::SpotPriceDeviationTooHigh {
+                        spot_price,
+                        twap,
+                        deviation,
+                        max: *max_deviation,
+                    });
+                }
+
+                Ok(decimal_mul(spot_price, quote.price))
+            }
+
+            PriceSource::AstroportTwap {
+                ..
+            } => {
+                let quote = unwrap_quote(quote)?;
+                let twap = quote.twap.ok_or(FieldsOfMarsError::TwapNotAvailable {})?;
+                Ok(decimal_mul(twap, quote.price))
+            }
+        }
+    }
+}
+
+fn unwrap_quote(quote: Option<&AstroportQuote>) -> Result<&AstroportQuote, FieldsOfMarsError> {
+    quote.ok_or(FieldsOfMarsError::QuoteNotProvided {})
+}
+
+/// Multiply two decimals. Unlike multiplying their numerators directly, this does not overflow for
+/// prices in any realistic range
+fn decimal_mul(a: Decimal, b: Decimal) -> Decimal {
+    Decimal::from_ratio(Uint128::new(a.numerator()) * b, a.denominator())
+}
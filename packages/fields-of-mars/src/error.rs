@@ -0,0 +1,67 @@
+use cosmwasm_std::{Decimal, OverflowError, StdError};
+use thiserror::Error;
+
+/// Errors returned by the adapters and the validation of types in this package
+#[derive(Error, Debug, PartialEq)]
+pub enum FieldsOfMarsError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("{0}")]
+    Overflow(#[from] OverflowError),
+
+    #[error("invalid {param}: {reason}")]
+    InvalidConfig {
+        param: String,
+        reason: String,
+    },
+
+    #[error("cannot find {asset} in pool {pair}")]
+    AssetNotInPool {
+        pair: String,
+        asset: String,
+    },
+
+    #[error("cannot find `{event}` event")]
+    EventNotFound {
+        event: String,
+    },
+
+    #[error("cannot find `{attribute}` attribute in `{event}` event")]
+    AttributeNotFound {
+        event: String,
+        attribute: String,
+    },
+
+    #[error("invalid `{attribute}` attribute: {value}")]
+    InvalidAttribute {
+        attribute: String,
+        value: String,
+    },
+
+    #[error("not enough price snapshots to compute twap")]
+    TwapNotAvailable {},
+
+    #[error("invalid price: zero")]
+    ZeroPrice {},
+
+    #[error("spot price {spot_price} deviates from twap {twap} by {deviation}; must be <= {max}")]
+    SpotPriceDeviationTooHigh {
+        spot_price: Decimal,
+        twap: Decimal,
+        deviation: Decimal,
+        max: Decimal,
+    },
+
+    #[error("quote must be provided for astroport price sources")]
+    QuoteNotProvided {},
+}
+
+impl FieldsOfMarsError {
+    pub fn invalid_config(param: impl Into<String>, reason: impl Into<String>) -> Self {
+        FieldsOfMarsError::InvalidConfig {
+            param: param.into(),
+            reason: reason.into(),
+        }
+    }
+}
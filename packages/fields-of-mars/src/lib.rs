@@ -1,5 +1,6 @@
-pub mod martian_field;
 pub mod adapters;
+pub mod error;
+pub mod martian_field;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod testing;
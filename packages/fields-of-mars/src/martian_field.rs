@@ -1,20 +1,30 @@
+use std::convert::TryFrom;
 use std::str::FromStr;
 
 use cosmwasm_std::{
-    to_binary, Addr, Api, CosmosMsg, Decimal, Decimal256, StdError, StdResult, Uint128, WasmMsg,
+    to_binary, Addr, Api, CosmosMsg, Decimal, Decimal256, QuerierWrapper, StdError, StdResult,
+    Uint128, WasmMsg,
 };
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cw_asset::{AssetInfoBase, AssetListBase};
+use cw_asset::{AssetInfoBase, AssetInfoUnchecked, AssetListBase};
 
-use crate::adapters::{ApolloFactoryBase, GeneratorBase, OracleBase, PairBase, RedBankBase};
+use crate::adapters::{
+    ApolloFactoryBase, ApolloFactoryUnchecked, GeneratorBase, GeneratorUnchecked, PairBase,
+    PairUnchecked, PriceSource, PriceSourceBase, PriceSourceUnchecked, RedBankBase,
+    RedBankUnchecked,
+};
+use crate::error::FieldsOfMarsError;
 
 const MIN_MAX_LTV: &str = "0.1";
 const MAX_MAX_LTV: &str = "0.95";
 const MAX_FEE_RATE: &str = "1";
 const MAX_BONUS_RATE: &str = "0.1";
+const MAX_HARVEST_BOUNTY: &str = "0.1";
+const MAX_LIQUIDATION_MAX_SPREAD: &str = "0.5";
+const MAX_PRICE_DEVIATION_TOLERANCE: &str = "0.5";
 
 //--------------------------------------------------------------------------------------------------
 // Config
@@ -54,22 +64,73 @@ pub struct ConfigBase<T> {
     pub astro_generator: GeneratorBase<T>,
     /// The Mars Protocol money market contract. We borrow the secondary asset here
     pub red_bank: RedBankBase<T>,
-    /// The Mars Protocol oracle contract. We read prices of the primary and secondary assets here
-    pub oracle: OracleBase<T>,
+    /// Where the price of the primary asset is read from
+    pub primary_price_source: PriceSourceBase<T>,
+    /// Where the price of the secondary asset is read from
+    ///
+    /// Astroport price sources quote assets in terms of the secondary asset, so this one must be
+    /// either the Mars oracle or a fixed price
+    pub secondary_price_source: PriceSourceBase<T>,
+    /// Where the price of ASTRO is read from. Used to derive the belief price when swapping rewards
+    pub astro_price_source: PriceSourceBase<T>,
     /// Account to receive fee payments
     pub treasury: T,
-    /// Account who can update config
+    /// Account who can update config and appoint other roles
+    ///
+    /// Governance can only be transferred in two steps: the current governance proposes a new
+    /// account, which then needs to accept the role
     pub governance: T,
-    /// Accounts who can harvest
-    pub operators: Vec<T>,
+    /// Account who can halt the contract in case of an emergency
+    pub guardian: T,
+    /// Account who can update the performance fee and the treasury address
+    pub fee_manager: T,
+    /// Account who can add or remove operators, i.e. accounts who can harvest
+    pub operator_manager: T,
+    /// Whether accounts other than operators can harvest
+    pub permissionless_harvest: bool,
     /// Maximum loan-to-value ratio (LTV) above which a user can be liquidated
     pub max_ltv: Decimal,
     /// Maximum loan-to-value ratio (LTV) when updating a user's position
     pub max_initial_ltv: Decimal,
     /// Percentage of profit to be charged as performance fee
     pub performance_fee: Decimal,
+    /// Percentage of profit to be paid to the account who triggers the harvest
+    pub harvest_bounty: Decimal,
+    /// Minimum number of seconds that must elapse between two harvests
+    pub min_harvest_interval: u64,
     /// During liquidation, percentage of the user's asset to be awared to the liquidator as bonus
     pub bonus_rate: Decimal,
+    /// During a partial liquidation, maximum percentage of the user's debt that can be repaid
+    pub close_factor: Decimal,
+    /// LTV at or above which a position is liquidated in full, instead of partially
+    ///
+    /// Below this LTV, a liquidation only repays enough debt to bring the position's LTV back to
+    /// `max_initial_ltv`, up to `close_factor` of the debt
+    pub full_liquidation_ltv: Decimal,
+    /// Maximum spread allowed when selling the primary asset to repay debts during liquidation,
+    /// relative to the price implied by the price sources
+    ///
+    /// Liquidators may specify a lower value, but not a higher one
+    pub liquidation_max_spread: Decimal,
+    /// Maximum relative deviation of a pool's spot price from the price implied by the price
+    /// sources, above which swaps in the pool are rejected
+    ///
+    /// The implied price is also used as the belief price of every swap, so that `max_spread`
+    /// is measured against it instead of the pool's spot price
+    pub price_deviation_tolerance: Decimal,
+    /// Minimum number of seconds between two snapshots of a pair's cumulative price. The
+    /// time-weighted average price (TWAP) is computed over the period between the two most recent
+    /// snapshots
+    ///
+    /// Snapshots are taken of the primary pair, and of the pairs used by Astroport price sources
+    pub twap_window: u64,
+    /// Maximum age (in seconds) of the most recent price snapshot, above which the TWAP is
+    /// considered stale; zero disables the price check
+    ///
+    /// Before a liquidation, the TWAP of the primary asset must be fresh, and the price implied by
+    /// the price sources must not deviate from it by more than `price_deviation_tolerance`. Otherwise
+    /// liquidations are refused; repaying and closing positions are not affected
+    pub max_price_age: u64,
     /// In order to receive Apollo Rewards, we must provide an APR QueryMsg.
     /// Here we outsource this to the contract address provided below.
     pub apr_query_adapter: T,
@@ -94,13 +155,26 @@ impl From<Config> for ConfigUnchecked {
             astro_pair: config.astro_pair.into(),
             astro_generator: config.astro_generator.into(),
             red_bank: config.red_bank.into(),
-            oracle: config.oracle.into(),
+            primary_price_source: config.primary_price_source.into(),
+            secondary_price_source: config.secondary_price_source.into(),
+            astro_price_source: config.astro_price_source.into(),
             treasury: config.treasury.into(),
             governance: config.governance.into(),
-            operators: config.operators.iter().map(|op| op.to_string()).collect(),
+            guardian: config.guardian.into(),
+            fee_manager: config.fee_manager.into(),
+            operator_manager: config.operator_manager.into(),
+            permissionless_harvest: config.permissionless_harvest,
             max_ltv: config.max_ltv,
             performance_fee: config.performance_fee,
+            harvest_bounty: config.harvest_bounty,
+            min_harvest_interval: config.min_harvest_interval,
             bonus_rate: config.bonus_rate,
+            close_factor: config.close_factor,
+            full_liquidation_ltv: config.full_liquidation_ltv,
+            liquidation_max_spread: config.liquidation_max_spread,
+            price_deviation_tolerance: config.price_deviation_tolerance,
+            twap_window: config.twap_window,
+            max_price_age: config.max_price_age,
             apr_query_adapter: config.apr_query_adapter.into(),
             apollo_factory: config.apollo_factory.into(),
             max_initial_ltv: config.max_initial_ltv,
@@ -119,17 +193,26 @@ impl ConfigUnchecked {
             astro_pair: self.astro_pair.check(api)?,
             astro_generator: self.astro_generator.check(api)?,
             red_bank: self.red_bank.check(api)?,
-            oracle: self.oracle.check(api)?,
+            primary_price_source: self.primary_price_source.check(api)?,
+            secondary_price_source: self.secondary_price_source.check(api)?,
+            astro_price_source: self.astro_price_source.check(api)?,
             treasury: api.addr_validate(&self.treasury)?,
             governance: api.addr_validate(&self.governance)?,
-            operators: self
-                .operators
-                .iter()
-                .map(|op| api.addr_validate(op))
-                .collect::<StdResult<Vec<Addr>>>()?,
+            guardian: api.addr_validate(&self.guardian)?,
+            fee_manager: api.addr_validate(&self.fee_manager)?,
+            operator_manager: api.addr_validate(&self.operator_manager)?,
+            permissionless_harvest: self.permissionless_harvest,
             max_ltv: self.max_ltv,
             performance_fee: self.performance_fee,
+            harvest_bounty: self.harvest_bounty,
+            min_harvest_interval: self.min_harvest_interval,
             bonus_rate: self.bonus_rate,
+            close_factor: self.close_factor,
+            full_liquidation_ltv: self.full_liquidation_ltv,
+            liquidation_max_spread: self.liquidation_max_spread,
+            price_deviation_tolerance: self.price_deviation_tolerance,
+            twap_window: self.twap_window,
+            max_price_age: self.max_price_age,
             apr_query_adapter: api.addr_validate(&self.apr_query_adapter)?,
             apollo_factory: self.apollo_factory.check(api)?,
             max_initial_ltv: self.max_initial_ltv,
@@ -139,34 +222,465 @@ impl ConfigUnchecked {
 }
 
 impl Config {
-    pub fn validate(&self) -> StdResult<()> {
+    pub fn validate(&self) -> Result<(), FieldsOfMarsError> {
         let min_max_ltv = Decimal::from_str(MIN_MAX_LTV)?;
         let max_max_ltv = Decimal::from_str(MAX_MAX_LTV)?;
         if self.max_ltv < min_max_ltv || self.max_ltv > max_max_ltv {
-            return Err(StdError::generic_err(format!(
-                "invalid max ltv: {}; must be in [{}, {}]",
-                self.max_ltv, MIN_MAX_LTV, MAX_MAX_LTV
-            )));
+            return Err(FieldsOfMarsError::invalid_config(
+                "max ltv",
+                format!("{}; must be in [{}, {}]", self.max_ltv, MIN_MAX_LTV, MAX_MAX_LTV),
+            ));
         }
 
         let max_fee_rate = Decimal::from_str(MAX_FEE_RATE)?;
         if self.performance_fee > max_fee_rate {
-            return Err(StdError::generic_err(format!(
-                "invalid fee rate: {}; must be <= {}",
-                self.performance_fee, MAX_FEE_RATE
-            )));
+            return Err(FieldsOfMarsError::invalid_config(
+                "fee rate",
+                format!("{}; must be <= {}", self.performance_fee, MAX_FEE_RATE),
+            ));
+        }
+
+        let max_harvest_bounty = Decimal::from_str(MAX_HARVEST_BOUNTY)?;
+        if self.harvest_bounty > max_harvest_bounty {
+            return Err(FieldsOfMarsError::invalid_config(
+                "harvest bounty",
+                format!("{}; must be <= {}", self.harvest_bounty, MAX_HARVEST_BOUNTY),
+            ));
+        }
+
+        if self.performance_fee + self.harvest_bounty > max_fee_rate {
+            return Err(FieldsOfMarsError::invalid_config(
+                "fee rate",
+                format!(
+                    "{} + harvest bounty {}; must be <= {}",
+                    self.performance_fee, self.harvest_bounty, MAX_FEE_RATE
+                ),
+            ));
         }
 
         let max_bonus_rate = Decimal::from_str(MAX_BONUS_RATE)?;
         if self.bonus_rate > max_bonus_rate {
-            return Err(StdError::generic_err(format!(
-                "invalid bonus rate: {}; must be <= {}",
-                self.bonus_rate, MAX_BONUS_RATE
-            )));
+            return Err(FieldsOfMarsError::invalid_config(
+                "bonus rate",
+                format!("{}; must be <= {}", self.bonus_rate, MAX_BONUS_RATE),
+            ));
+        }
+
+        if self.close_factor.is_zero() || self.close_factor > Decimal::one() {
+            return Err(FieldsOfMarsError::invalid_config(
+                "close factor",
+                format!("{}; must be in (0, 1]", self.close_factor),
+            ));
+        }
+
+        if self.full_liquidation_ltv < self.max_ltv {
+            return Err(FieldsOfMarsError::invalid_config(
+                "full liquidation ltv",
+                format!("{}; must be >= max ltv {}", self.full_liquidation_ltv, self.max_ltv),
+            ));
+        }
+
+        let max_liquidation_max_spread = Decimal::from_str(MAX_LIQUIDATION_MAX_SPREAD)?;
+        if self.liquidation_max_spread.is_zero()
+            || self.liquidation_max_spread > max_liquidation_max_spread
+        {
+            return Err(FieldsOfMarsError::invalid_config(
+                "liquidation max spread",
+                format!(
+                    "{}; must be in (0, {}]",
+                    self.liquidation_max_spread, MAX_LIQUIDATION_MAX_SPREAD
+                ),
+            ));
+        }
+
+        let max_price_deviation_tolerance = Decimal::from_str(MAX_PRICE_DEVIATION_TOLERANCE)?;
+        if self.price_deviation_tolerance.is_zero()
+            || self.price_deviation_tolerance > max_price_deviation_tolerance
+        {
+            return Err(FieldsOfMarsError::invalid_config(
+                "price deviation tolerance",
+                format!(
+                    "{}; must be in (0, {}]",
+                    self.price_deviation_tolerance, MAX_PRICE_DEVIATION_TOLERANCE
+                ),
+            ));
+        }
+
+        if self.twap_window == 0 {
+            return Err(FieldsOfMarsError::invalid_config("twap window", "0; must be > 0"));
+        }
+
+        if self.max_price_age != 0 && self.max_price_age < self.twap_window {
+            return Err(FieldsOfMarsError::invalid_config(
+                "max price age",
+                format!("{}; must be 0 or >= twap window {}", self.max_price_age, self.twap_window),
+            ));
+        }
+
+        for (name, source) in [
+            ("primary", &self.primary_price_source),
+            ("secondary", &self.secondary_price_source),
+            ("astro", &self.astro_price_source),
+        ] {
+            validate_price_source(name, source)?;
+        }
+
+        if self.secondary_price_source.pair().is_some() {
+            return Err(FieldsOfMarsError::invalid_config(
+                "secondary price source",
+                "must be mars oracle or fixed price",
+            ));
+        }
+
+        if self.max_initial_ltv > self.max_ltv {
+            return Err(FieldsOfMarsError::invalid_config(
+                "max initial ltv",
+                format!("{}; must be <= max ltv {}", self.max_initial_ltv, self.max_ltv),
+            ));
+        }
+
+        if self.primary_asset_info == self.secondary_asset_info {
+            return Err(FieldsOfMarsError::invalid_config(
+                "assets",
+                "primary and secondary assets must be different",
+            ));
         }
 
         Ok(())
     }
+
+    /// Assert that the Astroport pairs, including those used as price sources, consist of the
+    /// configured assets
+    ///
+    /// This requires querying the pairs, so unlike `validate` it is only invoked when the assets or
+    /// the pairs are updated
+    pub fn validate_pairs(&self, querier: &QuerierWrapper) -> Result<(), FieldsOfMarsError> {
+        self.primary_pair
+            .query_pool(querier, &self.primary_asset_info, &self.secondary_asset_info)
+            .map_err(|_| {
+                FieldsOfMarsError::invalid_config(
+                    "primary pair",
+                    "must consist of primary and secondary assets",
+                )
+            })?;
+
+        self.astro_pair
+            .query_pool(querier, &self.astro_token_info, &self.secondary_asset_info)
+            .map_err(|_| {
+                FieldsOfMarsError::invalid_config(
+                    "astro pair",
+                    "must consist of ASTRO and secondary asset",
+                )
+            })?;
+
+        for (name, source, asset_info) in [
+            ("primary", &self.primary_price_source, &self.primary_asset_info),
+            ("astro", &self.astro_price_source, &self.astro_token_info),
+        ] {
+            if let Some(pair) = source.pair() {
+                pair.query_pool(querier, asset_info, &self.secondary_asset_info).map_err(|_| {
+                    FieldsOfMarsError::invalid_config(
+                        format!("{} price source pair", name),
+                        format!("must consist of {} and secondary assets", name),
+                    )
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_price_source(name: &str, source: &PriceSource) -> Result<(), FieldsOfMarsError> {
+    match source {
+        PriceSource::Fixed {
+            price,
+        } if price.is_zero() => Err(FieldsOfMarsError::invalid_config(
+            format!("{} price source", name),
+            "fixed price must be > 0",
+        )),
+        PriceSource::AstroportSpot {
+            max_deviation,
+            ..
+        } if max_deviation.is_zero()
+            || *max_deviation > Decimal::from_str(MAX_PRICE_DEVIATION_TOLERANCE)? =>
+        {
+            Err(FieldsOfMarsError::invalid_config(
+                format!("{} price source", name),
+                format!(
+                    "max deviation {}; must be in (0, {}]",
+                    max_deviation, MAX_PRICE_DEVIATION_TOLERANCE
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Config parameters to be updated. Parameters that are `None` are left unchanged
+///
+/// NOTE: `governance` is not included here. It can only be transferred via `ProposeNewGovernance`
+/// and `AcceptGovernance`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct ConfigUpdate {
+    pub primary_asset_info: Option<AssetInfoUnchecked>,
+    pub secondary_asset_info: Option<AssetInfoUnchecked>,
+    pub astro_token_info: Option<AssetInfoUnchecked>,
+    pub primary_pair: Option<PairUnchecked>,
+    pub astro_pair: Option<PairUnchecked>,
+    pub astro_generator: Option<GeneratorUnchecked>,
+    pub red_bank: Option<RedBankUnchecked>,
+    pub primary_price_source: Option<PriceSourceUnchecked>,
+    pub secondary_price_source: Option<PriceSourceUnchecked>,
+    pub astro_price_source: Option<PriceSourceUnchecked>,
+    pub treasury: Option<String>,
+    pub guardian: Option<String>,
+    pub fee_manager: Option<String>,
+    pub operator_manager: Option<String>,
+    pub permissionless_harvest: Option<bool>,
+    pub max_ltv: Option<Decimal>,
+    pub max_initial_ltv: Option<Decimal>,
+    pub performance_fee: Option<Decimal>,
+    pub harvest_bounty: Option<Decimal>,
+    pub min_harvest_interval: Option<u64>,
+    pub bonus_rate: Option<Decimal>,
+    pub close_factor: Option<Decimal>,
+    pub full_liquidation_ltv: Option<Decimal>,
+    pub liquidation_max_spread: Option<Decimal>,
+    pub price_deviation_tolerance: Option<Decimal>,
+    pub twap_window: Option<u64>,
+    pub max_price_age: Option<u64>,
+    pub apr_query_adapter: Option<String>,
+    pub apollo_factory: Option<ApolloFactoryUnchecked>,
+    pub min_position_size: Option<Uint128>,
+}
+
+impl ConfigUpdate {
+    /// Whether any of the parameters that `Config::validate_pairs` depends on is to be updated
+    pub fn updates_pairs(&self) -> bool {
+        self.primary_price_source.is_some()
+            || self.astro_price_source.is_some()
+            || self.primary_asset_info.is_some()
+            || self.secondary_asset_info.is_some()
+            || self.astro_token_info.is_some()
+            || self.primary_pair.is_some()
+            || self.astro_pair.is_some()
+    }
+}
+
+impl ConfigUnchecked {
+    /// Overwrite parameters with the ones provided in the update
+    pub fn apply_update(&mut self, update: ConfigUpdate) {
+        if let Some(primary_asset_info) = update.primary_asset_info {
+            self.primary_asset_info = primary_asset_info;
+        }
+        if let Some(secondary_asset_info) = update.secondary_asset_info {
+            self.secondary_asset_info = secondary_asset_info;
+        }
+        if let Some(astro_token_info) = update.astro_token_info {
+            self.astro_token_info = astro_token_info;
+        }
+        if let Some(primary_pair) = update.primary_pair {
+            self.primary_pair = primary_pair;
+        }
+        if let Some(astro_pair) = update.astro_pair {
+            self.astro_pair = astro_pair;
+        }
+        if let Some(astro_generator) = update.astro_generator {
+            self.astro_generator = astro_generator;
+        }
+        if let Some(red_bank) = update.red_bank {
+            self.red_bank = red_bank;
+        }
+        if let Some(primary_price_source) = update.primary_price_source {
+            self.primary_price_source = primary_price_source;
+        }
+        if let Some(secondary_price_source) = update.secondary_price_source {
+            self.secondary_price_source = secondary_price_source;
+        }
+        if let Some(astro_price_source) = update.astro_price_source {
+            self.astro_price_source = astro_price_source;
+        }
+        if let Some(treasury) = update.treasury {
+            self.treasury = treasury;
+        }
+        if let Some(guardian) = update.guardian {
+            self.guardian = guardian;
+        }
+        if let Some(fee_manager) = update.fee_manager {
+            self.fee_manager = fee_manager;
+        }
+        if let Some(operator_manager) = update.operator_manager {
+            self.operator_manager = operator_manager;
+        }
+        if let Some(permissionless_harvest) = update.permissionless_harvest {
+            self.permissionless_harvest = permissionless_harvest;
+        }
+        if let Some(max_ltv) = update.max_ltv {
+            self.max_ltv = max_ltv;
+        }
+        if let Some(max_initial_ltv) = update.max_initial_ltv {
+            self.max_initial_ltv = max_initial_ltv;
+        }
+        if let Some(performance_fee) = update.performance_fee {
+            self.performance_fee = performance_fee;
+        }
+        if let Some(harvest_bounty) = update.harvest_bounty {
+            self.harvest_bounty = harvest_bounty;
+        }
+        if let Some(min_harvest_interval) = update.min_harvest_interval {
+            self.min_harvest_interval = min_harvest_interval;
+        }
+        if let Some(bonus_rate) = update.bonus_rate {
+            self.bonus_rate = bonus_rate;
+        }
+        if let Some(close_factor) = update.close_factor {
+            self.close_factor = close_factor;
+        }
+        if let Some(full_liquidation_ltv) = update.full_liquidation_ltv {
+            self.full_liquidation_ltv = full_liquidation_ltv;
+        }
+        if let Some(liquidation_max_spread) = update.liquidation_max_spread {
+            self.liquidation_max_spread = liquidation_max_spread;
+        }
+        if let Some(price_deviation_tolerance) = update.price_deviation_tolerance {
+            self.price_deviation_tolerance = price_deviation_tolerance;
+        }
+        if let Some(twap_window) = update.twap_window {
+            self.twap_window = twap_window;
+        }
+        if let Some(max_price_age) = update.max_price_age {
+            self.max_price_age = max_price_age;
+        }
+        if let Some(apr_query_adapter) = update.apr_query_adapter {
+            self.apr_query_adapter = apr_query_adapter;
+        }
+        if let Some(apollo_factory) = update.apollo_factory {
+            self.apollo_factory = apollo_factory;
+        }
+        if let Some(min_position_size) = update.min_position_size {
+            self.min_position_size = min_position_size;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingGovernanceBase<T> {
+    /// Account proposed to become the new governance
+    pub new_governance: T,
+    /// Timestamp (in seconds) after which the proposal can no longer be accepted; `None` if the
+    /// proposal does not expire
+    pub expires_at: Option<u64>,
+}
+
+pub type PendingGovernanceUnchecked = PendingGovernanceBase<String>;
+pub type PendingGovernance = PendingGovernanceBase<Addr>;
+
+impl From<PendingGovernance> for PendingGovernanceUnchecked {
+    fn from(pending: PendingGovernance) -> Self {
+        PendingGovernanceUnchecked {
+            new_governance: pending.new_governance.into(),
+            expires_at: pending.expires_at,
+        }
+    }
+}
+
+/// Metadata of an operator, i.e. an account who can harvest
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OperatorInfo {
+    /// Block height at which the operator was added
+    pub added_at_height: u64,
+    /// Timestamp (in seconds) after which the account can no longer harvest; `None` if the role
+    /// does not expire
+    pub expires_at: Option<u64>,
+}
+
+impl OperatorInfo {
+    pub fn is_expired(&self, time: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if time > expires_at)
+    }
+}
+
+/// Returned by the Operators QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OperatorResponse {
+    pub operator: String,
+    pub info: OperatorInfo,
+}
+
+/// Actions that can be paused by the guardian, e.g. if Astroport, Red Bank, or the oracle is
+/// malfunctioning
+///
+/// Repaying debts and unbonding are never paused, so that users can always close their positions
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct PauseFlags {
+    /// Whether `Action::Deposit` is paused
+    pub deposit: bool,
+    /// Whether `Action::Borrow` is paused
+    pub borrow: bool,
+    /// Whether `Action::Bond` is paused
+    pub bond: bool,
+    /// Whether `Action::Swap` is paused
+    pub swap: bool,
+    /// Whether `ExecuteMsg::Harvest` is paused
+    pub harvest: bool,
+    /// Whether `ExecuteMsg::Liquidate` and `ExecuteMsg::LiquidateMany` are paused
+    pub liquidate: bool,
+}
+
+/// Pause flags to be updated. Flags that are `None` are left unchanged
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct PauseFlagsUpdate {
+    pub deposit: Option<bool>,
+    pub borrow: Option<bool>,
+    pub bond: Option<bool>,
+    pub swap: Option<bool>,
+    pub harvest: Option<bool>,
+    pub liquidate: Option<bool>,
+}
+
+impl PauseFlags {
+    /// Apply an update to the flags. Returns the names and new values of the flags that have been
+    /// toggled
+    pub fn apply_update(&mut self, update: PauseFlagsUpdate) -> Vec<(&'static str, bool)> {
+        let mut toggled: Vec<(&'static str, bool)> = vec![];
+        for (name, flag, new_value) in [
+            ("deposit", &mut self.deposit, update.deposit),
+            ("borrow", &mut self.borrow, update.borrow),
+            ("bond", &mut self.bond, update.bond),
+            ("swap", &mut self.swap, update.swap),
+            ("harvest", &mut self.harvest, update.harvest),
+            ("liquidate", &mut self.liquidate, update.liquidate),
+        ] {
+            if let Some(new_value) = new_value {
+                if *flag != new_value {
+                    *flag = new_value;
+                    toggled.push((name, new_value));
+                }
+            }
+        }
+        toggled
+    }
+}
+
+/// A snapshot of a pair's cumulative price of its non-secondary asset, i.e. the primary asset for
+/// the primary pair, or ASTRO for the ASTRO pair
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceSnapshot {
+    /// Timestamp (in seconds) at which the snapshot was taken
+    pub timestamp: u64,
+    /// Cumulative price of the asset in terms of the secondary asset, as reported by the pair
+    pub price_cumulative: Uint128,
+}
+
+/// Returned by the Twap QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TwapResponse {
+    /// Time-weighted average price of the primary asset in terms of the secondary asset, over the
+    /// period between the two most recent snapshots
+    pub price: Decimal,
+    /// Timestamp (in seconds) of the most recent snapshot
+    pub last_updated: u64,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -181,6 +695,8 @@ pub struct StateBase<T> {
     pub total_debt_units: Uint128,
     /// Reward tokens that can be reinvested in the next harvest
     pub pending_rewards: AssetListBase<T>,
+    /// Timestamp (in seconds) of the last harvest
+    pub last_harvest: u64,
 }
 
 // `Addr` does not have `Default` implemented, so we can't derive the Default trait
@@ -190,6 +706,7 @@ impl<T> Default for StateBase<T> {
             total_bond_units: Uint128::zero(),
             total_debt_units: Uint128::zero(),
             pending_rewards: AssetListBase::default(),
+            last_harvest: 0,
         }
     }
 }
@@ -203,6 +720,7 @@ impl From<State> for StateUnchecked {
             total_bond_units: state.total_bond_units,
             total_debt_units: state.total_debt_units,
             pending_rewards: state.pending_rewards.into(),
+            last_harvest: state.last_harvest,
         }
     }
 }
@@ -265,6 +783,55 @@ pub struct Health {
     pub ltv: Option<Decimal>,
 }
 
+/// Returned by the Positions QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionResponse {
+    pub user: String,
+    pub position: PositionUnchecked,
+}
+
+/// A position along with its health, as returned by the PositionsWithHealth QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionWithHealthResponse {
+    pub user: String,
+    pub position: PositionUnchecked,
+    pub health: Health,
+}
+
+/// Returned by the PositionsWithHealth QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionsWithHealthResponse {
+    /// Positions scanned that pass the `min_ltv` filter, if any
+    pub positions: Vec<PositionWithHealthResponse>,
+    /// Address of the last user whose position was scanned, to be used as `start_after` to continue
+    /// the enumeration; `None` if there were no more positions to scan
+    pub last_scanned: Option<String>,
+}
+
+/// Returned by the LiquidationPrice QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidationPriceResponse {
+    /// Current price of the primary asset
+    pub current_price: Decimal,
+    /// Price of the primary asset at which the position's LTV reaches `max_ltv`, all else being
+    /// equal; `None` if the position has no debt or no bonded assets
+    pub liquidation_price: Option<Decimal>,
+    /// Relative drop of the primary asset's price that would make the position liquidatable; zero
+    /// if it already is, `None` if `liquidation_price` is `None`
+    pub buffer: Option<Decimal>,
+}
+
+/// Returned by the SimulateUpdatePosition QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SimulateUpdatePositionResponse {
+    /// The position after the simulated call, or at the point where it failed
+    pub position: PositionUnchecked,
+    /// Health of `position`
+    pub health: Health,
+    /// The error the call would fail with, if any
+    pub error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[schemars(deny_unknown_fields)]
 /// Returned by the UserInfo QueryMsg that we need to implement for Apollo Rewards support.
@@ -288,6 +855,93 @@ pub struct Snapshot {
     pub health: Health,
 }
 
+/// Cumulative accounting of a user's position, recorded during `UpdatePosition` and liquidations,
+/// from which the position's PnL is derived
+///
+/// Values are measured in the short asset, at the prices at the time of each operation
+#[derive(Default, Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionAccounting {
+    /// Total value of assets deposited
+    pub value_deposited: Uint128,
+    /// Total value of assets withdrawn, i.e. refunded to the user
+    pub value_withdrawn: Uint128,
+    /// Total value of debt borrowed
+    pub debt_value_borrowed: Uint128,
+    /// Total value of debt repaid
+    pub debt_value_repaid: Uint128,
+    /// Value deposited that has not been withdrawn yet
+    ///
+    /// When assets are withdrawn, the cost basis is reduced in proportion to the share of the
+    /// position's equity withdrawn, and the difference between the value withdrawn and the cost
+    /// basis released is realized as profit or loss
+    pub cost_basis: Uint128,
+    /// Total profit realized when withdrawing
+    pub realized_profit: Uint128,
+    /// Total loss realized when withdrawing
+    pub realized_loss: Uint128,
+}
+
+impl PositionAccounting {
+    /// Record a deposit of the given value
+    pub fn record_deposit(&mut self, value: Uint128) {
+        self.value_deposited += value;
+        self.cost_basis += value;
+    }
+
+    /// Record a withdrawal of the given value, out of the position's equity before the withdrawal
+    pub fn record_withdrawal(&mut self, value: Uint128, equity: Uint128) {
+        let cost_basis_released = if value >= equity {
+            self.cost_basis
+        } else {
+            self.cost_basis.multiply_ratio(value, equity)
+        };
+
+        if value >= cost_basis_released {
+            self.realized_profit += value - cost_basis_released;
+        } else {
+            self.realized_loss += cost_basis_released - value;
+        }
+
+        self.value_withdrawn += value;
+        self.cost_basis -= cost_basis_released;
+    }
+}
+
+/// A signed value, as CosmWasm does not provide a signed integer type
+#[derive(Default, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+pub struct SignedValue {
+    pub amount: Uint128,
+    pub negative: bool,
+}
+
+impl SignedValue {
+    /// Return `a - b`
+    pub fn difference(a: Uint128, b: Uint128) -> Self {
+        if a >= b {
+            SignedValue {
+                amount: a - b,
+                negative: false,
+            }
+        } else {
+            SignedValue {
+                amount: b - a,
+                negative: true,
+            }
+        }
+    }
+}
+
+/// Returned by the PositionPnl QueryMsg
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PositionPnlResponse {
+    pub accounting: PositionAccounting,
+    pub health: Health,
+    /// Profit realized when withdrawing, net of losses
+    pub realized_pnl: SignedValue,
+    /// The position's equity, i.e. bond value minus debt value, in excess of its cost basis
+    pub unrealized_pnl: SignedValue,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 /// Returned by the Tvl QueryMsg that we need to implement for Apollo Rewards support.
 pub struct TvlResponse {
@@ -300,13 +954,68 @@ pub struct AprResponse {
     pub apr: Decimal256,
 }
 
+//--------------------------------------------------------------------------------------------------
+// Replies: context of submessages whose replies are pending
+//--------------------------------------------------------------------------------------------------
+
+/// Identifies the operation a submessage performs, so that its reply can be routed to the right
+/// handler
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyId {
+    /// Providing liquidity to the primary pair
+    ProvideLiquidity = 0,
+    /// Burning liquidity tokens of the primary pair
+    WithdrawLiquidity = 1,
+    /// Swapping in the primary pair or the ASTRO pair
+    Swap = 2,
+    /// Updating a user's Apollo rewards during a liquidation. Only replied to on error
+    ApolloRewardUpdate = 3,
+}
+
+impl From<ReplyId> for u64 {
+    fn from(id: ReplyId) -> Self {
+        id as u64
+    }
+}
+
+impl TryFrom<u64> for ReplyId {
+    type Error = StdError;
+
+    fn try_from(id: u64) -> StdResult<Self> {
+        match id {
+            0 => Ok(ReplyId::ProvideLiquidity),
+            1 => Ok(ReplyId::WithdrawLiquidity),
+            2 => Ok(ReplyId::Swap),
+            3 => Ok(ReplyId::ApolloRewardUpdate),
+            id => Err(StdError::generic_err(format!("invalid reply id: {}", id))),
+        }
+    }
+}
+
+/// Context of a submessage whose reply is pending
+///
+/// It is saved right before the submessage is dispatched, and must be consumed by the reply handler
+/// before the context of another submessage can be saved
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReplyContext {
+    /// The operation the submessage performs
+    pub id: ReplyId,
+    /// The user whose unlocked assets are credited with the assets received; `None` if they are to
+    /// be added to the pending rewards
+    pub user_addr: Option<Addr>,
+    /// The contract's balances of the assets the submessage is expected to return, before it was
+    /// dispatched
+    pub balances_before: AssetListBase<Addr>,
+}
+
 //--------------------------------------------------------------------------------------------------
 // Message and response types
 //--------------------------------------------------------------------------------------------------
 
 pub mod msg {
     use super::*;
-    use cosmwasm_std::Empty;
+    use cosmwasm_std::Coin;
     use cw_asset::{AssetInfo, AssetUnchecked};
 
     pub type InstantiateMsg = ConfigUnchecked;
@@ -349,6 +1058,32 @@ pub mod msg {
             offer_amount: Uint128,
             max_spread: Option<Decimal>,
         },
+        /// Deposit either the primary or the secondary asset, swap the optimal portion of unlocked
+        /// assets to the other one, provide liquidity and bond the received liquidity tokens
+        ///
+        /// Any leftover is refunded to the user along with other unlocked assets
+        Zap {
+            asset: AssetUnchecked,
+            max_spread: Option<Decimal>,
+            slippage_tolerance: Option<Decimal>,
+        },
+        /// Borrow or repay secondary asset such that the position's LTV becomes `target_ltv`
+        ///
+        /// When increasing leverage, the borrowed asset is bonded along with all unlocked assets.
+        /// When decreasing leverage, liquidity tokens are unbonded as needed, primary asset is sold
+        /// to repay the debt, and the remaining unlocked assets are bonded again
+        SetLeverage {
+            target_ltv: Decimal,
+            max_spread: Option<Decimal>,
+            slippage_tolerance: Option<Decimal>,
+        },
+        /// Unbond all liquidity tokens, sell enough primary asset to pay off the debt in full, and
+        /// repay it. All remaining assets are refunded to the user
+        ///
+        /// Unlike liquidation, no bonus is paid, and the position can be closed at any LTV
+        ClosePosition {
+            max_spread: Option<Decimal>,
+        },
     }
 
     #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -368,6 +1103,10 @@ pub mod msg {
         UpdatePosition(Vec<Action>),
         /// Claim staking reward and reinvest
         ///
+        /// Only operators can call, unless `permissionless_harvest` is enabled. The caller is paid
+        /// `harvest_bounty` of the claimed rewards. At least `min_harvest_interval` seconds must
+        /// have elapsed since the last harvest
+        ///
         /// `max_spread` is used for ASTRO >> secondary swap and balancing operations
         ///
         /// `slippage_tolerance` is used for providing primary + secondary liquidity
@@ -375,15 +1114,85 @@ pub mod msg {
             max_spread: Option<Decimal>,
             slippage_tolerance: Option<Decimal>,
         },
-        /// Force close an underfunded position, repay all debts, and return all remaining funds to
-        /// the position's owner. The liquidator is awarded a portion of the remaining funds.
+        /// Liquidate an underfunded position
+        ///
+        /// If the position's LTV is at or above `full_liquidation_ltv`, force close it, repay all
+        /// debts, and return all remaining funds to the position's owner. The liquidator is awarded
+        /// a portion of the remaining funds.
+        ///
+        /// Otherwise, unbond just enough liquidity tokens to repay the debt needed to bring the LTV
+        /// back to `max_initial_ltv`, up to `close_factor` of the debt. The liquidator is awarded
+        /// the withdrawn assets in excess of the repaid debt, worth `bonus_rate` of it.
+        ///
+        /// If `repay_amount` is provided, the liquidator repays the debt with their own secondary
+        /// asset instead, either sent along with the message or, if it is a CW20 token, approved
+        /// to be transferred. No swap is made. The liquidator receives liquidity tokens worth the
+        /// repaid debt plus `bonus_rate` of it, withdrawn as primary + secondary assets, as well as
+        /// any funds in excess of the repayable debt.
+        ///
+        /// `max_spread` is used for selling the primary asset; it defaults to, and must not exceed,
+        /// `liquidation_max_spread`. It is validated, but not used, if `repay_amount` is provided.
         Liquidate {
             user: String,
+            repay_amount: Option<Uint128>,
+            max_spread: Option<Decimal>,
+        },
+        /// Liquidate multiple underfunded positions, in the same way as `Liquidate` without
+        /// `repay_amount`, using `liquidation_max_spread` as the max spread
+        ///
+        /// Positions that are healthy or already closed are skipped instead of failing the batch
+        LiquidateMany {
+            users: Vec<String>,
         },
-        /// Update data stored in config (only governance can call)
+        /// Update selected parameters in config (only governance can call)
+        ///
+        /// The resulting config is validated in full. Emits a `config_updated` event recording the
+        /// values before and after the update of each parameter that has changed
         UpdateConfig {
-            new_config: ConfigUnchecked,
+            config_update: ConfigUpdate,
+        },
+        /// Update the performance fee and/or the treasury address (only fee manager can call)
+        UpdateFeeConfig {
+            performance_fee: Option<Decimal>,
+            treasury: Option<String>,
+        },
+        /// Add an account who can harvest, or update the expiry of an existing one (only operator
+        /// manager can call)
+        ///
+        /// If `expires_in` (in seconds) is not provided, the role does not expire
+        AddOperator {
+            operator: String,
+            expires_in: Option<u64>,
+        },
+        /// Remove an account from the list of operators (only operator manager can call)
+        RemoveOperator {
+            operator: String,
+        },
+        /// Pause or unpause selected actions (only guardian or governance can call)
+        ///
+        /// Emits a `pause_flag_updated` event for each flag that is toggled
+        UpdatePauseFlags {
+            pause_flags_update: PauseFlagsUpdate,
+        },
+        /// Take a snapshot of the cumulative price of the primary pair, and of each pair used as a
+        /// price source, if at least `twap_window` seconds have elapsed since its last one. Anyone
+        /// can call
+        ///
+        /// Snapshots are also taken during harvests
+        UpdatePriceSnapshot {},
+        /// Propose a new account to take over governance (only governance can call)
+        ///
+        /// The proposed account must send `AcceptGovernance` before the proposal expires for the
+        /// transfer to take effect. If `expires_in` (in seconds) is not provided, the proposal does
+        /// not expire. Overwrites any existing proposal
+        ProposeNewGovernance {
+            new_governance: String,
+            expires_in: Option<u64>,
         },
+        /// Accept a pending governance proposal (only the proposed account can call)
+        AcceptGovernance {},
+        /// Withdraw a pending governance proposal (only governance can call)
+        CancelGovernanceProposal {},
         /// Callbacks; only callable by the strategy itself.
         Callback(CallbackMsg),
     }
@@ -440,25 +1249,58 @@ pub mod msg {
         /// Increase the user's unlocked secondary asset amount;
         ///
         /// If `swap_amount` is not provided, then use all available unlocked asset
+        ///
+        /// The swap uses a belief price derived from the price sources, and is rejected if the pool's
+        /// price deviates from it by more than `price_deviation_tolerance`
         Swap {
             user_addr: Option<Addr>,
             offer_asset_info: AssetInfo,
             offer_amount: Option<Uint128>,
             max_spread: Option<Decimal>,
         },
-        /// Swap the primary and secondary assets currently held by the contract as pending rewards,
-        /// such that the two assets have the same value and can be reinvested
+        /// Swap a user's unlocked primary and secondary assets, or the ones currently held by the
+        /// contract as pending rewards if `user_addr` is not provided, such that the two assets are
+        /// in the same ratio as the pool depths and can be provided without leaving any leftover
+        ///
+        /// The swap uses a belief price derived from the price sources, and is rejected if the pool's
+        /// price deviates from it by more than `price_deviation_tolerance`
         ///
-        /// _Only used during the `Harvest` function call_
+        /// _Used during the `Harvest` function call and the `Zap` action_
         Balance {
+            user_addr: Option<Addr>,
+            max_spread: Option<Decimal>,
+        },
+        /// Compute how much secondary asset needs to be borrowed or repaid for a user's position to
+        /// reach the target LTV, and invoke the callbacks to do so
+        ///
+        /// _Only used by the `SetLeverage` action_
+        SetLeverage {
+            user_addr: Addr,
+            target_ltv: Decimal,
+            max_spread: Option<Decimal>,
+            slippage_tolerance: Option<Decimal>,
+        },
+        /// Unbond all of a user's liquidity tokens, and invoke the callbacks to pay off the debt
+        ///
+        /// _Only used by the `ClosePosition` action_
+        ClosePosition {
+            user_addr: Addr,
             max_spread: Option<Decimal>,
         },
         /// Sell an appropriate amount of a user's unlocked primary asset, such that the user has
-        /// enough unlocked secondary asset to fully pay off debt
+        /// enough unlocked secondary asset to pay off `repay_amount` of debt, or the full debt if
+        /// `repay_amount` is not provided
         ///
-        /// _Only used during the `Liquidate` function call_
+        /// The swap uses a belief price derived from the price sources, and is rejected if the pool's
+        /// price deviates from it by more than `price_deviation_tolerance`. `max_spread` defaults to
+        /// `liquidation_max_spread` if not provided
+        ///
+        /// _Used during the `Liquidate` function call, and the `SetLeverage` and `ClosePosition`
+        /// actions_
         Cover {
             user_addr: Addr,
+            repay_amount: Option<Uint128>,
+            max_spread: Option<Decimal>,
         },
         /// Send a percentage of a user's unlocked primary & seoncdary asset to a recipient; default
         /// to the user if unspecified
@@ -507,6 +1349,19 @@ pub mod msg {
         Config {},
         /// Return the global state of the strategy. Response: `StateUnchecked`
         State {},
+        /// Return the pending governance transfer proposal, if any. Response:
+        /// `Option<PendingGovernanceUnchecked>`
+        PendingGovernance {},
+        /// Enumerate operators, sorted by address. Response: `Vec<OperatorResponse>`
+        Operators {
+            start_after: Option<String>,
+            limit: Option<u32>,
+        },
+        /// Return which actions are currently paused. Response: `PauseFlags`
+        PauseFlags {},
+        /// Return the TWAP of the primary asset and when it was last updated. Response:
+        /// `TwapResponse`
+        Twap {},
         /// Return data on an individual user's position. Response: `PositionUnchecked`
         Position {
             user: String,
@@ -515,6 +1370,40 @@ pub mod msg {
         Health {
             user: String,
         },
+        /// Query the price of the primary asset at which a user's position becomes liquidatable,
+        /// along with the current price and the buffer between them. Response:
+        /// `LiquidationPriceResponse`
+        LiquidationPrice {
+            user: String,
+        },
+        /// Enumerate positions, sorted by user address. Response: `Vec<PositionResponse>`
+        Positions {
+            start_after: Option<String>,
+            limit: Option<u32>,
+        },
+        /// Enumerate positions along with their health, sorted by user address, e.g. for keepers to
+        /// find liquidatable positions. If `min_ltv` is provided, only positions with an LTV no less
+        /// than it are returned; positions with no bonded assets are then skipped
+        ///
+        /// At most `limit` positions are scanned, whether or not they are returned. Response:
+        /// `PositionsWithHealthResponse`
+        PositionsWithHealth {
+            start_after: Option<String>,
+            limit: Option<u32>,
+            min_ltv: Option<Decimal>,
+        },
+        /// Simulate an `UpdatePosition` call by `user` sending `funds`, without executing it.
+        /// Response: `SimulateUpdatePositionResponse`
+        SimulateUpdatePosition {
+            user: String,
+            actions: Vec<Action>,
+            funds: Vec<Coin>,
+        },
+        /// Query the cumulative accounting of a user's position, along with its realized and
+        /// unrealized PnL. Response: `PositionPnlResponse`
+        PositionPnl {
+            user: String,
+        },
         /// Query the snapshot of a user's position
         ///
         /// NOTE: Snapshot is a temporary functionality used for calculating the user's PnL, which
@@ -536,6 +1425,15 @@ pub mod msg {
         Apr {},
     }
 
-    /// We currently don't need any input parameter for migration
-    pub type MigrateMsg = Empty;
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    pub struct MigrateMsg {
+        /// If provided, applied to the config once storage has been migrated to the current schema
+        ///
+        /// Config parameters introduced by a new version are initialized with default values during
+        /// migration. This can be used to set them to something else
+        ///
+        /// Parameters without a safe default must be provided. When migrating from v1.0.0, these are
+        /// `max_price_age` and `astro_price_source`
+        pub config_update: Option<ConfigUpdate>,
+    }
 }
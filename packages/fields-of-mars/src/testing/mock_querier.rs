@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use cosmwasm_std::testing::MockQuerier;
+use cosmwasm_std::{
+    from_binary, from_slice, to_binary, Addr, Binary, Coin, ContractResult, Decimal, Empty,
+    Querier, QuerierResult, QueryRequest, StdResult, SystemError, SystemResult, Uint128, WasmQuery,
+};
+use serde::Deserialize;
+
+use astroport::asset::Asset as AstroportAsset;
+use astroport::pair::{
+    CumulativePricesResponse, PoolResponse, ReverseSimulationResponse, SimulationResponse,
+};
+
+use cw_asset::{Asset, AssetInfo};
+
+use crate::adapters::ASTROPORT_COMMISSION_RATE;
+
+/// Queries to the Mars oracle, Red Bank, Astro generator, Astroport pairs and CW20 tokens that the
+/// mock querier answers. Each contract is identified by the query it receives, except for pairs and
+/// tokens, of which there can be more than one
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MockQueryMsg {
+    AssetPriceByReference {
+        asset_reference: Vec<u8>,
+    },
+    UserAssetDebt {
+        user_address: String,
+    },
+    Deposit {
+        user: String,
+    },
+    Pool {},
+    Simulation {
+        offer_asset: AstroportAsset,
+    },
+    ReverseSimulation {
+        ask_asset: AstroportAsset,
+    },
+    CumulativePrices {},
+    Balance {
+        address: String,
+    },
+}
+
+/// A pair's assets and total supply of liquidity tokens
+struct MockPool {
+    assets: [Asset; 2],
+    total_share: Uint128,
+}
+
+/// Mock querier answering the queries martian-field makes to the contracts it integrates with
+///
+/// Queries for which no response has been set are passed on to the wrapped `MockQuerier`, which
+/// fails them as being sent to a non-existent contract
+pub struct CustomQuerier {
+    base: MockQuerier,
+    prices: HashMap<String, Decimal>,
+    debts: HashMap<String, Uint128>,
+    bonded_amounts: HashMap<String, Uint128>,
+    pools: HashMap<String, MockPool>,
+    token_balances: HashMap<(String, String), Uint128>,
+}
+
+impl Default for CustomQuerier {
+    fn default() -> Self {
+        CustomQuerier {
+            base: MockQuerier::new(&[]),
+            prices: HashMap::new(),
+            debts: HashMap::new(),
+            bonded_amounts: HashMap::new(),
+            pools: HashMap::new(),
+            token_balances: HashMap::new(),
+        }
+    }
+}
+
+impl Querier for CustomQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_slice(bin_request) {
+            Ok(request) => request,
+            Err(err) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", err),
+                    request: bin_request.into(),
+                })
+            }
+        };
+
+        if let QueryRequest::Wasm(WasmQuery::Smart {
+            contract_addr,
+            msg,
+        }) = &request
+        {
+            if let Some(res) = self.handle_smart_query(contract_addr, msg) {
+                return SystemResult::Ok(ContractResult::from(res));
+            }
+        }
+
+        self.base.handle_query(&request)
+    }
+}
+
+impl CustomQuerier {
+    /// Set the native coins held by an account
+    pub fn update_balance(&mut self, addr: impl Into<String>, balance: Vec<Coin>) {
+        self.base.update_balance(addr, balance);
+    }
+
+    /// Set the price of an asset reported by the Mars oracle
+    pub fn set_oracle_price(&mut self, asset_info: &AssetInfo, price: Decimal) {
+        self.prices.insert(asset_info.to_string(), price);
+    }
+
+    /// Set the amount of debt a user owes to Red Bank
+    pub fn set_debt_amount(&mut self, user_addr: &Addr, amount: Uint128) {
+        self.debts.insert(user_addr.to_string(), amount);
+    }
+
+    /// Set the amount of liquidity tokens a user has bonded in the Astro generator
+    pub fn set_bonded_amount(&mut self, user_addr: &Addr, amount: Uint128) {
+        self.bonded_amounts.insert(user_addr.to_string(), amount);
+    }
+
+    /// Set the depths and total supply of liquidity tokens of an Astroport pair
+    pub fn set_pool(&mut self, pair_addr: &Addr, assets: [Asset; 2], total_share: Uint128) {
+        self.pools.insert(
+            pair_addr.to_string(),
+            MockPool {
+                assets,
+                total_share,
+            },
+        );
+    }
+
+    /// Set the amount of a CW20 token held by an account
+    pub fn set_token_balance(&mut self, token_addr: &Addr, addr: &Addr, amount: Uint128) {
+        self.token_balances.insert((token_addr.to_string(), addr.to_string()), amount);
+    }
+
+    fn handle_smart_query(&self, contract_addr: &str, msg: &Binary) -> Option<StdResult<Binary>> {
+        match from_binary(msg).ok()? {
+            MockQueryMsg::AssetPriceByReference {
+                asset_reference,
+            } => {
+                let asset_label = String::from_utf8(asset_reference).ok()?;
+                self.prices.get(&asset_label).map(to_binary)
+            }
+
+            MockQueryMsg::UserAssetDebt {
+                user_address,
+            } => self.debts.get(&user_address).map(|amount| {
+                // Red Bank returns a number of other fields, which martian-field doesn't read
+                Ok(Binary::from(
+                    format!(
+                        r#"{{"denom":"","asset_label":"","asset_reference":[],"asset_type":"native","amount_scaled":"0","amount":"{}"}}"#,
+                        amount
+                    )
+                    .as_bytes(),
+                ))
+            }),
+
+            MockQueryMsg::Deposit {
+                user,
+            } => self.bonded_amounts.get(&user).map(to_binary),
+
+            MockQueryMsg::Pool {} => self.pools.get(contract_addr).map(|pool| {
+                to_binary(&PoolResponse {
+                    assets: [(&pool.assets[0]).into(), (&pool.assets[1]).into()],
+                    total_share: pool.total_share,
+                })
+            }),
+
+            MockQueryMsg::Simulation {
+                offer_asset,
+            } => self.pools.get(contract_addr).map(|pool| {
+                let (offer_depth, ask_depth) = pool.depths(&offer_asset.info.into());
+                let return_amount =
+                    ask_depth - ask_depth.multiply_ratio(offer_depth, offer_depth + offer_asset.amount);
+                let commission_amount = return_amount * commission_rate();
+                to_binary(&SimulationResponse {
+                    return_amount: return_amount - commission_amount,
+                    spread_amount: Uint128::zero(),
+                    commission_amount,
+                })
+            }),
+
+            MockQueryMsg::ReverseSimulation {
+                ask_asset,
+            } => self.pools.get(contract_addr).map(|pool| {
+                let (ask_depth, offer_depth) = pool.depths(&ask_asset.info.into());
+                let one_minus_commission_rate = Decimal::one() - commission_rate();
+                let ask_amount_before_commission = ask_asset.amount.multiply_ratio(
+                    Uint128::new(1_000_000_000_000_000_000),
+                    Uint128::new(1_000_000_000_000_000_000) * one_minus_commission_rate,
+                );
+                let offer_amount = offer_depth
+                    .multiply_ratio(ask_depth, ask_depth - ask_amount_before_commission)
+                    - offer_depth;
+                to_binary(&ReverseSimulationResponse {
+                    offer_amount,
+                    spread_amount: Uint128::zero(),
+                    commission_amount: ask_amount_before_commission - ask_asset.amount,
+                })
+            }),
+
+            MockQueryMsg::CumulativePrices {} => self.pools.get(contract_addr).map(|pool| {
+                to_binary(&CumulativePricesResponse {
+                    assets: [(&pool.assets[0]).into(), (&pool.assets[1]).into()],
+                    total_share: pool.total_share,
+                    price0_cumulative_last: Uint128::zero(),
+                    price1_cumulative_last: Uint128::zero(),
+                })
+            }),
+
+            MockQueryMsg::Balance {
+                address,
+            } => self
+                .token_balances
+                .get(&(contract_addr.to_string(), address))
+                .map(|balance| to_binary(&cw20::BalanceResponse {
+                    balance: *balance,
+                })),
+        }
+    }
+}
+
+impl MockPool {
+    /// Depths of the given asset and of the other asset in the pool
+    fn depths(&self, asset_info: &AssetInfo) -> (Uint128, Uint128) {
+        if self.assets[0].info == *asset_info {
+            (self.assets[0].amount, self.assets[1].amount)
+        } else {
+            (self.assets[1].amount, self.assets[0].amount)
+        }
+    }
+}
+
+fn commission_rate() -> Decimal {
+    ASTROPORT_COMMISSION_RATE.parse().unwrap()
+}
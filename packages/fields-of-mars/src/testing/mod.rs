@@ -1,3 +1,5 @@
 mod helpers;
+mod mock_querier;
 
 pub use self::helpers::*;
+pub use self::mock_querier::*;
@@ -1,3 +1,4 @@
+import * as fs from "fs";
 import * as path from "path";
 import yargs from "yargs/yargs";
 import { MsgMigrateContract } from "@terra-money/terra.js";
@@ -14,6 +15,10 @@ const argv = yargs(process.argv)
       type: "string",
       demandOption: true,
     },
+    msg: {
+      type: "string",
+      demandOption: true,
+    },
     "code-id": {
       type: "number",
       demandOption: false,
@@ -24,6 +29,7 @@ const argv = yargs(process.argv)
 (async function () {
   const terra = createLCDClient(argv["network"]);
   const admin = createWallet(terra);
+  const msg = JSON.parse(fs.readFileSync(path.resolve(argv["msg"]), "utf8"));
 
   const uploadCode = async () => {
     const codeId = await storeCodeWithConfirm(
@@ -37,7 +43,7 @@ const argv = yargs(process.argv)
 
   const msgs = argv["contracts"]
     .split(",")
-    .map((addr) => new MsgMigrateContract(admin.key.accAddress, addr, codeId, {}));
+    .map((addr) => new MsgMigrateContract(admin.key.accAddress, addr, codeId, msg));
 
   const { txhash } = await sendTxWithConfirm(admin, msgs);
   console.log(`Contract migrated! txhash: ${txhash}`);
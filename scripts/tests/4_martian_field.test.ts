@@ -118,21 +118,67 @@ async function setupTest() {
     red_bank: {
       contract_addr: bank,
     },
-    oracle: {
-      contract_addr: oracle,
+    primary_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
+    },
+    secondary_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
+    },
+    astro_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
     },
     treasury: treasury.key.accAddress,
     governance: deployer.key.accAddress,
-    operators: [deployer.key.accAddress],
+    guardian: deployer.key.accAddress,
+    fee_manager: deployer.key.accAddress,
+    operator_manager: deployer.key.accAddress,
+    permissionless_harvest: false,
     max_ltv: "0.75", // 75%, i.e. for every 100 UST asset there must be no more than 75 UST debt
-    performance_fee: "0.2", // 20%
     max_initial_ltv: "0.66", // 66%, i.e. for every 100 UST asset there must be no more than 66 UST debt when updating the position
+    performance_fee: "0.2", // 20%
+    harvest_bounty: "0",
+    min_harvest_interval: 0,
     bonus_rate: "0.05", // 5%
+    close_factor: "0.5",
+    full_liquidation_ltv: "0.75", // same as max ltv, i.e. liquidated positions are closed in full
+    liquidation_max_spread: "0.05",
+    price_deviation_tolerance: "0.05",
+    twap_window: 1800,
+    max_price_age: 0, // no price snapshots are taken in this test, so the price check is disabled
+    // there is no mock Apollo factory; these need to point to a contract that accepts Apollo's
+    // UpdateUserRewards message for positions to be updated
+    apr_query_adapter: deployer.key.accAddress,
+    apollo_factory: {
+      contract_addr: deployer.key.accAddress,
+    },
+    min_position_size: "0",
   };
 
   ({ field } = await deployMartianField(deployer, config));
 
-  process.stdout.write("Configuring ANC and UST price oracle...");
+  process.stdout.write("Adding deployer as operator... ");
+  await sendTransaction(deployer, [
+    new MsgExecuteContract(deployer.key.accAddress, field, {
+      add_operator: {
+        operator: deployer.key.accAddress,
+      },
+    }),
+  ]);
+  console.log(chalk.green("Done!"));
+
+  process.stdout.write("Configuring ANC, ASTRO and UST price oracle...");
   await sendTransaction(deployer, [
     new MsgExecuteContract(deployer.key.accAddress, oracle, {
       set_asset: {
@@ -163,6 +209,21 @@ async function setupTest() {
         },
       },
     }),
+    new MsgExecuteContract(deployer.key.accAddress, oracle, {
+      set_asset: {
+        asset: {
+          cw20: {
+            contract_addr: astroToken,
+          },
+        },
+        price_source: {
+          astroport_spot: {
+            pair_address: astroUstPair,
+            asset_address: astroToken,
+          },
+        },
+      },
+    }),
   ]);
   console.log(chalk.green("Done!"));
 
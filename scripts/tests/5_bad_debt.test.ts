@@ -112,16 +112,52 @@ async function setupTest() {
     red_bank: {
       contract_addr: bank,
     },
-    oracle: {
-      contract_addr: oracle,
+    primary_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
+    },
+    secondary_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
+    },
+    astro_price_source: {
+      mars_oracle: {
+        oracle: {
+          contract_addr: oracle,
+        },
+      },
     },
     treasury: treasury.key.accAddress,
     governance: deployer.key.accAddress,
-    operators: [deployer.key.accAddress],
+    guardian: deployer.key.accAddress,
+    fee_manager: deployer.key.accAddress,
+    operator_manager: deployer.key.accAddress,
+    permissionless_harvest: false,
     max_ltv: "0.83",
-    performance_fee: "0",
     max_initial_ltv: "0.75",
+    performance_fee: "0",
+    harvest_bounty: "0",
+    min_harvest_interval: 0,
     bonus_rate: "0.05",
+    close_factor: "0.5",
+    full_liquidation_ltv: "0.83", // same as max ltv, i.e. liquidated positions are closed in full
+    liquidation_max_spread: "0.05",
+    price_deviation_tolerance: "0.05",
+    twap_window: 1800,
+    max_price_age: 0, // no price snapshots are taken in this test, so the price check is disabled
+    // there is no mock Apollo factory; these need to point to a contract that accepts Apollo's
+    // UpdateUserRewards message for positions to be updated
+    apr_query_adapter: deployer.key.accAddress,
+    apollo_factory: {
+      contract_addr: deployer.key.accAddress,
+    },
+    min_position_size: "0",
   };
   ({ field } = await deployMartianField(deployer, config));
 